@@ -14,17 +14,23 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::storage::catch_all_cache::PostgresCatchAllCache;
 use crate::storage::{postgres::PostgresStorage, StorageAdapter};
 use crate::throttle::ThrottleManager;
 use crate::worker::do_work::TaskWebhook;
 use crate::worker::setup_rabbit_mq;
 use anyhow::{bail, Context};
+use check_if_email_exists::mx::resolver::DnsConfig;
+use check_if_email_exists::score::{ClassificationPolicy, ScoreConfig};
 use check_if_email_exists::smtp::verif_method::{
 	EverythingElseVerifMethod, GmailVerifMethod, HotmailB2BVerifMethod, HotmailB2CVerifMethod,
 	MimecastVerifMethod, ProofpointVerifMethod, VerifMethod, VerifMethodSmtpConfig,
 	YahooVerifMethod, DEFAULT_PROXY_ID,
 };
-use check_if_email_exists::{CheckEmailInputProxy, WebdriverConfig, LOG_TARGET};
+use check_if_email_exists::smtp::{
+	load_smtp_patterns, CatchAllCache, InMemoryCatchAllCache, ProxyPool, SmtpTranscriptConfig,
+};
+use check_if_email_exists::{CheckEmailInputProxy, HttpClientConfig, WebdriverConfig, LOG_TARGET};
 use config::Config;
 use lapin::Channel;
 use serde::{Deserialize, Serialize};
@@ -32,7 +38,7 @@ use sqlx::PgPool;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
-use tracing::warn;
+use tracing::{info, warn};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BackendConfig {
@@ -57,6 +63,41 @@ pub struct BackendConfig {
 	pub webdriver_addr: String,
 	pub webdriver: WebdriverConfig,
 
+	/// DNS resolver configuration, used for the MX lookups.
+	#[serde(default)]
+	pub dns: DnsConfig,
+
+	/// Weights and thresholds of the confidence score.
+	#[serde(default)]
+	pub score: ScoreConfig,
+
+	/// Default classification policy, which can be overridden per request.
+	#[serde(default)]
+	pub classification_policy: ClassificationPolicy,
+
+	/// Cache of the catch-all status of domains.
+	#[serde(default)]
+	pub catch_all_cache: CatchAllCacheConfig,
+
+	/// Whether to add the SMTP conversation to the debug output.
+	#[serde(default)]
+	pub smtp_transcript: SmtpTranscriptConfig,
+
+	/// Timeout and user agent of the HTTP requests of the HTTP-based
+	/// verifications.
+	#[serde(default)]
+	pub http_client: HttpClientConfig,
+
+	/// Timeout of the whole verification of an email, in seconds. Leaving it
+	/// commented out will not set a total timeout.
+	#[serde(default)]
+	pub total_timeout: Option<u64>,
+
+	/// Path to a JSON file with additional SMTP reply patterns, loaded at
+	/// startup.
+	#[serde(default)]
+	pub smtp_patterns_path: Option<String>,
+
 	/** Backend-specific config*/
 	/// Backend host
 	pub http_host: String,
@@ -88,6 +129,9 @@ pub struct BackendConfig {
 
 	#[serde(skip)]
 	throttle_manager: Arc<ThrottleManager>,
+
+	#[serde(skip)]
+	catch_all_cache_impl: Option<Arc<dyn CatchAllCache>>,
 }
 
 impl BackendConfig {
@@ -97,6 +141,14 @@ impl BackendConfig {
 			backend_name: "".to_string(),
 			webdriver_addr: "".to_string(),
 			webdriver: WebdriverConfig::default(),
+			dns: DnsConfig::default(),
+			score: ScoreConfig::default(),
+			classification_policy: ClassificationPolicy::default(),
+			catch_all_cache: CatchAllCacheConfig::default(),
+			smtp_transcript: SmtpTranscriptConfig::default(),
+			http_client: HttpClientConfig::default(),
+			total_timeout: None,
+			smtp_patterns_path: None,
 			from_email: "".to_string(),
 			hello_name: "".to_string(),
 			smtp_timeout: None,
@@ -115,6 +167,7 @@ impl BackendConfig {
 			throttle_manager: Arc::new(
 				ThrottleManager::new(ThrottleConfig::new_without_throttle()),
 			),
+			catch_all_cache_impl: None,
 		}
 	}
 
@@ -134,6 +187,8 @@ impl BackendConfig {
 
 		VerifMethod {
 			proxies,
+			proxy_pools: self.overrides.proxy_pools.clone(),
+			http_proxies: self.overrides.http_proxies.clone(),
 			gmail: self
 				.overrides
 				.gmail
@@ -206,7 +261,12 @@ impl BackendConfig {
 			let rabbitmq_config = self.worker.rabbitmq.as_ref().ok_or_else(|| {
 				anyhow::anyhow!("Worker configuration is missing the rabbitmq configuration")
 			})?;
-			let channel = setup_rabbit_mq(&self.backend_name, rabbitmq_config).await?;
+			let channel = setup_rabbit_mq(
+				&self.backend_name,
+				rabbitmq_config,
+				&self.worker.defer_greylisted_secs,
+			)
+			.await?;
 			Some(Arc::new(channel))
 		} else {
 			None
@@ -216,6 +276,19 @@ impl BackendConfig {
 		// Initialize throttle manager
 		self.throttle_manager = Arc::new(ThrottleManager::new(self.throttle.clone()));
 
+		// Initialize catch-all cache
+		let ttl = Duration::from_secs(self.catch_all_cache.ttl_secs);
+		self.catch_all_cache_impl = match self.catch_all_cache.backend {
+			CatchAllCacheBackend::Noop => None,
+			CatchAllCacheBackend::Memory => Some(Arc::new(InMemoryCatchAllCache::new(ttl))),
+			CatchAllCacheBackend::Postgres => {
+				let pg_pool = self.get_pg_pool().ok_or_else(|| {
+					anyhow::anyhow!("The postgres catch-all cache requires a Postgres storage")
+				})?;
+				Some(Arc::new(PostgresCatchAllCache::new(pg_pool, ttl)))
+			}
+		};
+
 		Ok(())
 	}
 
@@ -235,11 +308,20 @@ impl BackendConfig {
 	pub fn get_throttle_manager(&self) -> Arc<ThrottleManager> {
 		self.throttle_manager.clone()
 	}
+
+	/// Get the catch-all cache, if one is configured.
+	pub fn get_catch_all_cache(&self) -> Option<Arc<dyn CatchAllCache>> {
+		self.catch_all_cache_impl.clone()
+	}
 }
 
 #[derive(Debug, Default, Deserialize, Clone, Serialize)]
 pub struct OverridesConfig {
 	pub proxies: HashMap<String, CheckEmailInputProxy>,
+	#[serde(default)]
+	pub proxy_pools: HashMap<String, ProxyPool>,
+	#[serde(default)]
+	pub http_proxies: HashMap<String, String>,
 	pub gmail: Option<GmailVerifMethod>,
 	pub hotmailb2b: Option<HotmailB2BVerifMethod>,
 	pub hotmailb2c: Option<HotmailB2CVerifMethod>,
@@ -254,6 +336,12 @@ pub struct WorkerConfig {
 	pub rabbitmq: Option<RabbitMQConfig>,
 	/// Optional webhook configuration to send email verification results.
 	pub webhook: Option<TaskWebhook>,
+	/// Delays, in seconds, after which a bulk verification task which failed
+	/// because of greylisting is verified again. The task is deferred once
+	/// per delay, without holding a concurrency slot in the meantime. Empty
+	/// to not defer greylisted tasks.
+	#[serde(default)]
+	pub defer_greylisted_secs: Vec<u64>,
 }
 
 /// Worker configuration that must be present if worker.enable is true. Used as
@@ -308,6 +396,36 @@ pub struct PostgresConfig {
 	pub extra: Option<serde_json::Value>,
 }
 
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CatchAllCacheBackend {
+	/// Don't cache, probe the SMTP server on each verification.
+	#[default]
+	Noop,
+	/// Cache in memory, per backend instance.
+	Memory,
+	/// Cache in the Postgres database, shared between all the workers.
+	/// Requires the Postgres storage.
+	Postgres,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize)]
+#[serde(default)]
+pub struct CatchAllCacheConfig {
+	pub backend: CatchAllCacheBackend,
+	/// How long a cached catch-all status stays valid, in seconds.
+	pub ttl_secs: u64,
+}
+
+impl Default for CatchAllCacheConfig {
+	fn default() -> Self {
+		Self {
+			backend: CatchAllCacheBackend::Noop,
+			ttl_secs: 24 * 60 * 60,
+		}
+	}
+}
+
 #[derive(Debug, Deserialize, Clone, Serialize)]
 pub struct CommercialLicenseTrialConfig {
 	pub api_token: String,
@@ -340,12 +458,23 @@ pub async fn load_config() -> Result<BackendConfig, anyhow::Error> {
 	// `proxies` field.
 	cfg.get_verif_method().validate_proxies()?;
 
+	// 3. Load the additional SMTP reply patterns, if any.
+	if let Some(path) = &cfg.smtp_patterns_path {
+		let count = load_smtp_patterns(path)
+			.with_context(|| format!("Failed to load SMTP patterns from {}", path))?;
+		info!(target: LOG_TARGET, path=?path, count=count, "Loaded additional SMTP patterns");
+	}
+
 	Ok(cfg)
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use check_if_email_exists::smtp::{
+		CircuitBreakerConfig, IpVersionPreference, ProxySelection, RetryPolicy, SmtpErrorDesc,
+		SourceIpSelection, StarttlsMode,
+	};
 	use serial_test::serial;
 	use std::{env, time::Duration};
 	use {
@@ -414,6 +543,10 @@ port = 1052
 username = "user2"
 password = "pass2"
 
+[proxy_pools.pool1]
+proxies = [{ proxy = "proxy1", weight = 3 }, { proxy = "proxy2" }]
+selection = "weighted"
+
 [hotmailb2c]
 type = "headless"
 
@@ -428,6 +561,10 @@ proxy = "proxy1"
 smtp_port = 465
 retries = 3
 smtp_timeout = { secs = 23, nanos = 0 }
+source_ips = ["192.0.2.1", "2001:db8::1"]
+source_ip_selection = "sticky_per_domain"
+retry_policy = { backoff_ms = [60000, 300000], retryable_errors = ["greylisted", "needs_rdns"], retryable_codes = [451] }
+circuit_breaker = { failure_threshold = 3 }
 
 # Allow skipping internal fields
 [hotmailb2b]
@@ -468,6 +605,13 @@ type = "smtp"
 			"my-proxy2"
 		);
 		assert_eq!(verif_method.proxies.get("proxy2").unwrap().port, 1052);
+		let pool = verif_method.proxy_pools.get("pool1").unwrap();
+		assert_eq!(pool.selection, ProxySelection::Weighted);
+		assert_eq!(
+			pool.proxies.iter().map(|m| m.weight).collect::<Vec<_>>(),
+			vec![3, 1]
+		);
+		assert_eq!(pool.quarantine_secs, 300);
 		assert_eq!(
 			verif_method
 				.proxies
@@ -493,9 +637,26 @@ type = "smtp"
 				from_email: "from@email.com".to_string(),
 				hello_name: "email.com".to_string(),
 				smtp_port: 465,
+				fallback_smtp_ports: vec![],
 				retries: 3,
+				retry_policy: RetryPolicy {
+					backoff_ms: vec![60000, 300000],
+					retryable_errors: vec![SmtpErrorDesc::Greylisted, SmtpErrorDesc::NeedsRDNS],
+					retryable_codes: vec![451],
+					..Default::default()
+				},
+				circuit_breaker: Some(CircuitBreakerConfig {
+					failure_threshold: 3,
+					..Default::default()
+				}),
 				proxy: Some("proxy1".to_string()),
+				proxy_pool: None,
 				smtp_timeout: Some(Duration::from_secs(23)),
+				starttls: StarttlsMode::Disabled,
+				implicit_tls: false,
+				source_ips: vec!["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()],
+				source_ip_selection: SourceIpSelection::StickyPerDomain,
+				ip_version_preference: IpVersionPreference::Any,
 			})
 		);
 
@@ -505,9 +666,18 @@ type = "smtp"
 				from_email: "reacher@gmail.com".to_string(),
 				hello_name: "gmail.com".to_string(),
 				smtp_port: 25,
+				fallback_smtp_ports: vec![],
 				retries: 1,
+				retry_policy: RetryPolicy::default(),
+				circuit_breaker: None,
 				proxy: None,
+				proxy_pool: None,
 				smtp_timeout: None,
+				starttls: StarttlsMode::Disabled,
+				implicit_tls: false,
+				source_ips: vec![],
+				source_ip_selection: SourceIpSelection::RoundRobin,
+				ip_version_preference: IpVersionPreference::Any,
 			})
 		);
 
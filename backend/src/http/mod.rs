@@ -45,6 +45,9 @@ pub fn create_routes(
 		))
 		.or(v0::bulk::get::get_bulk_job_status(pg_pool.clone()))
 		.or(v0::bulk::results::get_bulk_job_result(pg_pool))
+		.or(v1::check_email::sse::v1_check_email_sse(Arc::clone(
+			&config,
+		)))
 		.or(v1::check_email::post::v1_check_email(Arc::clone(&config)))
 		.or(v1::bulk::post::v1_create_bulk_job(Arc::clone(&config)))
 		.or(v1::bulk::get_progress::v1_get_bulk_job_progress(
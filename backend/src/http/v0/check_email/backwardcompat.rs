@@ -35,18 +35,21 @@ impl BackwardCompatYahooVerifMethod {
 		match self {
 			BackwardCompatYahooVerifMethod::Api => YahooVerifMethod::Api,
 			BackwardCompatYahooVerifMethod::Headless => YahooVerifMethod::Headless,
-			BackwardCompatYahooVerifMethod::Smtp => YahooVerifMethod::Smtp(VerifMethodSmtpConfig {
-				from_email,
-				hello_name,
-				smtp_port,
-				smtp_timeout,
-				proxy: if use_default_proxy {
-					Some(DEFAULT_PROXY_ID.to_string())
-				} else {
-					None
-				},
-				retries,
-			}),
+			BackwardCompatYahooVerifMethod::Smtp => {
+				YahooVerifMethod::Smtp(Box::new(VerifMethodSmtpConfig {
+					from_email,
+					hello_name,
+					smtp_port,
+					smtp_timeout,
+					proxy: if use_default_proxy {
+						Some(DEFAULT_PROXY_ID.to_string())
+					} else {
+						None
+					},
+					retries,
+					..Default::default()
+				}))
+			}
 		}
 	}
 }
@@ -72,7 +75,7 @@ impl BackwardCompatHotmailB2CVerifMethod {
 		match self {
 			BackwardCompatHotmailB2CVerifMethod::Headless => HotmailB2CVerifMethod::Headless,
 			BackwardCompatHotmailB2CVerifMethod::Smtp => {
-				HotmailB2CVerifMethod::Smtp(VerifMethodSmtpConfig {
+				HotmailB2CVerifMethod::Smtp(Box::new(VerifMethodSmtpConfig {
 					from_email,
 					hello_name,
 					smtp_port,
@@ -83,7 +86,8 @@ impl BackwardCompatHotmailB2CVerifMethod {
 						None
 					},
 					retries,
-				})
+					..Default::default()
+				}))
 			}
 		}
 	}
@@ -16,6 +16,7 @@
 
 //! This file implements the `POST /v0/check_email` endpoint.
 
+use check_if_email_exists::score::ClassificationPolicy;
 use check_if_email_exists::smtp::verif_method::VerifMethod;
 use check_if_email_exists::{check_email, CheckEmailInput, CheckEmailInputProxy, LOG_TARGET};
 use serde::{Deserialize, Serialize};
@@ -36,6 +37,8 @@ pub struct CheckEmailRequest {
 	pub proxy: Option<CheckEmailInputProxy>,
 	pub smtp_timeout: Option<Duration>,
 	pub smtp_port: Option<u16>,
+	/// Override the classification policy of the backend config.
+	pub classification_policy: Option<ClassificationPolicy>,
 	// The following fields are for backward compatibility.
 	pub yahoo_verif_method: Option<BackwardCompatYahooVerifMethod>,
 	pub hotmailb2c_verif_method: Option<BackwardCompatHotmailB2CVerifMethod>,
@@ -105,6 +108,16 @@ impl CheckEmailRequest {
 			sentry_dsn: config.sentry_dsn.clone(),
 			backend_name: config.backend_name.clone(),
 			webdriver_config: config.webdriver.clone(),
+			dns: config.dns.clone(),
+			score: config.score.clone(),
+			classification_policy: self
+				.classification_policy
+				.clone()
+				.unwrap_or_else(|| config.classification_policy.clone()),
+			catch_all_cache: config.get_catch_all_cache(),
+			smtp_transcript: config.smtp_transcript.clone(),
+			http_client: config.http_client.clone(),
+			total_timeout: config.total_timeout.map(Duration::from_secs),
 			..Default::default()
 		}
 	}
@@ -60,6 +60,21 @@ struct ResponseSummary {
 	total_unknown: i32,
 }
 
+/// Average time spent in each stage of the verifications processed so far,
+/// in milliseconds. A stage is None if no verification went through it.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct ResponseDurations {
+	syntax: Option<f64>,
+	mx: Option<f64>,
+	misc: Option<f64>,
+	smtp: Option<f64>,
+	smtp_connect: Option<f64>,
+	smtp_greeting: Option<f64>,
+	smtp_catch_all_rcpt: Option<f64>,
+	smtp_rcpt: Option<f64>,
+	smtp_headless: Option<f64>,
+}
+
 /// Complete information about a bulk verification job
 #[derive(Debug, Serialize)]
 struct Response {
@@ -69,9 +84,46 @@ struct Response {
 	total_records: i32,
 	total_processed: i32,
 	summary: ResponseSummary,
+	average_durations_ms: ResponseDurations,
 	job_status: ValidStatus,
 }
 
+/// SQL expression averaging, in milliseconds, the duration at the given path
+/// of the results. Durations are serialized as `{"secs": _, "nanos": _}`.
+fn avg_duration_ms(path: &str) -> String {
+	format!(
+		"AVG((result #>> '{{{path},secs}}')::float8 * 1000 + (result #>> '{{{path},nanos}}')::float8 / 1000000)"
+	)
+}
+
+/// Average the per-stage durations of the job's results.
+async fn average_durations(
+	job_id: i32,
+	conn_pool: &PgPool,
+) -> Result<ResponseDurations, sqlx::Error> {
+	let columns = [
+		("syntax", "debug,durations,syntax"),
+		("mx", "debug,durations,mx"),
+		("misc", "debug,durations,misc"),
+		("smtp", "debug,durations,smtp"),
+		("smtp_connect", "debug,smtp,durations,connect"),
+		("smtp_greeting", "debug,smtp,durations,greeting"),
+		("smtp_catch_all_rcpt", "debug,smtp,durations,catch_all_rcpt"),
+		("smtp_rcpt", "debug,smtp,durations,rcpt"),
+		("smtp_headless", "debug,smtp,durations,headless"),
+	]
+	.iter()
+	.map(|(name, path)| format!("{} AS {name}", avg_duration_ms(path)))
+	.collect::<Vec<_>>()
+	.join(", ");
+
+	let query = format!("SELECT {columns} FROM v1_task_result WHERE job_id = $1");
+	sqlx::query_as(&query)
+		.bind(job_id)
+		.fetch_one(conn_pool)
+		.await
+}
+
 async fn http_handler(job_id: i32, conn_pool: PgPool) -> Result<impl warp::Reply, warp::Rejection> {
 	let job_rec = sqlx::query_as!(
 		JobRecord,
@@ -104,6 +156,10 @@ async fn http_handler(job_id: i32, conn_pool: PgPool) -> Result<impl warp::Reply
 	.await
 	.map_err(ReacherResponseError::from)?;
 
+	let average_durations_ms = average_durations(job_id, &conn_pool)
+		.await
+		.map_err(ReacherResponseError::from)?;
+
 	let (job_status, finished_at) = if (agg_info
 		.total_processed
 		.expect("sql COUNT() returns an int. qed.") as i32)
@@ -141,6 +197,7 @@ async fn http_handler(job_id: i32, conn_pool: PgPool) -> Result<impl warp::Reply
 				.unknown_count
 				.expect("sql COUNT returns an int. qed.") as i32,
 		},
+		average_durations_ms,
 		job_status,
 	}))
 }
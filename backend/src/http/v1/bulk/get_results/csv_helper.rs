@@ -53,6 +53,9 @@ pub struct CsvResponse {
 	#[serde(rename = "syntax.username")]
 	syntax_username: String,
 	error: Option<String>,
+	/// Reason codes explaining `is_reachable`, separated by ";". Last, so
+	/// that the other columns keep their position.
+	reasons: String,
 }
 
 impl TryFrom<CsvWrapper> for CsvResponse {
@@ -74,7 +77,6 @@ impl TryFrom<CsvWrapper> for CsvResponse {
 			.and_then(|v| v.as_str())
 			.ok_or("is_reachable should be a string")?
 			.to_string();
-
 		let misc = top_level
 			.get("misc")
 			.and_then(|v| v.as_object())
@@ -147,6 +149,20 @@ impl TryFrom<CsvWrapper> for CsvResponse {
 
 		let error = top_level.get("error").map(|v| v.to_string());
 
+		// Results stored before reason codes were introduced don't have this
+		// field.
+		let reasons = top_level
+			.get("reasons")
+			.and_then(|v| v.as_array())
+			.map(|reasons| {
+				reasons
+					.iter()
+					.filter_map(|r| r.as_str())
+					.collect::<Vec<_>>()
+					.join(";")
+			})
+			.unwrap_or_default();
+
 		Ok(CsvResponse {
 			input,
 			is_reachable,
@@ -163,6 +179,7 @@ impl TryFrom<CsvWrapper> for CsvResponse {
 			syntax_domain,
 			syntax_username,
 			error,
+			reasons,
 		})
 	}
 }
@@ -18,6 +18,7 @@
 
 use std::sync::Arc;
 
+use check_if_email_exists::score::ClassificationPolicy;
 use check_if_email_exists::LOG_TARGET;
 use futures::stream::StreamExt;
 use futures::stream::TryStreamExt;
@@ -45,6 +46,9 @@ use crate::worker::do_work::TaskWebhook;
 struct Request {
 	input: Vec<String>,
 	webhook: Option<TaskWebhook>,
+	/// Override the classification policy of the backend config for all
+	/// emails of the job.
+	classification_policy: Option<ClassificationPolicy>,
 }
 
 /// POST v1/bulk endpoint response body.
@@ -77,6 +81,7 @@ async fn http_handler(
 
 	let n = body.input.len();
 	let webhook = body.webhook.clone();
+	let classification_policy = body.classification_policy.clone();
 	let stream = futures::stream::iter(body.input.into_iter());
 
 	let properties = BasicProperties::default()
@@ -89,6 +94,7 @@ async fn http_handler(
 		.try_for_each_concurrent(10, |to_email| async {
 			let input = CheckEmailRequest {
 				to_email,
+				classification_policy: classification_policy.clone(),
 				..Default::default()
 			}
 			.to_check_email_input(Arc::clone(&config));
@@ -97,6 +103,7 @@ async fn http_handler(
 				input,
 				job_id: CheckEmailJobId::Bulk(rec.id),
 				webhook: webhook.clone(),
+				deferrals: 0,
 			};
 
 			publish_task(
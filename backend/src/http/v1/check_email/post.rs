@@ -16,7 +16,7 @@
 
 //! This file implements the `POST /v1/check_email` endpoint.
 
-use check_if_email_exists::{check_email, LOG_TARGET};
+use check_if_email_exists::{check_email, CheckEventSender, LOG_TARGET};
 use futures::{StreamExt, TryFutureExt};
 use lapin::options::{
 	BasicAckOptions, BasicConsumeOptions, BasicRejectOptions, QueueDeclareOptions,
@@ -33,17 +33,22 @@ use crate::http::v0::check_email::post::{with_config, CheckEmailRequest};
 use crate::http::v1::bulk::post::publish_task;
 use crate::http::{check_header, ReacherResponseError};
 use crate::storage::commercial_license_trial::send_to_reacher;
+use crate::throttle::ThrottleManager;
 use crate::worker::consume::MAX_QUEUE_PRIORITY;
 use crate::worker::do_work::{CheckEmailJobId, CheckEmailTask};
 use crate::worker::single_shot::SingleShotReply;
 
-async fn handle_without_worker(
+/// Verify the email in this process. The progress of the verification is
+/// emitted on `events`, if set.
+pub(super) async fn handle_without_worker(
 	config: Arc<BackendConfig>,
 	body: &CheckEmailRequest,
-	throttle_manager: &crate::throttle::ThrottleManager,
+	throttle_manager: &ThrottleManager,
+	events: Option<CheckEventSender>,
 ) -> Result<Vec<u8>, warp::Rejection> {
 	info!(target: LOG_TARGET, email=body.to_email, "Starting verification");
-	let input = body.to_check_email_input(Arc::clone(&config));
+	let mut input = body.to_check_email_input(Arc::clone(&config));
+	input.events = events;
 	let result = check_email(&input).await;
 	let result_ok = Ok(result);
 
@@ -58,6 +63,7 @@ async fn handle_without_worker(
 				input: body.to_check_email_input(Arc::clone(&config)),
 				job_id: CheckEmailJobId::SingleShot,
 				webhook: None,
+				deferrals: 0,
 			},
 			&result_ok,
 			storage.get_extra(),
@@ -76,7 +82,8 @@ async fn handle_without_worker(
 	Ok(serde_json::to_vec(&result).map_err(ReacherResponseError::from)?)
 }
 
-async fn handle_with_worker(
+/// Send the email to verify to a worker, and wait for its reply.
+pub(super) async fn handle_with_worker(
 	config: Arc<BackendConfig>,
 	body: &CheckEmailRequest,
 ) -> Result<Vec<u8>, warp::Rejection> {
@@ -114,6 +121,7 @@ async fn handle_with_worker(
 			input: body.to_check_email_input(config.clone()),
 			job_id: CheckEmailJobId::SingleShot,
 			webhook: None,
+			deferrals: 0,
 		},
 		properties,
 	)
@@ -180,11 +188,12 @@ async fn handle_with_worker(
 	.into())
 }
 
-/// The main endpoint handler that implements the logic of this route.
-async fn http_handler(
-	config: Arc<BackendConfig>,
-	body: CheckEmailRequest,
-) -> Result<impl warp::Reply, warp::Rejection> {
+/// Validate the request, and check it doesn't exceed the throttle. Returns
+/// the throttle manager, to count the verification once done.
+pub(super) async fn check_request(
+	config: &BackendConfig,
+	body: &CheckEmailRequest,
+) -> Result<Arc<ThrottleManager>, warp::Rejection> {
 	// The to_email field must be present
 	if body.to_email.is_empty() {
 		return Err(ReacherResponseError::new(
@@ -207,8 +216,18 @@ async fn http_handler(
 		.into());
 	}
 
+	Ok(throttle_manager)
+}
+
+/// The main endpoint handler that implements the logic of this route.
+async fn http_handler(
+	config: Arc<BackendConfig>,
+	body: CheckEmailRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+	let throttle_manager = check_request(&config, &body).await?;
+
 	let result_bz = if !config.worker.enable {
-		handle_without_worker(Arc::clone(&config), &body, &throttle_manager).await?
+		handle_without_worker(Arc::clone(&config), &body, &throttle_manager, None).await?
 	} else {
 		handle_with_worker(Arc::clone(&config), &body).await?
 	};
@@ -0,0 +1,164 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! This file implements the server-sent events flavour of the
+//! `POST /v1/check_email` endpoint, used when the request accepts
+//! `text/event-stream`. Each stage of the verification is sent as an event
+//! as it completes, and the last event contains the result.
+
+use check_if_email_exists::LOG_TARGET;
+use futures::{stream, StreamExt};
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinError;
+use warp::sse::Event;
+use warp::Filter;
+
+use super::post::{check_request, handle_with_worker, handle_without_worker};
+use crate::config::BackendConfig;
+use crate::http::v0::check_email::post::{with_config, CheckEmailRequest};
+use crate::http::{check_header, ReacherResponseError};
+
+/// The last event of the stream: the result of the verification, in the
+/// same format as the JSON response of `POST /v1/check_email`, or the error
+/// which prevented it.
+fn final_event(result: Result<Result<Vec<u8>, warp::Rejection>, JoinError>) -> serde_json::Value {
+	let error = match result {
+		Ok(Ok(output)) => match serde_json::from_slice::<serde_json::Value>(&output) {
+			Ok(output) => return json!({ "type": "done", "output": output }),
+			Err(e) => e.to_string(),
+		},
+		Ok(Err(rejection)) => match rejection.find::<ReacherResponseError>() {
+			Some(e) => e.to_string(),
+			None => format!("{:?}", rejection),
+		},
+		Err(e) => e.to_string(),
+	};
+
+	json!({ "type": "error", "error": error })
+}
+
+/// Whether the `Accept` header of the request lists the `text/event-stream`
+/// media type, e.g. `text/event-stream, */*` or `text/event-stream;
+/// charset=utf-8`, without excluding it with `q=0`. A wildcard alone doesn't
+/// count, so that JSON clients keep getting JSON.
+fn accepts_event_stream(accept: &str) -> bool {
+	accept.split(',').any(|media_range| {
+		let mut parts = media_range.split(';').map(str::trim);
+		let is_event_stream = parts
+			.next()
+			.is_some_and(|media_type| media_type.eq_ignore_ascii_case("text/event-stream"));
+		let is_excluded = parts.any(|param| {
+			let mut kv = param.splitn(2, '=').map(str::trim);
+			matches!(
+				(kv.next(), kv.next().map(str::parse::<f32>)),
+				(Some(key), Some(Ok(q))) if key.eq_ignore_ascii_case("q") && q == 0.0
+			)
+		});
+
+		is_event_stream && !is_excluded
+	})
+}
+
+/// Warp filter which only lets through the requests accepting
+/// `text/event-stream`.
+fn with_event_stream_accepted() -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+	warp::header::<String>("accept")
+		.and_then(|accept: String| async move {
+			if accepts_event_stream(&accept) {
+				Ok(())
+			} else {
+				Err(warp::reject::not_found())
+			}
+		})
+		.untuple_one()
+}
+
+async fn http_handler(
+	config: Arc<BackendConfig>,
+	body: CheckEmailRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+	// Invalid and throttled requests get a plain HTTP error, before the
+	// stream starts.
+	let throttle_manager = check_request(&config, &body).await?;
+
+	// The verification runs in its own task, so that the result is still
+	// stored if the client disconnects.
+	let (tx, rx) = mpsc::unbounded_channel();
+	let task = tokio::spawn(async move {
+		if !config.worker.enable {
+			handle_without_worker(config, &body, &throttle_manager, Some(tx)).await
+		} else {
+			// The workers don't report the progress, only the result.
+			drop(tx);
+			handle_with_worker(config, &body).await
+		}
+	});
+
+	// The channel closes once the verification is done, so the final event
+	// comes after all the others.
+	let events = stream::unfold(rx, |mut rx| async move {
+		rx.recv().await.map(|event| (event, rx))
+	})
+	.map(|event| Event::default().json_data(event));
+	let done = stream::once(async move { Event::default().json_data(final_event(task.await)) });
+
+	Ok(warp::sse::reply(
+		warp::sse::keep_alive().stream(events.chain(done)),
+	))
+}
+
+/// Create the server-sent events flavour of the `POST /v1/check_email`
+/// endpoint. It must be mounted before `v1_check_email`.
+pub fn v1_check_email_sse(
+	config: Arc<BackendConfig>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+	warp::path!("v1" / "check_email")
+		.and(warp::post())
+		.and(with_event_stream_accepted())
+		.and(check_header(Arc::clone(&config)))
+		.and(with_config(config.clone()))
+		// When accepting a body, we want a JSON body (and to reject huge
+		// payloads)...
+		.and(warp::body::content_length_limit(1024 * 16))
+		.and(warp::body::json())
+		.and_then(http_handler)
+		// View access logs by setting `RUST_LOG=reacher`.
+		.with(warp::log(LOG_TARGET))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::accepts_event_stream;
+
+	#[test]
+	fn test_accepts_event_stream() {
+		assert!(accepts_event_stream("text/event-stream"));
+		assert!(accepts_event_stream("Text/Event-Stream"));
+		assert!(accepts_event_stream("text/event-stream, */*"));
+		assert!(accepts_event_stream(
+			"application/json, text/event-stream;q=0.5"
+		));
+		assert!(accepts_event_stream("text/event-stream; charset=utf-8"));
+
+		assert!(!accepts_event_stream("application/json"));
+		assert!(!accepts_event_stream("*/*"));
+		assert!(!accepts_event_stream(
+			"text/event-stream;q=0, application/json"
+		));
+	}
+}
@@ -0,0 +1,79 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use check_if_email_exists::smtp::CatchAllCache;
+use check_if_email_exists::LOG_TARGET;
+use futures::future::BoxFuture;
+use sqlx::PgPool;
+use std::time::Duration;
+use tracing::warn;
+
+/// A catch-all cache stored in the `catch_all_cache` Postgres table, so that
+/// it's shared between all the workers. Database errors are logged and
+/// treated as cache misses.
+#[derive(Debug)]
+pub struct PostgresCatchAllCache {
+	pg_pool: PgPool,
+	ttl: Duration,
+}
+
+impl PostgresCatchAllCache {
+	pub fn new(pg_pool: PgPool, ttl: Duration) -> Self {
+		Self { pg_pool, ttl }
+	}
+}
+
+impl CatchAllCache for PostgresCatchAllCache {
+	fn get<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Option<bool>> {
+		Box::pin(async move {
+			let result: Result<Option<(bool,)>, _> = sqlx::query_as(
+				"SELECT is_catch_all FROM catch_all_cache
+				WHERE domain = $1 AND checked_at > NOW() - make_interval(secs => $2)",
+			)
+			.bind(domain.to_lowercase())
+			.bind(self.ttl.as_secs_f64())
+			.fetch_optional(&self.pg_pool)
+			.await;
+
+			match result {
+				Ok(row) => row.map(|(is_catch_all,)| is_catch_all),
+				Err(err) => {
+					warn!(target: LOG_TARGET, domain=domain, error=?err, "Failed to read catch-all cache");
+					None
+				}
+			}
+		})
+	}
+
+	fn set<'a>(&'a self, domain: &'a str, is_catch_all: bool) -> BoxFuture<'a, ()> {
+		Box::pin(async move {
+			let result = sqlx::query(
+				"INSERT INTO catch_all_cache (domain, is_catch_all, checked_at)
+				VALUES ($1, $2, NOW())
+				ON CONFLICT (domain)
+				DO UPDATE SET is_catch_all = EXCLUDED.is_catch_all, checked_at = EXCLUDED.checked_at",
+			)
+			.bind(domain.to_lowercase())
+			.bind(is_catch_all)
+			.execute(&self.pg_pool)
+			.await;
+
+			if let Err(err) = result {
+				warn!(target: LOG_TARGET, domain=domain, error=?err, "Failed to write catch-all cache");
+			}
+		})
+	}
+}
@@ -14,6 +14,7 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod catch_all_cache;
 pub mod commercial_license_trial;
 pub mod error;
 pub mod postgres;
@@ -21,16 +21,30 @@ use crate::worker::do_work::CheckEmailJobId;
 use anyhow::Context;
 use check_if_email_exists::LOG_TARGET;
 use futures::stream::StreamExt;
-use lapin::{options::*, types::FieldTable, Channel, Connection, ConnectionProperties};
+use lapin::{
+	options::*,
+	types::{AMQPValue, FieldTable},
+	Channel, Connection, ConnectionProperties,
+};
 use sentry_anyhow::capture_anyhow;
 use std::sync::Arc;
 use tracing::{debug, error, info, trace};
 
-/// Our RabbitMQ only has one queue: "check_email".
+/// Our RabbitMQ consumes one queue: "check_email".
 pub const CHECK_EMAIL_QUEUE: &str = "check_email";
 pub const MAX_QUEUE_PRIORITY: u8 = 5;
 
-/// Set up the RabbitMQ connection and declare the "check_email" queue.
+/// Greylisted tasks wait in this queue, without consumer, until they expire
+/// and are dead-lettered back to "check_email". There's one queue per delay,
+/// with a queue-level TTL, so that its messages expire in the order they were
+/// published: RabbitMQ only expires the messages at the head of a queue.
+pub fn check_email_deferred_queue(delay_secs: u64) -> String {
+	format!("check_email.deferred.{}", delay_secs)
+}
+
+/// Set up the RabbitMQ connection and declare the "check_email" queue, and
+/// the "check_email.deferred.<secs>" queue of each delay greylisted tasks are
+/// deferred for.
 ///
 /// The check channel is used to consume messages from the queue. It has a
 /// global prefetch limit set to the concurrency limit.
@@ -39,6 +53,7 @@ pub const MAX_QUEUE_PRIORITY: u8 = 5;
 pub async fn setup_rabbit_mq(
 	backend_name: &str,
 	config: &RabbitMQConfig,
+	defer_greylisted_secs: &[u64],
 ) -> Result<Channel, anyhow::Error> {
 	let options = ConnectionProperties::default()
 		// Use tokio executor and reactor.
@@ -68,6 +83,35 @@ pub async fn setup_rabbit_mq(
 		)
 		.await?;
 
+	// The deferred queues aren't priority queues, so that they stay in
+	// publication order. The priority of the message is kept when it goes
+	// back to "check_email".
+	for &delay_secs in defer_greylisted_secs {
+		let mut deferred_queue_args = FieldTable::default();
+		deferred_queue_args.insert(
+			"x-message-ttl".into(),
+			AMQPValue::LongLongInt((delay_secs * 1000) as i64),
+		);
+		deferred_queue_args.insert(
+			"x-dead-letter-exchange".into(),
+			AMQPValue::LongString("".into()),
+		);
+		deferred_queue_args.insert(
+			"x-dead-letter-routing-key".into(),
+			AMQPValue::LongString(CHECK_EMAIL_QUEUE.into()),
+		);
+		channel
+			.queue_declare(
+				&check_email_deferred_queue(delay_secs),
+				QueueDeclareOptions {
+					durable: true,
+					..Default::default()
+				},
+				deferred_queue_args,
+			)
+			.await?;
+	}
+
 	// Set up prefetch (concurrency) limit using qos
 	channel
 		.basic_qos(
@@ -14,12 +14,15 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use super::consume::check_email_deferred_queue;
 use crate::config::BackendConfig;
 use crate::storage::commercial_license_trial::send_to_reacher;
 use crate::throttle::ThrottleResult;
 use crate::worker::single_shot::send_single_shot_reply;
 use check_if_email_exists::{
-	check_email, CheckEmailInput, CheckEmailOutput, Reachable, LOG_TARGET,
+	check_email,
+	smtp::{SmtpError, SmtpErrorDesc},
+	CheckEmailInput, CheckEmailOutput, Reachable, LOG_TARGET,
 };
 use http::HeaderMap;
 use lapin::message::Delivery;
@@ -33,14 +36,17 @@ use thiserror::Error;
 use tracing::{debug, info};
 use warp::http::StatusCode;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct CheckEmailTask {
 	pub input: CheckEmailInput,
 	pub job_id: CheckEmailJobId,
 	pub webhook: Option<TaskWebhook>,
+	/// Number of times the task was deferred because of greylisting.
+	#[serde(default)]
+	pub deferrals: usize,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum CheckEmailJobId {
 	/// Single-shot email verification, they won't have an actual job id.
@@ -124,11 +130,35 @@ pub(crate) async fn do_check_email_work(
 	channel: Arc<Channel>,
 	config: Arc<BackendConfig>,
 ) -> Result<(), anyhow::Error> {
-	let worker_output = check_email_and_send_result(task).await;
+	let worker_output = check_email_task(task, &config).await;
+
+	if let Some(delay_secs) = get_greylisting_deferral(task, &worker_output, &config) {
+		// Instead of retrying right away, come back once the greylisting
+		// window is hopefully over, without holding a concurrency slot.
+		defer_task(task, &delivery, &channel, delay_secs).await?;
+		delivery.ack(BasicAckOptions::default()).await?;
+		info!(target: LOG_TARGET,
+			email=task.input.to_email,
+			deferrals=task.deferrals + 1,
+			delay_secs=delay_secs,
+			"Deferred greylisted task"
+		);
+		return Ok(());
+	}
+
+	// Only send the final result to the webhook, not the ones we retry.
+	let worker_output = match worker_output {
+		Ok(output) if delivery.redelivered || !is_retryable_unknown(&output) => {
+			send_webhook(task, &output).await.map(|()| output)
+		}
+		worker_output => worker_output,
+	};
 
 	match (&worker_output, delivery.redelivered) {
-		(Ok(output), false) if output.is_reachable == Reachable::Unknown => {
-			// If is_reachable is unknown, then we requeue the message, but only once.
+		(Ok(output), false) if is_retryable_unknown(output) => {
+			// If is_reachable is unknown, then we requeue the message, but only once,
+			// and only if the SMTP error might go away, e.g. greylisting, but not
+			// a blacklisted IP.
 			// We might want to add a requeue counter in the future, see:
 			// https://stackoverflow.com/questions/25226080/rabbitmq-how-to-requeue-message-with-counter
 			delivery
@@ -175,19 +205,100 @@ pub(crate) async fn do_check_email_work(
 	Ok(())
 }
 
+/// Whether the result is unknown because of an SMTP error which might go
+/// away, e.g. greylisting, but not a blacklisted IP.
+fn is_retryable_unknown(output: &CheckEmailOutput) -> bool {
+	output.is_reachable == Reachable::Unknown
+		&& output
+			.smtp
+			.as_ref()
+			.err()
+			.is_none_or(SmtpError::is_retryable)
+}
+
+/// Returns the delay before verifying the task again, in seconds, if it's a
+/// bulk verification task which failed because of greylisting, and wasn't
+/// deferred more often than configured.
+fn get_greylisting_deferral(
+	task: &CheckEmailTask,
+	worker_output: &Result<CheckEmailOutput, TaskError>,
+	config: &BackendConfig,
+) -> Option<u64> {
+	let is_greylisted = matches!(
+		worker_output,
+		Ok(output) if output.is_reachable == Reachable::Unknown
+			&& matches!(
+				output.smtp.as_ref().err().and_then(SmtpError::get_description),
+				Some(SmtpErrorDesc::Greylisted)
+			)
+	);
+	match task.job_id {
+		CheckEmailJobId::Bulk(_) if is_greylisted => config
+			.worker
+			.defer_greylisted_secs
+			.get(task.deferrals)
+			.copied(),
+		_ => None,
+	}
+}
+
+/// Publish the task to the "check_email.deferred.<secs>" queue of the given
+/// delay, from which it goes back to the "check_email" queue once expired.
+async fn defer_task(
+	task: &CheckEmailTask,
+	delivery: &Delivery,
+	channel: &Channel,
+	delay_secs: u64,
+) -> Result<(), anyhow::Error> {
+	let task_json = serde_json::to_vec(&CheckEmailTask {
+		deferrals: task.deferrals + 1,
+		..task.clone()
+	})?;
+	channel
+		.basic_publish(
+			"",
+			&check_email_deferred_queue(delay_secs),
+			BasicPublishOptions::default(),
+			&task_json,
+			delivery.properties.clone(),
+		)
+		.await?
+		.await?;
+
+	Ok(())
+}
+
 /// Checks the email and sends the result to the webhook.
 pub async fn check_email_and_send_result(
 	task: &CheckEmailTask,
+	config: &BackendConfig,
 ) -> Result<CheckEmailOutput, TaskError> {
-	let output = check_email(&task.input).await;
+	let output = check_email_task(task, config).await?;
+	send_webhook(task, &output).await?;
 
-	// Check if we have a webhook to send the output to.
+	Ok(output)
+}
+
+/// Checks the email of the task.
+async fn check_email_task(
+	task: &CheckEmailTask,
+	config: &BackendConfig,
+) -> Result<CheckEmailOutput, TaskError> {
+	// The catch-all cache isn't serialized in the task, use the worker's one.
+	let mut input = task.input.clone();
+	input.catch_all_cache = config.get_catch_all_cache();
+
+	Ok(check_email(&input).await)
+}
+
+/// Sends the result to the task's webhook, if any.
+async fn send_webhook(task: &CheckEmailTask, output: &CheckEmailOutput) -> Result<(), TaskError> {
 	if let Some(TaskWebhook {
 		on_each_email: Some(webhook),
 	}) = &task.webhook
 	{
 		let webhook_output = WebhookOutput {
-			result: &output,
+			result: output,
 			extra: &webhook.extra,
 		};
 
@@ -205,5 +316,5 @@ pub async fn check_email_and_send_result(
 		debug!(target: LOG_TARGET, email=?webhook_output.result.input,res=?res, "Received webhook response");
 	}
 
-	Ok(output)
+	Ok(())
 }
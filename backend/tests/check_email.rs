@@ -23,8 +23,8 @@ mod tests {
 	use warp::http::StatusCode;
 	use warp::test::request;
 
-	const FOO_BAR_RESPONSE: &str = r#"{"input":"foo@bar","is_reachable":"invalid","misc":{"is_disposable":false,"is_role_account":false,"is_b2c":false,"gravatar_url":null,"haveibeenpwned":null},"mx":{"accepts_mail":false,"records":[]},"smtp":{"can_connect_smtp":false,"has_full_inbox":false,"is_catch_all":false,"is_deliverable":false,"is_disabled":false},"syntax":{"address":null,"domain":"","is_valid_syntax":false,"username":"","normalized_email":null,"suggestion":null}"#;
-	const FOO_BAR_BAZ_RESPONSE: &str = r#"{"input":"foo@bar.baz","is_reachable":"invalid","misc":{"is_disposable":false,"is_role_account":false,"is_b2c":false,"gravatar_url":null,"haveibeenpwned":null},"mx":{"accepts_mail":false,"records":[]},"smtp":{"can_connect_smtp":false,"has_full_inbox":false,"is_catch_all":false,"is_deliverable":false,"is_disabled":false},"syntax":{"address":"foo@bar.baz","domain":"bar.baz","is_valid_syntax":true,"username":"foo","normalized_email":"foo@bar.baz","suggestion":null}"#;
+	const FOO_BAR_RESPONSE: &str = r#"{"input":"foo@bar","is_reachable":"invalid","misc":{"is_disposable":false,"is_role_account":false,"is_b2c":false,"gravatar_url":null,"haveibeenpwned":null},"mx":{"accepts_mail":false,"records":[],"kind":"no_records"},"smtp":{"can_connect_smtp":false,"has_full_inbox":false,"is_catch_all":false,"is_deliverable":false,"is_disabled":false,"rcpt_reply":null,"tls":{"negotiated":false,"protocol_version":null,"certificate_valid":null}},"syntax":{"address":null,"domain":"","is_valid_syntax":false,"username":"","normalized_email":null,"suggestion":null}"#;
+	const FOO_BAR_BAZ_RESPONSE: &str = r#"{"input":"foo@bar.baz","is_reachable":"invalid","misc":{"is_disposable":false,"is_role_account":false,"is_b2c":false,"gravatar_url":null,"haveibeenpwned":null},"mx":{"accepts_mail":false,"records":[],"kind":"no_records"},"smtp":{"can_connect_smtp":false,"has_full_inbox":false,"is_catch_all":false,"is_deliverable":false,"is_disabled":false,"rcpt_reply":null,"tls":{"negotiated":false,"protocol_version":null,"certificate_valid":null}},"syntax":{"address":"foo@bar.baz","domain":"bar.baz","is_valid_syntax":true,"username":"foo","normalized_email":"foo@bar.baz","suggestion":null}"#;
 
 	fn create_backend_config(header_secret: &str) -> Arc<BackendConfig> {
 		let mut config = BackendConfig::empty();
@@ -123,4 +123,29 @@ mod tests {
 		assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{:?}", resp.body());
 		assert_eq!(resp.body(), r#"{"error":"to_email field is required."}"#);
 	}
+
+	#[tokio::test]
+	async fn test_v1_check_email_event_stream() {
+		let resp = request()
+			.path("/v1/check_email")
+			.method("POST")
+			.header(REACHER_SECRET_HEADER, "foobar")
+			.header("accept", "text/event-stream")
+			.json(&serde_json::from_str::<CheckEmailRequest>(r#"{"to_email": "foo@bar"}"#).unwrap())
+			.reply(&create_routes(create_backend_config("foobar")))
+			.await;
+
+		assert_eq!(resp.status(), StatusCode::OK, "{:?}", resp.body());
+		assert_eq!(resp.headers()["content-type"], "text/event-stream");
+		let body = std::str::from_utf8(resp.body()).unwrap();
+		let events = body
+			.lines()
+			.filter_map(|line| line.strip_prefix("data:"))
+			.map(|data| serde_json::from_str::<serde_json::Value>(data).unwrap())
+			.collect::<Vec<_>>();
+		assert_eq!(events.len(), 2, "{}", body);
+		assert_eq!(events[0]["type"], "syntax_checked");
+		assert_eq!(events[1]["type"], "done");
+		assert_eq!(events[1]["output"]["is_reachable"], "invalid");
+	}
 }
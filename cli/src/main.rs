@@ -15,7 +15,9 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use check_if_email_exists::{
-	check_email, smtp::verif_method::VerifMethod, CheckEmailInputBuilder, CheckEmailInputProxy,
+	check_email,
+	smtp::{load_smtp_patterns, verif_method::VerifMethod},
+	CheckEmailInputBuilder, CheckEmailInputProxy, ProxyType,
 };
 use clap::Parser;
 use once_cell::sync::Lazy;
@@ -51,6 +53,11 @@ pub struct Cli {
 	#[clap(long, env)]
 	pub proxy_password: Option<String>,
 
+	/// Use an HTTP proxy with the CONNECT method instead of a SOCKS5 proxy.
+	/// Only used when `--proxy-host` flag is set.
+	#[clap(long, env, default_value = "false", parse(try_from_str))]
+	pub proxy_http_connect: bool,
+
 	/// The port to use for the SMTP request.
 	#[clap(long, env, default_value = "25")]
 	pub smtp_port: u16,
@@ -63,6 +70,11 @@ pub struct Cli {
 	#[clap(long, env, parse(try_from_str))]
 	pub haveibeenpwned_api_key: Option<String>,
 
+	/// Path to a JSON file with additional SMTP reply patterns, in the same
+	/// format as the built-in `patterns.json`.
+	#[clap(long, env)]
+	pub smtp_patterns_path: Option<String>,
+
 	/// The email to check.
 	pub to_email: String,
 }
@@ -76,10 +88,19 @@ async fn main() -> Result<(), anyhow::Error> {
 
 	let to_email = &CONF.to_email;
 
+	if let Some(path) = &CONF.smtp_patterns_path {
+		load_smtp_patterns(path)?;
+	}
+
 	let proxy = CONF
 		.proxy_host
 		.as_ref()
 		.map(|proxy_host| CheckEmailInputProxy {
+			proxy_type: if CONF.proxy_http_connect {
+				ProxyType::HttpConnect
+			} else {
+				ProxyType::Socks5
+			},
 			host: proxy_host.clone(),
 			port: CONF.proxy_port,
 			username: CONF.proxy_username.clone(),
@@ -0,0 +1,424 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Verify a stream of emails concurrently.
+
+use crate::mx::MxDetails;
+use crate::smtp::SmtpSessionPool;
+use crate::syntax::check_syntax;
+use crate::util::input_output::{CheckEmailInput, CheckEmailOutput};
+use crate::{check_email_with_mx, lookup_mx, LOG_TARGET};
+use futures::stream::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OnceCell, Semaphore, SemaphorePermit};
+
+/// Configuration of the [check_emails](crate::check_emails) function.
+#[derive(Debug, Clone, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct BatchConfig {
+	/// Maximum number of emails verified at the same time.
+	///
+	/// Defaults to 10.
+	pub concurrency: usize,
+	/// Maximum number of emails verified at the same time against the same
+	/// MX host, e.g. to avoid hammering a corporate mail server. Emails on
+	/// different domains sharing the same MX host share this limit.
+	///
+	/// Defaults to 2.
+	pub per_mx_concurrency: usize,
+	/// Whether to reuse SMTP connections to the same MX host, sending several
+	/// RCPT TO over one connection, and probing catch-all once per session
+	/// and domain. Inputs which already have a `smtp_session_pool` keep it.
+	///
+	/// Defaults to false.
+	pub reuse_smtp_sessions: bool,
+}
+
+impl Default for BatchConfig {
+	fn default() -> Self {
+		Self {
+			concurrency: 10,
+			per_mx_concurrency: 2,
+			reuse_smtp_sessions: false,
+		}
+	}
+}
+
+/// State shared by all the verifications of a batch.
+#[derive(Debug)]
+struct BatchState {
+	/// Limits the number of emails verified, or whose MX is looked up, at the
+	/// same time.
+	concurrency: Semaphore,
+	per_mx_concurrency: usize,
+	/// MX lookups, by domain. Concurrent verifications on the same domain
+	/// wait for the same lookup. Failed lookups are stored as None, and each
+	/// verification then retries the lookup on its own.
+	mx: Mutex<HashMap<String, Arc<OnceCell<Option<MxDetails>>>>>,
+	/// Semaphores limiting the concurrency per MX host.
+	semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+	/// SMTP sessions shared by the batch, if enabled.
+	smtp_session_pool: Option<Arc<SmtpSessionPool>>,
+}
+
+impl BatchState {
+	async fn get_mx(&self, input: &CheckEmailInput, domain: &str) -> Option<MxDetails> {
+		let cell = {
+			let mut mx = self.mx.lock().expect("MX mutex is not poisoned. qed.");
+			Arc::clone(mx.entry(domain.to_lowercase()).or_default())
+		};
+
+		cell.get_or_init(|| async {
+			let syntax = check_syntax(&input.to_email);
+			lookup_mx(input, &syntax).await.ok()
+		})
+		.await
+		.clone()
+	}
+
+	fn get_semaphore(&self, key: String) -> Arc<Semaphore> {
+		let mut semaphores = self
+			.semaphores
+			.lock()
+			.expect("Semaphores mutex is not poisoned. qed.");
+		Arc::clone(
+			semaphores
+				.entry(key)
+				.or_insert_with(|| Arc::new(Semaphore::new(self.per_mx_concurrency))),
+		)
+	}
+
+	async fn check(&self, mut input: CheckEmailInput) -> CheckEmailOutput {
+		if input.smtp_session_pool.is_none() {
+			input.smtp_session_pool = self.smtp_session_pool.clone();
+		}
+
+		let syntax = check_syntax(&input.to_email);
+		// Let check_email handle the invalid syntax case.
+		if !syntax.is_valid_syntax {
+			let _permit = self.acquire_global().await;
+			return check_email_with_mx(&input, None).await;
+		}
+
+		let mx = {
+			let _permit = self.acquire_global().await;
+			self.get_mx(&input, &syntax.domain).await
+		};
+		let key = mx
+			.as_ref()
+			.and_then(|mx| mx.primary_host())
+			.map(|host| host.to_lowercase().to_string())
+			.unwrap_or_else(|| syntax.domain.to_lowercase());
+
+		// Wait for the per-MX permit before the global one, so that emails on
+		// a busy MX host don't hold global slots which emails on other MX
+		// hosts could use.
+		let semaphore = self.get_semaphore(key);
+		// The semaphore is never closed.
+		let _mx_permit = semaphore
+			.acquire()
+			.await
+			.expect("Semaphore is not closed. qed.");
+
+		tracing::debug!(
+			target: LOG_TARGET,
+			email=%input.to_email,
+			available_permits=semaphore.available_permits(),
+			"Acquired per-MX permit"
+		);
+
+		let _permit = self.acquire_global().await;
+		check_email_with_mx(&input, mx).await
+	}
+
+	async fn acquire_global(&self) -> SemaphorePermit<'_> {
+		// The semaphore is never closed.
+		self.concurrency
+			.acquire()
+			.await
+			.expect("Semaphore is not closed. qed.")
+	}
+}
+
+/// Verify a stream of emails, and yield the results as they finish, which
+/// may not be in the order of the inputs.
+///
+/// MX lookups are shared between emails on the same domain, using the DNS
+/// resolver of the first email of that domain. The number of concurrent
+/// verifications is limited globally and per MX host, as configured in
+/// `config`. An email waiting for a per-MX permit doesn't count in the
+/// global limit, but at most `concurrency * per_mx_concurrency` inputs are
+/// pulled from the stream at the same time, so that a large stream isn't
+/// loaded in memory.
+pub fn check_emails<S>(inputs: S, config: BatchConfig) -> impl Stream<Item = CheckEmailOutput>
+where
+	S: Stream<Item = CheckEmailInput>,
+{
+	let smtp_session_pool = config
+		.reuse_smtp_sessions
+		.then(|| Arc::new(SmtpSessionPool::new()));
+	let concurrency = config.concurrency.max(1);
+	let per_mx_concurrency = config.per_mx_concurrency.max(1);
+	let state = Arc::new(BatchState {
+		concurrency: Semaphore::new(concurrency),
+		per_mx_concurrency,
+		mx: Mutex::default(),
+		semaphores: Mutex::default(),
+		smtp_session_pool: smtp_session_pool.clone(),
+	});
+
+	// Once all emails are verified, close the remaining SMTP sessions.
+	let close_sessions = futures::stream::once(async move {
+		if let Some(pool) = smtp_session_pool {
+			pool.close().await;
+		}
+	})
+	.filter_map(|_| futures::future::ready(None));
+
+	inputs
+		.map(move |input| {
+			let state = Arc::clone(&state);
+			async move { state.check(input).await }
+		})
+		// The concurrency is limited by the semaphores of the state. The
+		// inputs waiting for a per-MX permit are limited here, so that they
+		// don't use up the memory.
+		.buffer_unordered(concurrency.saturating_mul(per_mx_concurrency))
+		.chain(close_sessions)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::mx::resolver::{DnsResolver, InMemoryResolver};
+	use crate::reason::ReasonCode;
+	use crate::smtp::verif_method::{EverythingElseVerifMethod, VerifMethodSmtpConfig};
+	use crate::util::input_output::{CheckEmailInputBuilder, Reachable};
+	use futures::future::BoxFuture;
+	use hickory_proto::rr::Name;
+	use hickory_resolver::error::ResolveError;
+	use hickory_resolver::lookup::MxLookup;
+	use std::net::IpAddr;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::time::Duration;
+	use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufStream};
+	use tokio::net::TcpListener;
+
+	/// An in-memory resolver counting the MX lookups of each domain.
+	#[derive(Debug)]
+	struct CountingResolver {
+		inner: InMemoryResolver,
+		mx_lookups: Mutex<HashMap<String, usize>>,
+	}
+
+	impl DnsResolver for CountingResolver {
+		fn lookup_mx<'a>(
+			&'a self,
+			domain: &'a str,
+		) -> BoxFuture<'a, Result<MxLookup, ResolveError>> {
+			*self
+				.mx_lookups
+				.lock()
+				.unwrap()
+				.entry(domain.to_string())
+				.or_default() += 1;
+			self.inner.lookup_mx(domain)
+		}
+
+		fn lookup_host<'a>(
+			&'a self,
+			host: &'a Name,
+		) -> BoxFuture<'a, Result<Vec<IpAddr>, ResolveError>> {
+			self.inner.lookup_host(host)
+		}
+	}
+
+	/// A fake SMTP server accepting all emails, which waits `delay` before
+	/// replying to RCPT TO. Returns its port, and the peak number of
+	/// connections open at the same time.
+	async fn fake_smtp_server(delay: Duration) -> (u16, Arc<AtomicUsize>) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let active = Arc::new(AtomicUsize::new(0));
+		let peak = Arc::new(AtomicUsize::new(0));
+
+		let p = Arc::clone(&peak);
+		tokio::spawn(async move {
+			loop {
+				let (stream, _) = listener.accept().await.unwrap();
+				let (active, peak) = (Arc::clone(&active), Arc::clone(&p));
+				tokio::spawn(async move {
+					peak.fetch_max(active.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+					let mut stream = BufStream::new(stream);
+					stream.write_all(b"220 localhost\r\n").await.unwrap();
+					stream.flush().await.unwrap();
+					let mut line = String::new();
+					while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+						let cmd = line.to_lowercase();
+						if cmd.starts_with("rcpt") {
+							tokio::time::sleep(delay).await;
+						}
+						let reply: &[u8] = if cmd.starts_with("quit") {
+							b"221 Bye\r\n"
+						} else if cmd.starts_with("starttls") {
+							b"502 5.5.1 Command not implemented\r\n"
+						} else {
+							b"250 OK\r\n"
+						};
+						if stream.write_all(reply).await.is_err() || stream.flush().await.is_err() {
+							break;
+						}
+						if cmd.starts_with("quit") {
+							break;
+						}
+						line.clear();
+					}
+					active.fetch_sub(1, Ordering::SeqCst);
+				});
+			}
+		});
+
+		(port, peak)
+	}
+
+	#[tokio::test]
+	async fn should_check_all_emails() {
+		// Domains without MX records return early, without SMTP.
+		let resolver: Arc<dyn DnsResolver> =
+			Arc::new(InMemoryResolver::new().with_mx("null.com", 0, "."));
+		let inputs = vec!["foo@null.com", "bar@null.com", "not-an-email"]
+			.into_iter()
+			.map(|email| {
+				CheckEmailInputBuilder::default()
+					.to_email(email.into())
+					.dns_resolver(Some(resolver.clone()))
+					.build()
+					.unwrap()
+			});
+
+		let mut outputs: Vec<CheckEmailOutput> =
+			check_emails(futures::stream::iter(inputs), BatchConfig::default())
+				.collect()
+				.await;
+		outputs.sort_by(|a, b| a.input.cmp(&b.input));
+
+		assert_eq!(outputs.len(), 3);
+		assert_eq!(outputs[0].input, "bar@null.com");
+		assert_eq!(outputs[0].reasons, vec![ReasonCode::NullMx]);
+		assert_eq!(outputs[1].is_reachable, Reachable::Invalid);
+		assert_eq!(outputs[2].reasons, vec![ReasonCode::InvalidSyntax]);
+	}
+
+	#[tokio::test]
+	async fn should_not_pull_whole_stream() {
+		let (port, _) = fake_smtp_server(Duration::from_millis(100)).await;
+		let resolver: Arc<dyn DnsResolver> =
+			Arc::new(InMemoryResolver::new().with_mx("slow.com", 10, "localhost."));
+		let pulled = Arc::new(AtomicUsize::new(0));
+		// An endless stream of emails on the same MX host.
+		let inputs = futures::stream::iter(0..).map({
+			let pulled = Arc::clone(&pulled);
+			move |i| {
+				pulled.fetch_add(1, Ordering::SeqCst);
+				let mut input = CheckEmailInputBuilder::default()
+					.to_email(format!("foo{}@slow.com", i))
+					.dns_resolver(Some(Arc::clone(&resolver)))
+					.build()
+					.unwrap();
+				input.verif_method.everything_else =
+					EverythingElseVerifMethod::Smtp(VerifMethodSmtpConfig {
+						smtp_port: port,
+						..Default::default()
+					});
+				input
+			}
+		});
+		let config = BatchConfig {
+			concurrency: 2,
+			per_mx_concurrency: 1,
+			reuse_smtp_sessions: false,
+		};
+
+		let outputs: Vec<CheckEmailOutput> = check_emails(inputs, config).take(3).collect().await;
+
+		assert_eq!(outputs.len(), 3);
+		// At most 2 * 1 inputs are in flight, plus the ones already done.
+		assert!(pulled.load(Ordering::SeqCst) <= 2 + 3);
+	}
+
+	#[tokio::test]
+	async fn should_not_block_other_mx_hosts() {
+		let (slow_port, slow_peak) = fake_smtp_server(Duration::from_millis(200)).await;
+		let (fast_port, fast_peak) = fake_smtp_server(Duration::ZERO).await;
+		// Two domains on the slow MX host, one on the fast one.
+		let resolver = Arc::new(CountingResolver {
+			inner: InMemoryResolver::new()
+				.with_mx("slow1.com", 10, "localhost.")
+				.with_mx("slow2.com", 10, "localhost.")
+				.with_mx("fast.com", 10, "127.0.0.1."),
+			mx_lookups: Mutex::default(),
+		});
+		let dns_resolver: Arc<dyn DnsResolver> = resolver.clone();
+
+		// The email on the fast MX host comes last.
+		let emails = (0..6)
+			.map(|i| format!("foo{}@slow{}.com", i, i % 2 + 1))
+			.chain(std::iter::once("foo@fast.com".to_string()));
+		let inputs = emails.map(|email| {
+			let port = if email.ends_with("@fast.com") {
+				fast_port
+			} else {
+				slow_port
+			};
+			let mut input = CheckEmailInputBuilder::default()
+				.to_email(email)
+				.dns_resolver(Some(Arc::clone(&dns_resolver)))
+				.build()
+				.unwrap();
+			input.verif_method.everything_else =
+				EverythingElseVerifMethod::Smtp(VerifMethodSmtpConfig {
+					smtp_port: port,
+					..Default::default()
+				});
+			input
+		});
+		// All 7 inputs fit in the 4 * 2 inputs pulled at the same time.
+		let config = BatchConfig {
+			concurrency: 4,
+			per_mx_concurrency: 2,
+			reuse_smtp_sessions: false,
+		};
+
+		let outputs: Vec<CheckEmailOutput> = check_emails(futures::stream::iter(inputs), config)
+			.collect()
+			.await;
+
+		assert_eq!(outputs.len(), 7);
+		// The emails waiting for the slow MX host don't hold the global slot
+		// the fast one needs.
+		assert_eq!(outputs[0].input, "foo@fast.com");
+		// The fake servers accept any email, so they're catch-all.
+		assert!(outputs.iter().all(|o| o.is_reachable == Reachable::Risky));
+		assert_eq!(slow_peak.load(Ordering::SeqCst), 2);
+		assert_eq!(fast_peak.load(Ordering::SeqCst), 1);
+
+		let mx_lookups = resolver.mx_lookups.lock().unwrap();
+		assert_eq!(mx_lookups.len(), 3);
+		assert!(mx_lookups.values().all(|count| *count == 1));
+	}
+}
@@ -0,0 +1,115 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Events emitted as the verification of an email progresses, e.g. to show
+//! each stage in a UI as it completes.
+
+use crate::smtp::verif_method::EmailProvider;
+use crate::syntax::SyntaxDetails;
+use crate::{check_email, CheckEmailInput, CheckEmailOutput};
+use futures::{future, stream, Stream, StreamExt};
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+/// An event emitted during the verification of an email.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CheckEvent {
+	/// The syntax of the email was checked.
+	SyntaxChecked { syntax: SyntaxDetails },
+	/// The MX hosts of the domain were found, ordered by preference.
+	MxFound { hosts: Vec<String> },
+	/// The email provider was detected from an MX host, which determines
+	/// the verification method.
+	ProviderDetected {
+		mx_host: String,
+		provider: EmailProvider,
+	},
+	/// An SMTP session is ready to verify the email, either a new connection
+	/// or one reused from the session pool.
+	SmtpConnected {
+		mx_host: String,
+		port: u16,
+		reused_session: bool,
+	},
+	/// The catch-all status of the domain was determined.
+	CatchAllDetermined { domain: String, is_catch_all: bool },
+	/// The verification is done. Only emitted by `check_email_stream`, as
+	/// `check_email` returns the output.
+	Done { output: Box<CheckEmailOutput> },
+}
+
+/// The sending half of the channel events are emitted on, see
+/// `CheckEmailInput.events`.
+pub type CheckEventSender = mpsc::UnboundedSender<CheckEvent>;
+
+/// Emit an event, if the input has a channel for them. A closed channel is
+/// ignored, the verification goes on without anyone listening.
+pub(crate) fn emit(events: Option<&CheckEventSender>, event: impl FnOnce() -> CheckEvent) {
+	if let Some(events) = events {
+		let _ = events.send(event());
+	}
+}
+
+/// Verify a single email like [check_email](check_email), returning a stream
+/// of the events emitted as the verification progresses, ending with a
+/// `CheckEvent::Done` containing the output.
+///
+/// The verification runs as the stream is polled, and is cancelled if the
+/// stream is dropped.
+pub fn check_email_stream(input: &CheckEmailInput) -> impl Stream<Item = CheckEvent> {
+	let (tx, rx) = mpsc::unbounded_channel();
+	let input = CheckEmailInput {
+		events: Some(tx.clone()),
+		..input.clone()
+	};
+
+	// The output is sent on the same channel, so that it comes after all
+	// the other events. The channel closes once the input is dropped.
+	let check = stream::once(async move {
+		let output = check_email(&input).await;
+		let _ = tx.send(CheckEvent::Done {
+			output: Box::new(output),
+		});
+	})
+	.filter_map(|()| future::ready(None));
+	let events = stream::unfold(rx, |mut rx| async move {
+		rx.recv().await.map(|event| (event, rx))
+	});
+
+	stream::select(events, check)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn should_end_with_done() {
+		let input = CheckEmailInput {
+			to_email: "foo".into(),
+			..Default::default()
+		};
+
+		let events = check_email_stream(&input).collect::<Vec<_>>().await;
+		assert_eq!(events.len(), 2);
+		assert!(matches!(
+			&events[0],
+			CheckEvent::SyntaxChecked { syntax } if !syntax.is_valid_syntax
+		));
+		assert!(matches!(&events[1], CheckEvent::Done { .. }));
+	}
+}
@@ -14,8 +14,8 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::LOG_TARGET;
-use reqwest::Client;
+use crate::util::http_client::create_client;
+use crate::{CheckEmailInput, LOG_TARGET};
 
 const MAIN_API_URL: &str = "https://haveibeenpwned.com/api/v3/";
 
@@ -23,8 +23,22 @@ const MAIN_API_URL: &str = "https://haveibeenpwned.com/api/v3/";
 /// HaveIBeenPwned API.
 /// This function will return the number of times the email has been found in
 /// any breach.
-pub async fn check_haveibeenpwned(to_email: &str, api_key: Option<String>) -> Option<bool> {
-	let client = Client::new();
+pub async fn check_haveibeenpwned(
+	to_email: &str,
+	api_key: &str,
+	input: &CheckEmailInput,
+) -> Option<bool> {
+	let client = match create_client(input, "haveibeenpwned") {
+		Ok(client) => client,
+		Err(e) => {
+			tracing::error!(
+				target: LOG_TARGET,
+				error=?e,
+				"Error creating HaveIBeenPwned client"
+			);
+			return None;
+		}
+	};
 	let url = format!(
 		"{}breachedaccount/{}?truncateResponse=false",
 		MAIN_API_URL, to_email
@@ -32,8 +46,7 @@ pub async fn check_haveibeenpwned(to_email: &str, api_key: Option<String>) -> Op
 
 	let request = client
 		.get(&url)
-		.header("User-Agent", "reacher")
-		.header("hibp-api-key", api_key.unwrap_or_default())
+		.header("hibp-api-key", api_key)
 		.send()
 		.await;
 
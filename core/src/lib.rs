@@ -34,7 +34,7 @@
 //! - Catch-all address. Is this email address a catch-all address?
 //!
 //! ```rust
-//! use check_if_email_exists::{check_email, CheckEmailInputBuilder, CheckEmailInputProxy};
+//! use check_if_email_exists::{check_email, CheckEmailInputBuilder, CheckEmailInputProxy, ProxyType};
 //! use check_if_email_exists::smtp::verif_method::{VerifMethod, VerifMethodSmtpConfig, GmailVerifMethod};
 //! use std::collections::HashMap;
 //!
@@ -46,6 +46,7 @@
 //!     // proxy, you can do so like this:
 //!     let mut proxies = HashMap::new();
 //!     proxies.insert("proxy1".to_string(), CheckEmailInputProxy {
+//!         proxy_type: ProxyType::Socks5,               // Or ProxyType::HttpConnect for an HTTP proxy
 //!         host: "my-proxy.io".to_string(),             // Use a SOCKS5 proxy to verify the email
 //!         port: 1080,
 //!         username: None,                              // You can also set it non-empty
@@ -79,22 +80,35 @@
 //! }
 //! ```
 
+mod batch;
+mod events;
 mod haveibeenpwned;
 pub mod misc;
 pub mod mx;
+pub mod reason;
 mod rules;
+pub mod score;
 pub mod smtp;
 pub mod syntax;
 mod util;
 
-use misc::{check_misc, MiscDetails};
-use mx::check_mx;
+pub use batch::{check_emails, BatchConfig};
+use events::emit;
+pub use events::{check_email_stream, CheckEvent, CheckEventSender};
+use misc::{check_misc, MiscDetails, MiscError};
+use mx::resolver::get_resolver;
+use mx::{check_mx, MxDetails, MxError, MxKind};
+use reason::{find_reasons, ReasonCode};
 use rustls::crypto::ring;
-use smtp::{check_smtp, SmtpDetails, SmtpError};
+use score::{calculate_score, ClassificationPolicy, Score, ScoreConfig};
+use smtp::{check_smtp_with_mx_fallback, SmtpDebug, SmtpDetails, SmtpError};
 pub use smtp::{is_gmail, is_hotmail, is_hotmail_b2b, is_hotmail_b2c, is_yahoo};
+use std::future::Future;
 use std::sync::Once;
-use std::time::{Duration, SystemTime};
-use syntax::{check_syntax, get_similar_mail_provider};
+use std::time::{Duration, Instant, SystemTime};
+use syntax::{check_syntax, get_similar_mail_provider, SyntaxDetails};
+pub use util::http_client::HttpClientConfig;
+use util::input_output::add_elapsed;
 pub use util::input_output::*;
 #[cfg(feature = "sentry")]
 pub use util::sentry::*;
@@ -112,25 +126,20 @@ pub fn initialize_crypto_provider() {
 	});
 }
 
-/// Given an email's misc and smtp details, calculate an estimate of our
-/// confidence on how reachable the email is.
-///
-/// Maybe we can switch to a points-based system?
-/// ref: https://github.com/reacherhq/check-if-email-exists/issues/935
-fn calculate_reachable(misc: &MiscDetails, smtp: &Result<SmtpDetails, SmtpError>) -> Reachable {
-	if let Ok(smtp) = smtp {
-		if misc.is_disposable || misc.is_role_account || smtp.is_catch_all || smtp.has_full_inbox {
-			return Reachable::Risky;
-		}
-
-		if !smtp.is_deliverable || !smtp.can_connect_smtp || smtp.is_disabled {
-			return Reachable::Invalid;
-		}
+/// Given an email's misc and smtp details, calculate its confidence score,
+/// and derive from it and the classification policy an estimate of how
+/// reachable the email is, along with the reasons explaining it.
+fn calculate_reachable(
+	misc: &MiscDetails,
+	smtp: &Result<SmtpDetails, SmtpError>,
+	smtp_debug: &SmtpDebug,
+	config: &ScoreConfig,
+	policy: &ClassificationPolicy,
+) -> (Reachable, Score, Vec<ReasonCode>) {
+	let score = calculate_score(misc, smtp, smtp_debug, config, policy);
+	let reachable = policy.apply(config.reachable(&score, smtp.is_ok()), &score);
 
-		Reachable::Safe
-	} else {
-		Reachable::Unknown
-	}
+	(reachable, score, find_reasons(misc, smtp))
 }
 
 /// The main function of this library: verify a single email. Performs, in the
@@ -143,9 +152,91 @@ fn calculate_reachable(misc: &MiscDetails, smtp: &Result<SmtpDetails, SmtpError>
 ///
 /// Returns a `CheckEmailOutput` output, whose `is_reachable` field is one of
 /// `Safe`, `Invalid`, `Risky` or `Unknown`.
+///
+/// To verify many emails, see [check_emails](check_emails). To follow the
+/// progress of the verification, see [check_email_stream](check_email_stream).
 pub async fn check_email(input: &CheckEmailInput) -> CheckEmailOutput {
+	check_email_with_mx(input, None).await
+}
+
+/// Look up the MX records of the email's domain, using the DNS resolver of
+/// the input.
+async fn lookup_mx(input: &CheckEmailInput, syntax: &SyntaxDetails) -> Result<MxDetails, MxError> {
+	let resolver = get_resolver(input)?;
+	check_mx(syntax, resolver.as_ref()).await
+}
+
+/// Run a stage of the verification, unless the deadline set by
+/// `CheckEmailInput.total_timeout` passes first, in which case the stage is
+/// returned as error.
+async fn run_stage<F: Future>(
+	deadline: Option<tokio::time::Instant>,
+	stage: CheckStage,
+	f: F,
+) -> Result<F::Output, CheckStage> {
+	match deadline {
+		Some(deadline) => tokio::time::timeout_at(deadline, f)
+			.await
+			.map_err(|_| stage),
+		None => Ok(f.await),
+	}
+}
+
+/// The output of a verification which didn't finish before
+/// `CheckEmailInput.total_timeout`, with the results of the stages which
+/// finished.
+fn timed_out_output(
+	input: &CheckEmailInput,
+	start_time: SystemTime,
+	stage: CheckStage,
+	mut syntax: SyntaxDetails,
+	mx: Option<MxDetails>,
+	misc: Option<MiscDetails>,
+	durations: StageDurations,
+) -> CheckEmailOutput {
+	let total_timeout = input.total_timeout.unwrap_or_default();
+	get_similar_mail_provider(&mut syntax);
+
+	tracing::debug!(
+		target: LOG_TARGET,
+		email=%input.to_email,
+		stage=?stage,
+		total_timeout=?total_timeout,
+		"Verification timed out"
+	);
+
+	let end_time = SystemTime::now();
+
+	CheckEmailOutput {
+		input: input.to_email.to_string(),
+		is_reachable: Reachable::Unknown,
+		reasons: vec![ReasonCode::TotalTimeout],
+		misc: misc.ok_or(MiscError::Timeout(total_timeout)),
+		mx: mx.ok_or(MxError::Timeout(total_timeout)),
+		smtp: Err(SmtpError::Timeout(total_timeout)),
+		syntax,
+		debug: DebugDetails {
+			start_time: start_time.into(),
+			end_time: end_time.into(),
+			duration: end_time
+				.duration_since(start_time)
+				.unwrap_or(Duration::from_secs(0)),
+			backend_name: input.backend_name.clone(),
+			timed_out_stage: Some(stage),
+			durations,
+			..Default::default()
+		},
+		..Default::default()
+	}
+}
+
+/// Same as [check_email](check_email), but if `mx` is set, use these MX
+/// details instead of looking them up.
+async fn check_email_with_mx(input: &CheckEmailInput, mx: Option<MxDetails>) -> CheckEmailOutput {
 	initialize_crypto_provider();
 	let start_time = SystemTime::now();
+	let deadline = input.total_timeout.map(|t| tokio::time::Instant::now() + t);
+	let mut durations = StageDurations::default();
 	let to_email = &input.to_email;
 
 	tracing::debug!(
@@ -153,12 +244,22 @@ pub async fn check_email(input: &CheckEmailInput) -> CheckEmailOutput {
 		email=%to_email,
 		"Checking email"
 	);
+	let stage_start = Instant::now();
 	let mut my_syntax = check_syntax(to_email.as_ref());
+	add_elapsed(&mut durations.syntax, stage_start);
+	emit(input.events.as_ref(), || CheckEvent::SyntaxChecked {
+		syntax: my_syntax.clone(),
+	});
 	if !my_syntax.is_valid_syntax {
 		return CheckEmailOutput {
 			input: to_email.to_string(),
 			is_reachable: Reachable::Invalid,
+			reasons: vec![ReasonCode::InvalidSyntax],
 			syntax: my_syntax,
+			debug: DebugDetails {
+				durations,
+				..Default::default()
+			},
 			..Default::default()
 		};
 	}
@@ -170,7 +271,23 @@ pub async fn check_email(input: &CheckEmailInput) -> CheckEmailOutput {
 		"Found syntax validation"
 	);
 
-	let my_mx = match check_mx(&my_syntax).await {
+	let my_mx = match mx {
+		Some(mx) => Ok(mx),
+		None => {
+			let stage_start = Instant::now();
+			let mx = run_stage(deadline, CheckStage::Mx, lookup_mx(input, &my_syntax)).await;
+			add_elapsed(&mut durations.mx, stage_start);
+			match mx {
+				Ok(mx) => mx,
+				Err(stage) => {
+					return timed_out_output(
+						input, start_time, stage, my_syntax, None, None, durations,
+					);
+				}
+			}
+		}
+	};
+	let my_mx = match my_mx {
 		Ok(m) => m,
 		e => {
 			get_similar_mail_provider(&mut my_syntax);
@@ -180,47 +297,81 @@ pub async fn check_email(input: &CheckEmailInput) -> CheckEmailOutput {
 			return CheckEmailOutput {
 				input: to_email.to_string(),
 				is_reachable: Reachable::Unknown,
+				reasons: vec![ReasonCode::MxLookupFailed],
 				mx: e,
 				syntax: my_syntax,
+				debug: DebugDetails {
+					durations,
+					..Default::default()
+				},
 				..Default::default()
 			};
 		}
 	};
 
-	// Return if we didn't find any MX records.
-	if my_mx.lookup.is_err() {
+	// Return if the domain doesn't accept mail, i.e. it has no MX records
+	// (nor an A/AAAA fallback), or it explicitly published a null MX.
+	if !my_mx.accepts_mail() {
 		get_similar_mail_provider(&mut my_syntax);
 
+		tracing::debug!(
+			target: LOG_TARGET,
+			email=%to_email,
+			mx_kind=?my_mx.kind,
+			"Domain does not accept mail"
+		);
+
+		let reason = match my_mx.kind {
+			MxKind::Null => ReasonCode::NullMx,
+			_ => ReasonCode::NoMx,
+		};
+
 		return CheckEmailOutput {
 			input: to_email.to_string(),
 			is_reachable: Reachable::Invalid,
+			reasons: vec![reason],
 			mx: Ok(my_mx),
 			syntax: my_syntax,
+			debug: DebugDetails {
+				durations,
+				..Default::default()
+			},
 			..Default::default()
 		};
 	}
 
-	let mx_hosts: Vec<String> = my_mx
-		.lookup
-		.as_ref()
-		.expect("If lookup is error, we already returned. qed.")
-		.iter()
-		.map(|host| host.to_string())
-		.collect();
+	// The MX hosts to try, ordered by preference. If the domain has no MX
+	// records, this is the domain itself (implicit MX).
+	let mx_hosts = my_mx.hosts();
 
 	tracing::debug!(
 		target: LOG_TARGET,
 		email=%to_email,
 		mx_hosts=?mx_hosts,
+		mx_kind=?my_mx.kind,
 		"Found MX hosts"
 	);
+	emit(input.events.as_ref(), || CheckEvent::MxFound {
+		hosts: mx_hosts.iter().map(ToString::to_string).collect(),
+	});
 
-	let my_misc = check_misc(
-		&my_syntax,
-		input.check_gravatar,
-		input.haveibeenpwned_api_key.clone(),
-	)
-	.await;
+	let stage_start = Instant::now();
+	let misc = run_stage(deadline, CheckStage::Misc, check_misc(&my_syntax, input)).await;
+	add_elapsed(&mut durations.misc, stage_start);
+	let my_misc = match misc {
+		Ok(misc) => misc,
+		Err(stage) => {
+			return timed_out_output(
+				input,
+				start_time,
+				stage,
+				my_syntax,
+				Some(my_mx),
+				None,
+				durations,
+			);
+		}
+	};
 
 	tracing::debug!(
 		target: LOG_TARGET,
@@ -229,40 +380,56 @@ pub async fn check_email(input: &CheckEmailInput) -> CheckEmailOutput {
 		"Found misc details"
 	);
 
-	// From the list of MX records, we choose the one with the lowest priority.
-	let mx_records = my_mx
-		.lookup
-		.as_ref()
-		.expect("If lookup is error, we already returned. qed.")
-		.iter()
-		.min_by_key(|a| a.preference())
-		.expect("There should be at least one MX record after filtering.");
-	let host = mx_records;
-
-	let (my_smtp, smtp_debug) = check_smtp(
+	let smtp = check_smtp_with_mx_fallback(
 		my_syntax
 			.address
 			.as_ref()
 			.expect("We already checked that the email has valid format. qed."),
-		host.exchange(),
+		&mx_hosts,
 		my_syntax.domain.as_ref(),
 		input,
-	)
-	.await;
+	);
+	let stage_start = Instant::now();
+	let smtp = run_stage(deadline, CheckStage::Smtp, smtp).await;
+	add_elapsed(&mut durations.smtp, stage_start);
+	let (my_smtp, smtp_debug) = match smtp {
+		Ok(smtp) => smtp,
+		Err(stage) => {
+			return timed_out_output(
+				input,
+				start_time,
+				stage,
+				my_syntax,
+				Some(my_mx),
+				Some(my_misc),
+				durations,
+			);
+		}
+	};
 
 	if my_smtp.is_err() {
 		get_similar_mail_provider(&mut my_syntax);
 	}
 
+	let (is_reachable, score, reasons) = calculate_reachable(
+		&my_misc,
+		&my_smtp,
+		&smtp_debug,
+		&input.score,
+		&input.classification_policy,
+	);
+
 	let end_time = SystemTime::now();
 
 	let output = CheckEmailOutput {
 		input: to_email.to_string(),
-		is_reachable: calculate_reachable(&my_misc, &my_smtp),
+		is_reachable,
+		reasons,
 		misc: Ok(my_misc),
 		mx: Ok(my_mx),
 		smtp: my_smtp,
 		syntax: my_syntax,
+		score,
 		debug: DebugDetails {
 			start_time: start_time.into(),
 			end_time: end_time.into(),
@@ -271,6 +438,8 @@ pub async fn check_email(input: &CheckEmailInput) -> CheckEmailOutput {
 				.unwrap_or(Duration::from_secs(0)),
 			smtp: smtp_debug,
 			backend_name: input.backend_name.clone(),
+			timed_out_stage: None,
+			durations,
 		},
 	};
 
@@ -279,3 +448,45 @@ pub async fn check_email(input: &CheckEmailInput) -> CheckEmailOutput {
 
 	output
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use mx::resolver::{DnsResolver, InMemoryResolver};
+	use smtp::verif_method::{EverythingElseVerifMethod, VerifMethodSmtpConfig};
+	use std::sync::Arc;
+	use tokio::net::TcpListener;
+
+	#[tokio::test]
+	async fn should_return_partial_results_on_total_timeout() {
+		// An SMTP server which never sends its greeting.
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let resolver: Arc<dyn DnsResolver> =
+			Arc::new(InMemoryResolver::new().with_mx("slow.com", 10, "localhost."));
+		let mut input = CheckEmailInput {
+			to_email: "foo@slow.com".into(),
+			dns_resolver: Some(resolver),
+			total_timeout: Some(Duration::from_millis(200)),
+			..Default::default()
+		};
+		input.verif_method.everything_else =
+			EverythingElseVerifMethod::Smtp(VerifMethodSmtpConfig {
+				smtp_port: listener.local_addr().unwrap().port(),
+				..Default::default()
+			});
+
+		let output = check_email(&input).await;
+		assert_eq!(output.is_reachable, Reachable::Unknown);
+		assert_eq!(output.reasons, vec![ReasonCode::TotalTimeout]);
+		assert_eq!(output.debug.timed_out_stage, Some(CheckStage::Smtp));
+		assert!(output.mx.is_ok());
+		assert!(output.misc.is_ok());
+
+		// The stages before SMTP are timed, and SMTP until the timeout.
+		let durations = output.debug.durations;
+		assert!(durations.syntax.is_some());
+		assert!(durations.mx.is_some());
+		assert!(durations.misc.is_some());
+		assert!(durations.smtp.unwrap() >= Duration::from_millis(100));
+	}
+}
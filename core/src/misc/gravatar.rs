@@ -14,13 +14,14 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::LOG_TARGET;
+use crate::util::http_client::create_client;
+use crate::{CheckEmailInput, LOG_TARGET};
 use md5::Digest;
 
 const API_BASE_URL: &str = "https://www.gravatar.com/avatar/";
 
-pub async fn check_gravatar(to_email: &str) -> Option<String> {
-	let client = reqwest::Client::new();
+pub async fn check_gravatar(to_email: &str, input: &CheckEmailInput) -> Option<String> {
+	let client = create_client(input, "gravatar").ok()?;
 
 	let mail_hash: Digest = md5::compute(to_email);
 
@@ -17,10 +17,11 @@
 mod gravatar;
 use crate::haveibeenpwned::check_haveibeenpwned;
 use crate::syntax::SyntaxDetails;
+use crate::CheckEmailInput;
 use gravatar::check_gravatar;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashSet, default::Default};
+use std::{collections::HashSet, default::Default, time::Duration};
 use thiserror::Error;
 
 const ROLE_ACCOUNTS: &str = include_str!("./roles.txt");
@@ -55,19 +56,18 @@ pub struct MiscDetails {
 	pub haveibeenpwned: Option<bool>,
 }
 
-/// Error occurred connecting to this email server via SMTP. Right now this
-/// enum has no variant, as `check_misc` cannot fail. But putting a placeholder
-/// right now to avoid future breaking changes.
+/// Error occurred while fetching the misc details. `check_misc` itself cannot
+/// fail, but it can be cut short by `CheckEmailInput.total_timeout`.
 #[derive(Debug, Error, Serialize)]
 #[serde(tag = "type", content = "message")]
-pub enum MiscError {}
+pub enum MiscError {
+	/// The misc checks didn't finish before `CheckEmailInput.total_timeout`.
+	#[error("Timeout error: {0:?}")]
+	Timeout(Duration),
+}
 
 /// Fetch misc details about the email address, such as whether it's disposable.
-pub async fn check_misc(
-	syntax: &SyntaxDetails,
-	cfg_check_gravatar: bool,
-	haveibeenpwned_api_key: Option<String>,
-) -> MiscDetails {
+pub async fn check_misc(syntax: &SyntaxDetails, input: &CheckEmailInput) -> MiscDetails {
 	let address = syntax
 		.address
 		.as_ref()
@@ -76,14 +76,14 @@ pub async fn check_misc(
 
 	let mut gravatar_url: Option<String> = None;
 
-	if cfg_check_gravatar {
-		gravatar_url = check_gravatar(address.as_ref()).await;
+	if input.check_gravatar {
+		gravatar_url = check_gravatar(address.as_ref(), input).await;
 	}
 
 	let mut haveibeenpwned: Option<bool> = None;
 
-	if haveibeenpwned_api_key.is_some() {
-		haveibeenpwned = check_haveibeenpwned(address.as_ref(), haveibeenpwned_api_key).await;
+	if let Some(api_key) = &input.haveibeenpwned_api_key {
+		haveibeenpwned = check_haveibeenpwned(address.as_ref(), api_key, input).await;
 	}
 
 	MiscDetails {
@@ -115,7 +115,11 @@ mod tests {
 			suggestion: None,
 		};
 
-		let misc_details = check_misc(&syntax, true, None).await;
+		let input = CheckEmailInput {
+			check_gravatar: true,
+			..Default::default()
+		};
+		let misc_details = check_misc(&syntax, &input).await;
 
 		assert!(!misc_details.is_disposable); // gmail.com is not in mailchecker
 		assert!(misc_details.is_role_account); // test is in roles.txt
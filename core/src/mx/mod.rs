@@ -14,34 +14,118 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod resolver;
+
 use crate::syntax::SyntaxDetails;
 use crate::util::ser_with_display::ser_with_display;
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::rdata::MX;
+use hickory_proto::rr::Name;
 use hickory_resolver::error::{ResolveError, ResolveErrorKind};
 use hickory_resolver::lookup::MxLookup;
-use hickory_resolver::system_conf::read_system_conf;
-use hickory_resolver::TokioAsyncResolver;
+use rand::seq::SliceRandom;
+use resolver::DnsResolver;
 use serde::{ser::SerializeMap, Serialize, Serializer};
 use std::io;
+use std::time::Duration;
 use thiserror::Error;
 
+/// How the domain declares which hosts receive its email.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum MxKind {
+	/// The domain has explicit MX records.
+	Explicit,
+	/// The domain has no MX records, but has an A/AAAA record. As per RFC 5321
+	/// section 5.1, the domain itself is then used as an implicit MX host.
+	Implicit(Name),
+	/// The domain publishes a null MX record (RFC 7505), i.e. it explicitly
+	/// states that it doesn't accept email.
+	Null,
+	/// The domain has neither MX records nor A/AAAA records.
+	#[default]
+	NoRecords,
+}
+
+impl MxKind {
+	fn as_str(&self) -> &'static str {
+		match self {
+			MxKind::Explicit => "explicit",
+			MxKind::Implicit(_) => "implicit",
+			MxKind::Null => "null",
+			MxKind::NoRecords => "no_records",
+		}
+	}
+}
+
 /// Details about the MX lookup.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MxDetails {
 	/// MX lookup of this DNS.
 	pub lookup: Result<MxLookup, ResolveError>,
+	/// Whether the hosts to deliver to come from explicit MX records, from the
+	/// implicit A/AAAA fallback, or whether the domain doesn't accept mail.
+	pub kind: MxKind,
 }
 
 impl Default for MxDetails {
 	fn default() -> Self {
 		MxDetails {
 			lookup: Err(ResolveError::from("Skipped")),
+			kind: MxKind::NoRecords,
 		}
 	}
 }
 
 impl From<MxLookup> for MxDetails {
 	fn from(lookup: MxLookup) -> Self {
-		MxDetails { lookup: Ok(lookup) }
+		// RFC 7505: a null MX is a single MX record with an empty (root)
+		// exchange, usually with preference 0.
+		let kind = if lookup.iter().next().is_none() {
+			MxKind::NoRecords
+		} else if lookup.iter().all(|mx| mx.exchange().is_root()) {
+			MxKind::Null
+		} else {
+			MxKind::Explicit
+		};
+
+		MxDetails {
+			lookup: Ok(lookup),
+			kind,
+		}
+	}
+}
+
+impl MxDetails {
+	/// Whether the domain is configured to receive email, either via explicit
+	/// MX records or via the implicit A/AAAA fallback.
+	pub fn accepts_mail(&self) -> bool {
+		matches!(self.kind, MxKind::Explicit | MxKind::Implicit(_))
+	}
+
+	/// The hosts to try to deliver email to, in the order in which they
+	/// should be tried. Empty if the domain doesn't accept email.
+	pub fn hosts(&self) -> Vec<Name> {
+		match (&self.kind, &self.lookup) {
+			(MxKind::Explicit, Ok(lookup)) => {
+				order_mx_hosts(lookup.iter().filter(|mx| !mx.exchange().is_root()))
+			}
+			(MxKind::Implicit(host), _) => vec![host.clone()],
+			_ => vec![],
+		}
+	}
+
+	/// The most preferred host, with ties broken by name so that the result
+	/// is stable across calls. None if the domain doesn't accept email.
+	pub fn primary_host(&self) -> Option<Name> {
+		match (&self.kind, &self.lookup) {
+			(MxKind::Explicit, Ok(lookup)) => lookup
+				.iter()
+				.filter(|mx| !mx.exchange().is_root())
+				.min_by_key(|mx| (mx.preference(), mx.exchange().to_lowercase()))
+				.map(|mx| mx.exchange().clone()),
+			(MxKind::Implicit(host), _) => Some(host.clone()),
+			_ => None,
+		}
 	}
 }
 
@@ -50,20 +134,21 @@ impl Serialize for MxDetails {
 	where
 		S: Serializer,
 	{
-		let records: Vec<String> = self
-			.lookup
-			.as_ref()
-			.map(|lookup| {
-				lookup
-					.iter()
-					.map(|host| host.exchange().to_string())
-					.collect::<Vec<_>>()
-			})
-			.unwrap_or_else(|_| Vec::new()); // In case of a resolve error, we don't serialize the error.
-
-		let mut map = serializer.serialize_map(Some(2))?;
-		map.serialize_entry("accepts_mail", &!records.is_empty())?;
+		let records: Vec<String> = match (&self.kind, &self.lookup) {
+			(MxKind::Implicit(host), _) => vec![host.to_string()],
+			(MxKind::Explicit, Ok(lookup)) => lookup
+				.iter()
+				.map(|host| host.exchange().to_string())
+				.collect::<Vec<_>>(),
+			// In case of a resolve error, we don't serialize the error. A null
+			// MX is not a mail server either.
+			_ => Vec::new(),
+		};
+
+		let mut map = serializer.serialize_map(Some(3))?;
+		map.serialize_entry("accepts_mail", &self.accepts_mail())?;
 		map.serialize_entry("records", &records)?;
+		map.serialize_entry("kind", self.kind.as_str())?;
 		map.end()
 	}
 }
@@ -80,6 +165,9 @@ pub enum MxError {
 	#[serde(serialize_with = "ser_with_display")]
 	#[error("Resolve error: {0}")]
 	ResolveError(Box<ResolveError>),
+	/// The MX lookup didn't finish before `CheckEmailInput.total_timeout`.
+	#[error("Timeout error: {0:?}")]
+	Timeout(Duration),
 }
 
 impl From<io::Error> for MxError {
@@ -94,22 +182,80 @@ impl From<ResolveError> for MxError {
 	}
 }
 
-/// Make a MX lookup.
-pub async fn check_mx(syntax: &SyntaxDetails) -> Result<MxDetails, MxError> {
-	// Construct a new Resolver with default configuration options
-	let (config, opts) = read_system_conf()?;
-	let resolver = TokioAsyncResolver::tokio(config, opts);
-
-	match resolver.mx_lookup(&syntax.domain).await {
+/// Make a MX lookup. If the domain has no MX records, fall back to its
+/// A/AAAA records as an implicit MX, as described in RFC 5321 section 5.1.
+pub async fn check_mx(
+	syntax: &SyntaxDetails,
+	resolver: &dyn DnsResolver,
+) -> Result<MxDetails, MxError> {
+	match resolver.lookup_mx(&syntax.domain).await {
+		// Some resolvers answer a domain without MX records with an empty
+		// lookup, instead of a NoRecordsFound error.
+		Ok(lookup) if lookup.iter().next().is_none() => {
+			check_implicit_mx(resolver, &syntax.domain, Ok(lookup)).await
+		}
 		Ok(lookup) => Ok(MxDetails::from(lookup)),
 		Err(err) => match err.kind() {
-			// Prefer to return an empty MX lookup if there are no records.
-			ResolveErrorKind::NoRecordsFound { .. } => Ok(MxDetails { lookup: Err(err) }),
+			// The domain doesn't exist at all, no need to look for A/AAAA
+			// records.
+			ResolveErrorKind::NoRecordsFound {
+				response_code: ResponseCode::NXDomain,
+				..
+			} => Ok(MxDetails {
+				lookup: Err(err),
+				kind: MxKind::NoRecords,
+			}),
+			ResolveErrorKind::NoRecordsFound { .. } => {
+				check_implicit_mx(resolver, &syntax.domain, Err(err)).await
+			}
+			_ => Err(err.into()),
+		},
+	}
+}
+
+/// Look up the A/AAAA records of the domain, to use it as an implicit MX
+/// host. The `mx_lookup` is the empty lookup or the error we got when looking
+/// up the MX records.
+async fn check_implicit_mx(
+	resolver: &dyn DnsResolver,
+	domain: &str,
+	mx_lookup: Result<MxLookup, ResolveError>,
+) -> Result<MxDetails, MxError> {
+	// Use the FQDN, so that the resolver doesn't try the search domains.
+	let host = Name::from_ascii(format!("{}.", domain.trim_end_matches('.')))
+		.map_err(ResolveError::from)?;
+
+	match resolver.lookup_host(&host).await {
+		Ok(_) => Ok(MxDetails {
+			lookup: mx_lookup,
+			kind: MxKind::Implicit(host),
+		}),
+		Err(err) => match err.kind() {
+			ResolveErrorKind::NoRecordsFound { .. } => Ok(MxDetails {
+				lookup: mx_lookup,
+				kind: MxKind::NoRecords,
+			}),
 			_ => Err(err.into()),
 		},
 	}
 }
 
+/// Order MX records in the order in which they should be tried: by
+/// increasing preference, and randomly among records with the same
+/// preference, as per RFC 5321 section 5.1.
+pub fn order_mx_hosts<'a>(records: impl Iterator<Item = &'a MX>) -> Vec<Name> {
+	let mut records: Vec<&MX> = records.collect();
+	// Shuffle first, then rely on the sort being stable to keep the random
+	// order among equal preferences.
+	records.shuffle(&mut rand::thread_rng());
+	records.sort_by_key(|mx| mx.preference());
+
+	records
+		.into_iter()
+		.map(|mx| mx.exchange().clone())
+		.collect()
+}
+
 /// Check if the MX host is from Google, i.e. either a @gmail.com address, or
 /// a Google Suite email.
 pub fn is_gmail(mx_host: &str) -> bool {
@@ -163,3 +309,79 @@ pub fn is_proofpoint(mx_host: &str) -> bool {
 pub fn is_yahoo(mx_host: &str) -> bool {
 	mx_host.to_lowercase().ends_with(".yahoodns.net.")
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use hickory_proto::op::Query;
+	use hickory_proto::rr::{RData, RecordType};
+	use hickory_resolver::lookup::Lookup;
+	use std::str::FromStr;
+	use std::sync::Arc;
+
+	fn single_mx_lookup(preference: u16, exchange: &str) -> MxLookup {
+		let query = Query::query(Name::from_str("example.com.").unwrap(), RecordType::MX);
+		let rdata = RData::MX(MX::new(preference, Name::from_str(exchange).unwrap()));
+
+		Lookup::from_rdata(query, rdata).into()
+	}
+
+	#[test]
+	fn should_detect_null_mx() {
+		let details = MxDetails::from(single_mx_lookup(0, "."));
+		assert_eq!(details.kind, MxKind::Null);
+		assert!(!details.accepts_mail());
+		assert!(details.hosts().is_empty());
+
+		let details = MxDetails::from(single_mx_lookup(10, "mx.example.com."));
+		assert_eq!(details.kind, MxKind::Explicit);
+		assert!(details.accepts_mail());
+		assert_eq!(details.hosts().len(), 1);
+
+		// An empty answer is not a null MX.
+		let query = Query::query(Name::from_str("example.com.").unwrap(), RecordType::MX);
+		let details = MxDetails::from(MxLookup::from(Lookup::new_with_max_ttl(
+			query,
+			Arc::from(vec![]),
+		)));
+		assert_eq!(details.kind, MxKind::NoRecords);
+		assert!(!details.accepts_mail());
+	}
+
+	#[test]
+	fn should_use_implicit_mx_host() {
+		let host = Name::from_str("example.com.").unwrap();
+		let details = MxDetails {
+			lookup: Err(ResolveError::from("No MX records")),
+			kind: MxKind::Implicit(host.clone()),
+		};
+
+		assert!(details.accepts_mail());
+		assert_eq!(details.hosts(), vec![host]);
+		assert_eq!(
+			serde_json::to_string(&details).unwrap(),
+			r#"{"accepts_mail":true,"records":["example.com."],"kind":"implicit"}"#
+		);
+	}
+
+	#[test]
+	fn should_order_mx_hosts_by_preference() {
+		let records = [
+			MX::new(20, Name::from_str("alt2.example.com.").unwrap()),
+			MX::new(10, Name::from_str("alt1a.example.com.").unwrap()),
+			MX::new(5, Name::from_str("primary.example.com.").unwrap()),
+			MX::new(10, Name::from_str("alt1b.example.com.").unwrap()),
+		];
+
+		let hosts: Vec<String> = order_mx_hosts(records.iter())
+			.iter()
+			.map(|h| h.to_string())
+			.collect();
+
+		assert_eq!(hosts.len(), 4);
+		assert_eq!(hosts[0], "primary.example.com.");
+		assert!(hosts[1..3].contains(&"alt1a.example.com.".to_string()));
+		assert!(hosts[1..3].contains(&"alt1b.example.com.".to_string()));
+		assert_eq!(hosts[3], "alt2.example.com.");
+	}
+}
@@ -0,0 +1,412 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! DNS resolvers used for the MX and A/AAAA lookups.
+//!
+//! By default, one resolver is created per `DnsConfig` and shared (along
+//! with its cache) across all verifications of the process. A custom
+//! resolver, such as the `InMemoryResolver` for tests, can also be passed in
+//! the `CheckEmailInput`.
+
+use super::MxError;
+use crate::util::input_output::CheckEmailInput;
+use futures::future::BoxFuture;
+use hickory_proto::op::{Query, ResponseCode};
+use hickory_proto::rr::rdata::MX;
+use hickory_proto::rr::{Name, RData, Record, RecordType};
+use hickory_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use hickory_resolver::lookup::{Lookup, MxLookup};
+use hickory_resolver::system_conf::read_system_conf;
+use hickory_resolver::TokioAsyncResolver;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A DNS resolver, able to perform the lookups needed to find out where an
+/// email domain receives its mail.
+pub trait DnsResolver: Debug + Send + Sync {
+	/// Look up the MX records of the domain.
+	fn lookup_mx<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Result<MxLookup, ResolveError>>;
+
+	/// Look up the A and AAAA records of the host.
+	fn lookup_host<'a>(
+		&'a self,
+		host: &'a Name,
+	) -> BoxFuture<'a, Result<Vec<IpAddr>, ResolveError>>;
+}
+
+impl DnsResolver for TokioAsyncResolver {
+	fn lookup_mx<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Result<MxLookup, ResolveError>> {
+		Box::pin(self.mx_lookup(domain))
+	}
+
+	fn lookup_host<'a>(
+		&'a self,
+		host: &'a Name,
+	) -> BoxFuture<'a, Result<Vec<IpAddr>, ResolveError>> {
+		Box::pin(async move {
+			let lookup = self.lookup_ip(host.clone()).await?;
+			Ok(lookup.iter().collect())
+		})
+	}
+}
+
+/// The protocol to use to talk to an upstream nameserver.
+#[derive(Debug, Default, Clone, Copy, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsProtocol {
+	/// Plain DNS over UDP.
+	#[default]
+	Udp,
+	/// Plain DNS over TCP.
+	Tcp,
+	/// DNS-over-TLS (RFC 7858).
+	Tls,
+	/// DNS-over-HTTPS (RFC 8484).
+	Https,
+}
+
+impl DnsProtocol {
+	fn default_port(&self) -> u16 {
+		match self {
+			DnsProtocol::Udp | DnsProtocol::Tcp => 53,
+			DnsProtocol::Tls => 853,
+			DnsProtocol::Https => 443,
+		}
+	}
+}
+
+impl From<DnsProtocol> for Protocol {
+	fn from(protocol: DnsProtocol) -> Self {
+		match protocol {
+			DnsProtocol::Udp => Protocol::Udp,
+			DnsProtocol::Tcp => Protocol::Tcp,
+			DnsProtocol::Tls => Protocol::Tls,
+			DnsProtocol::Https => Protocol::Https,
+		}
+	}
+}
+
+/// An upstream nameserver to send the DNS queries to.
+#[derive(Debug, Clone, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct DnsNameserver {
+	/// IP address of the nameserver, e.g. "127.0.0.1" for a local unbound.
+	pub ip: IpAddr,
+	/// Port of the nameserver.
+	///
+	/// Defaults to 53 for UDP and TCP, 853 for TLS and 443 for HTTPS.
+	pub port: Option<u16>,
+	/// Protocol to use to talk to the nameserver.
+	///
+	/// Defaults to UDP.
+	#[serde(default)]
+	pub protocol: DnsProtocol,
+	/// Name of the nameserver in its TLS certificate, e.g. "dns.google".
+	/// Required for the TLS and HTTPS protocols.
+	pub tls_dns_name: Option<String>,
+}
+
+/// Configuration of the DNS resolver used for MX and A/AAAA lookups.
+#[derive(Debug, Default, Clone, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(default)]
+pub struct DnsConfig {
+	/// Upstream nameservers to use, tried in order. If empty, the system
+	/// configuration (e.g. /etc/resolv.conf) is used.
+	pub nameservers: Vec<DnsNameserver>,
+	/// Maximum number of DNS responses to keep in the resolver's cache.
+	///
+	/// Defaults to the system configuration, or 32 if unset.
+	pub cache_size: Option<usize>,
+	/// Timeout for each DNS query, in ms.
+	///
+	/// Defaults to the system configuration, or 5s if unset.
+	pub timeout_ms: Option<u64>,
+}
+
+impl DnsConfig {
+	/// Create a new resolver from this configuration.
+	fn build_resolver(&self) -> Result<TokioAsyncResolver, MxError> {
+		let (resolver_config, mut opts) = if self.nameservers.is_empty() {
+			read_system_conf()?
+		} else {
+			let mut resolver_config = ResolverConfig::new();
+			for nameserver in &self.nameservers {
+				if matches!(nameserver.protocol, DnsProtocol::Tls | DnsProtocol::Https)
+					&& nameserver.tls_dns_name.is_none()
+				{
+					return Err(ResolveError::from(format!(
+						"Nameserver {} needs a tls_dns_name to use DNS-over-TLS/HTTPS",
+						nameserver.ip
+					))
+					.into());
+				}
+
+				let port = nameserver
+					.port
+					.unwrap_or_else(|| nameserver.protocol.default_port());
+				let mut ns_config = NameServerConfig::new(
+					SocketAddr::new(nameserver.ip, port),
+					nameserver.protocol.into(),
+				);
+				ns_config.tls_dns_name = nameserver.tls_dns_name.clone();
+				resolver_config.add_name_server(ns_config);
+			}
+
+			(resolver_config, ResolverOpts::default())
+		};
+
+		if let Some(cache_size) = self.cache_size {
+			opts.cache_size = cache_size;
+		}
+		if let Some(timeout_ms) = self.timeout_ms {
+			opts.timeout = Duration::from_millis(timeout_ms);
+		}
+
+		Ok(TokioAsyncResolver::tokio(resolver_config, opts))
+	}
+}
+
+/// Resolvers shared across all verifications, one per DNS configuration.
+static SHARED_RESOLVERS: Lazy<Mutex<HashMap<DnsConfig, Arc<dyn DnsResolver>>>> =
+	Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Get the DNS resolver to use for this input: the custom `dns_resolver` if
+/// set, or else the shared resolver for the `dns` configuration, which is
+/// created on first use.
+pub fn get_resolver(input: &CheckEmailInput) -> Result<Arc<dyn DnsResolver>, MxError> {
+	if let Some(resolver) = &input.dns_resolver {
+		return Ok(Arc::clone(resolver));
+	}
+
+	let mut resolvers = SHARED_RESOLVERS
+		.lock()
+		.expect("Shared resolvers mutex is not poisoned. qed.");
+	if let Some(resolver) = resolvers.get(&input.dns) {
+		return Ok(Arc::clone(resolver));
+	}
+
+	let resolver: Arc<dyn DnsResolver> = Arc::new(input.dns.build_resolver()?);
+	resolvers.insert(input.dns.clone(), Arc::clone(&resolver));
+
+	Ok(resolver)
+}
+
+#[derive(Debug, Default, Clone)]
+struct InMemoryZone {
+	mx: Vec<(u16, String)>,
+	/// Whether to answer MX lookups with an empty lookup, instead of an
+	/// error, when there are no MX records.
+	empty_mx: bool,
+	ips: Vec<IpAddr>,
+}
+
+/// A resolver answering from an in-memory list of records, without any
+/// network access. Useful to test MX-dependent code paths offline.
+///
+/// Domains which have not been added are answered with NXDOMAIN.
+#[derive(Debug, Default, Clone)]
+pub struct InMemoryResolver {
+	zones: HashMap<String, InMemoryZone>,
+}
+
+/// Normalize a domain name to use it as a key in the in-memory zones.
+fn zone_key(domain: &str) -> String {
+	domain.trim_end_matches('.').to_lowercase()
+}
+
+impl InMemoryResolver {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Add an MX record to the domain.
+	pub fn with_mx(mut self, domain: &str, preference: u16, exchange: &str) -> Self {
+		self.zones
+			.entry(zone_key(domain))
+			.or_default()
+			.mx
+			.push((preference, exchange.to_string()));
+		self
+	}
+
+	/// Answer MX lookups of the domain without MX records with an empty
+	/// lookup, like some resolvers do, instead of a NoRecordsFound error.
+	pub fn with_empty_mx(mut self, domain: &str) -> Self {
+		self.zones.entry(zone_key(domain)).or_default().empty_mx = true;
+		self
+	}
+
+	/// Add an A or AAAA record to the host.
+	pub fn with_ip(mut self, host: &str, ip: IpAddr) -> Self {
+		self.zones.entry(zone_key(host)).or_default().ips.push(ip);
+		self
+	}
+
+	fn no_records(&self, name: Name, record_type: RecordType) -> ResolveError {
+		let response_code = if self.zones.contains_key(&zone_key(&name.to_string())) {
+			ResponseCode::NoError
+		} else {
+			ResponseCode::NXDomain
+		};
+
+		ResolveErrorKind::NoRecordsFound {
+			query: Box::new(Query::query(name, record_type)),
+			soa: None,
+			negative_ttl: None,
+			response_code,
+			trusted: true,
+		}
+		.into()
+	}
+}
+
+impl DnsResolver for InMemoryResolver {
+	fn lookup_mx<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Result<MxLookup, ResolveError>> {
+		Box::pin(async move {
+			let name = Name::from_str(domain)?;
+			let zone = self.zones.get(&zone_key(domain));
+			let mx = zone.map(|zone| zone.mx.as_slice()).unwrap_or_default();
+			if mx.is_empty() && !zone.is_some_and(|zone| zone.empty_mx) {
+				return Err(self.no_records(name, RecordType::MX));
+			}
+
+			let records = mx
+				.iter()
+				.map(|(preference, exchange)| {
+					Ok(Record::from_rdata(
+						name.clone(),
+						3600,
+						RData::MX(MX::new(*preference, Name::from_str(exchange)?)),
+					))
+				})
+				.collect::<Result<Vec<_>, ResolveError>>()?;
+			let query = Query::query(name, RecordType::MX);
+
+			Ok(Lookup::new_with_max_ttl(query, Arc::from(records)).into())
+		})
+	}
+
+	fn lookup_host<'a>(
+		&'a self,
+		host: &'a Name,
+	) -> BoxFuture<'a, Result<Vec<IpAddr>, ResolveError>> {
+		Box::pin(async move {
+			let ips = self
+				.zones
+				.get(&zone_key(&host.to_string()))
+				.map(|zone| zone.ips.clone())
+				.unwrap_or_default();
+			if ips.is_empty() {
+				return Err(self.no_records(host.clone(), RecordType::A));
+			}
+
+			Ok(ips)
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::mx::{check_mx, MxKind};
+	use crate::syntax::check_syntax;
+	use std::net::Ipv4Addr;
+
+	fn resolver() -> InMemoryResolver {
+		InMemoryResolver::new()
+			.with_mx("explicit.com", 10, "mx2.explicit.com.")
+			.with_mx("explicit.com", 5, "mx1.explicit.com.")
+			.with_ip("implicit.com", IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)))
+			.with_mx("null.com", 0, ".")
+			.with_empty_mx("empty-implicit.com")
+			.with_ip(
+				"empty-implicit.com",
+				IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)),
+			)
+			.with_empty_mx("empty.com")
+	}
+
+	#[tokio::test]
+	async fn should_find_explicit_mx() {
+		let syntax = check_syntax("foo@explicit.com");
+		let mx = check_mx(&syntax, &resolver()).await.unwrap();
+
+		assert_eq!(mx.kind, MxKind::Explicit);
+		let hosts: Vec<String> = mx.hosts().iter().map(|h| h.to_string()).collect();
+		assert_eq!(hosts, vec!["mx1.explicit.com.", "mx2.explicit.com."]);
+	}
+
+	#[tokio::test]
+	async fn should_fall_back_to_implicit_mx() {
+		let syntax = check_syntax("foo@implicit.com");
+		let mx = check_mx(&syntax, &resolver()).await.unwrap();
+
+		assert_eq!(
+			mx.kind,
+			MxKind::Implicit(Name::from_str("implicit.com.").unwrap())
+		);
+		assert!(mx.accepts_mail());
+	}
+
+	#[tokio::test]
+	async fn should_fall_back_to_implicit_mx_on_empty_answer() {
+		let syntax = check_syntax("foo@empty-implicit.com");
+		let mx = check_mx(&syntax, &resolver()).await.unwrap();
+		assert_eq!(
+			mx.kind,
+			MxKind::Implicit(Name::from_str("empty-implicit.com.").unwrap())
+		);
+		assert!(mx.accepts_mail());
+
+		let syntax = check_syntax("foo@empty.com");
+		let mx = check_mx(&syntax, &resolver()).await.unwrap();
+		assert_eq!(mx.kind, MxKind::NoRecords);
+		assert!(!mx.accepts_mail());
+	}
+
+	#[tokio::test]
+	async fn should_detect_null_mx_and_nxdomain() {
+		let syntax = check_syntax("foo@null.com");
+		let mx = check_mx(&syntax, &resolver()).await.unwrap();
+		assert_eq!(mx.kind, MxKind::Null);
+
+		let syntax = check_syntax("foo@does-not-exist.com");
+		let mx = check_mx(&syntax, &resolver()).await.unwrap();
+		assert_eq!(mx.kind, MxKind::NoRecords);
+		assert!(!mx.accepts_mail());
+	}
+
+	#[test]
+	fn should_require_tls_dns_name() {
+		let config = DnsConfig {
+			nameservers: vec![DnsNameserver {
+				ip: IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)),
+				port: None,
+				protocol: DnsProtocol::Tls,
+				tls_dns_name: None,
+			}],
+			..Default::default()
+		};
+
+		assert!(config.build_resolver().is_err());
+	}
+}
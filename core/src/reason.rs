@@ -0,0 +1,226 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Machine-readable reason codes explaining the `Reachable` verdict of an
+//! email verification.
+
+use crate::misc::MiscDetails;
+use crate::smtp::{SmtpDetails, SmtpError, SmtpErrorDesc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A stable, machine-readable reason explaining the verdict of a
+/// verification. An output can have several reasons.
+#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasonCode {
+	/// The email address is not syntactically valid.
+	InvalidSyntax,
+	/// The domain has no MX records, nor A/AAAA records to fall back to.
+	NoMx,
+	/// The domain explicitly does not accept mail (RFC 7505 null MX).
+	NullMx,
+	/// The DNS lookup of the MX records failed.
+	MxLookupFailed,
+	/// The SMTP server rejected the email address.
+	Undeliverable,
+	/// The email account is disabled or blocked by the provider.
+	Disabled,
+	/// The mailbox exists, but its inbox is full.
+	MailboxFull,
+	/// The domain accepts all emails.
+	CatchAll,
+	/// The email is a role account, e.g. support@ or info@.
+	RoleAccount,
+	/// The domain is a disposable email provider.
+	Disposable,
+	/// The SMTP verification timed out.
+	SmtpTimeout,
+	/// The SMTP server refused us because our IP is blacklisted.
+	IpBlacklisted,
+	/// The SMTP server refused us because our IP has no reverse DNS.
+	NeedsRdns,
+	/// The SMTP server refused us because we opened too many connections.
+	TooManyConnections,
+	/// The SMTP server deferred us because of greylisting.
+	Greylisted,
+	/// The SMTP server rate limits us.
+	RateLimited,
+	/// The SMTP server requires TLS.
+	TlsRequired,
+	/// The SMTP server refused our sender, e.g. because of SPF or DMARC.
+	PolicyRejected,
+	/// We could not connect to the SMTP server, or the connection broke.
+	SmtpConnectionFailed,
+	/// We didn't connect to the SMTP server, because it failed too often
+	/// lately.
+	CircuitOpen,
+	/// The SMTP server answered with an error we could not categorize.
+	SmtpError,
+	/// The verification via headless browser failed.
+	HeadlessFailed,
+	/// The verification via the provider's HTTP API failed.
+	ApiFailed,
+	/// The verification didn't finish before `CheckEmailInput.total_timeout`.
+	TotalTimeout,
+}
+
+impl ReasonCode {
+	/// The code, as serialized in JSON and in CSV.
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			ReasonCode::InvalidSyntax => "invalid_syntax",
+			ReasonCode::NoMx => "no_mx",
+			ReasonCode::NullMx => "null_mx",
+			ReasonCode::MxLookupFailed => "mx_lookup_failed",
+			ReasonCode::Undeliverable => "undeliverable",
+			ReasonCode::Disabled => "disabled",
+			ReasonCode::MailboxFull => "mailbox_full",
+			ReasonCode::CatchAll => "catch_all",
+			ReasonCode::RoleAccount => "role_account",
+			ReasonCode::Disposable => "disposable",
+			ReasonCode::SmtpTimeout => "smtp_timeout",
+			ReasonCode::IpBlacklisted => "ip_blacklisted",
+			ReasonCode::NeedsRdns => "needs_rdns",
+			ReasonCode::TooManyConnections => "too_many_connections",
+			ReasonCode::Greylisted => "greylisted",
+			ReasonCode::RateLimited => "rate_limited",
+			ReasonCode::TlsRequired => "tls_required",
+			ReasonCode::PolicyRejected => "policy_rejected",
+			ReasonCode::SmtpConnectionFailed => "smtp_connection_failed",
+			ReasonCode::CircuitOpen => "circuit_open",
+			ReasonCode::SmtpError => "smtp_error",
+			ReasonCode::HeadlessFailed => "headless_failed",
+			ReasonCode::ApiFailed => "api_failed",
+			ReasonCode::TotalTimeout => "total_timeout",
+		}
+	}
+}
+
+impl fmt::Display for ReasonCode {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.as_str())
+	}
+}
+
+impl From<&SmtpError> for ReasonCode {
+	fn from(err: &SmtpError) -> Self {
+		match err.get_description() {
+			Some(SmtpErrorDesc::IpBlacklisted) => return ReasonCode::IpBlacklisted,
+			Some(SmtpErrorDesc::NeedsRDNS) => return ReasonCode::NeedsRdns,
+			Some(SmtpErrorDesc::TooManyConnections) => return ReasonCode::TooManyConnections,
+			Some(SmtpErrorDesc::Greylisted) => return ReasonCode::Greylisted,
+			Some(SmtpErrorDesc::RateLimited) => return ReasonCode::RateLimited,
+			Some(SmtpErrorDesc::TlsRequired) => return ReasonCode::TlsRequired,
+			Some(SmtpErrorDesc::PolicyRejected) => return ReasonCode::PolicyRejected,
+			None => {}
+		}
+
+		match err {
+			SmtpError::Timeout(_) => ReasonCode::SmtpTimeout,
+			SmtpError::CircuitOpen(_) => ReasonCode::CircuitOpen,
+			SmtpError::HeadlessError(_) => ReasonCode::HeadlessFailed,
+			SmtpError::YahooError(_)
+			| SmtpError::GmailError(_)
+			| SmtpError::Microsoft365Error(_) => ReasonCode::ApiFailed,
+			_ if err.is_connection_error() => ReasonCode::SmtpConnectionFailed,
+			_ => ReasonCode::SmtpError,
+		}
+	}
+}
+
+/// Find all the reasons explaining the verdict of a verification which went
+/// through the SMTP stage, from its misc and SMTP details.
+pub fn find_reasons(misc: &MiscDetails, smtp: &Result<SmtpDetails, SmtpError>) -> Vec<ReasonCode> {
+	let mut reasons = vec![];
+
+	match smtp {
+		Ok(smtp) => {
+			if !smtp.can_connect_smtp {
+				reasons.push(ReasonCode::SmtpConnectionFailed);
+			}
+			if smtp.is_disabled {
+				reasons.push(ReasonCode::Disabled);
+			}
+			if smtp.has_full_inbox {
+				reasons.push(ReasonCode::MailboxFull);
+			}
+			if smtp.can_connect_smtp
+				&& !smtp.is_deliverable
+				&& !smtp.is_disabled
+				&& !smtp.has_full_inbox
+			{
+				reasons.push(ReasonCode::Undeliverable);
+			}
+			if smtp.is_catch_all {
+				reasons.push(ReasonCode::CatchAll);
+			}
+		}
+		Err(err) => reasons.push(err.into()),
+	}
+
+	if misc.is_role_account {
+		reasons.push(ReasonCode::RoleAccount);
+	}
+	if misc.is_disposable {
+		reasons.push(ReasonCode::Disposable);
+	}
+
+	reasons
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+
+	#[test]
+	fn should_find_risky_reasons() {
+		let misc = MiscDetails {
+			is_role_account: true,
+			..Default::default()
+		};
+		let smtp = Ok(SmtpDetails {
+			can_connect_smtp: true,
+			is_deliverable: true,
+			is_catch_all: true,
+			..Default::default()
+		});
+
+		assert_eq!(
+			find_reasons(&misc, &smtp),
+			vec![ReasonCode::CatchAll, ReasonCode::RoleAccount]
+		);
+	}
+
+	#[test]
+	fn should_find_smtp_error_reasons() {
+		let smtp = Err(SmtpError::Timeout(Duration::from_secs(10)));
+		assert_eq!(
+			find_reasons(&MiscDetails::default(), &smtp),
+			vec![ReasonCode::SmtpTimeout]
+		);
+	}
+
+	#[test]
+	fn should_display_as_in_json() {
+		let code = ReasonCode::SmtpConnectionFailed;
+		assert_eq!(
+			serde_json::to_string(&code).unwrap(),
+			format!("\"{}\"", code)
+		);
+	}
+}
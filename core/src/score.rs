@@ -0,0 +1,559 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Points-based confidence score of an email verification.
+//!
+//! Each verification starts at 100 points, and each signal found during the
+//! verification (catch-all domain, role account...) adds its weight, which is
+//! usually negative. The final score is clamped between 0 and 100, and the
+//! `Reachable` verdict is derived from thresholds on that score. A
+//! `ClassificationPolicy` can then force the verdict for some signals.
+//!
+//! ref: https://github.com/reacherhq/check-if-email-exists/issues/935
+
+use crate::misc::MiscDetails;
+use crate::smtp::verif_method::EmailProvider;
+use crate::smtp::{SmtpDebug, SmtpDebugVerifMethod, SmtpDetails, SmtpError};
+use crate::util::input_output::Reachable;
+use serde::{Deserialize, Serialize};
+
+/// The maximum score, given to an email with no negative signal.
+pub const MAX_SCORE: u8 = 100;
+
+/// A signal found during the verification, which contributes to the score.
+#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoreSignal {
+	/// The SMTP server rejected the email, the account is disabled, or we
+	/// could not connect to the SMTP server.
+	Undeliverable,
+	/// The SMTP verification could not complete, so we don't know whether
+	/// the email is deliverable.
+	SmtpUnknown,
+	/// The domain accepts all emails, so the SMTP answer is not conclusive.
+	CatchAll,
+	/// The email is a role account, e.g. support@ or info@.
+	RoleAccount,
+	/// The domain is a disposable email provider.
+	Disposable,
+	/// The mailbox exists, but its inbox is full.
+	FullInbox,
+	/// The email provider is known to give unreliable answers over SMTP,
+	/// e.g. Yahoo and Hotmail B2C.
+	UnreliableProvider,
+	/// The email was verified using a headless browser, which is less
+	/// reliable than SMTP or an API, as the provider's web flow may change.
+	HeadlessVerification,
+}
+
+/// Weights of each signal, added to the score when the signal is present.
+///
+/// The signals covered by the `ClassificationPolicy` (catch-all, role
+/// account, disposable and full inbox) weigh 0 when the policy classifies
+/// them as Safe. Otherwise, the policy forces their verdict, so with the
+/// default policy their weights only change the value of the score, not the
+/// `Reachable` verdict.
+#[derive(Debug, Clone, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct ScoreWeights {
+	pub undeliverable: i32,
+	pub smtp_unknown: i32,
+	pub catch_all: i32,
+	pub role_account: i32,
+	pub disposable: i32,
+	pub full_inbox: i32,
+	pub unreliable_provider: i32,
+	pub headless_verification: i32,
+}
+
+impl Default for ScoreWeights {
+	fn default() -> Self {
+		Self {
+			undeliverable: -100,
+			smtp_unknown: -50,
+			catch_all: -25,
+			role_account: -25,
+			disposable: -25,
+			full_inbox: -25,
+			unreliable_provider: -10,
+			headless_verification: -5,
+		}
+	}
+}
+
+impl ScoreWeights {
+	/// Get the weight of a signal.
+	pub fn get(&self, signal: ScoreSignal) -> i32 {
+		match signal {
+			ScoreSignal::Undeliverable => self.undeliverable,
+			ScoreSignal::SmtpUnknown => self.smtp_unknown,
+			ScoreSignal::CatchAll => self.catch_all,
+			ScoreSignal::RoleAccount => self.role_account,
+			ScoreSignal::Disposable => self.disposable,
+			ScoreSignal::FullInbox => self.full_inbox,
+			ScoreSignal::UnreliableProvider => self.unreliable_provider,
+			ScoreSignal::HeadlessVerification => self.headless_verification,
+		}
+	}
+}
+
+/// Configuration of the score: the weight of each signal, and the thresholds
+/// used to derive the `Reachable` verdict from the score.
+///
+/// The defaults are chosen so that the verdict matches the one of the
+/// previous, non-points-based, system in most cases.
+#[derive(Debug, Clone, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct ScoreConfig {
+	pub weights: ScoreWeights,
+	/// Emails with a score greater than or equal to this threshold are Safe.
+	///
+	/// Defaults to 80.
+	pub safe_threshold: u8,
+	/// Emails with a score greater than or equal to this threshold, but
+	/// below `safe_threshold`, are Risky. Emails below it are Invalid.
+	///
+	/// Defaults to 10.
+	pub risky_threshold: u8,
+}
+
+impl Default for ScoreConfig {
+	fn default() -> Self {
+		Self {
+			weights: ScoreWeights::default(),
+			safe_threshold: 80,
+			risky_threshold: 10,
+		}
+	}
+}
+
+impl ScoreConfig {
+	/// Derive the `Reachable` verdict from a score. If the SMTP verification
+	/// could not complete, the verdict is always Unknown.
+	pub fn reachable(&self, score: &Score, smtp_completed: bool) -> Reachable {
+		if !smtp_completed {
+			Reachable::Unknown
+		} else if score.value >= self.safe_threshold {
+			Reachable::Safe
+		} else if score.value >= self.risky_threshold {
+			Reachable::Risky
+		} else {
+			Reachable::Invalid
+		}
+	}
+}
+
+/// The verdict a signal leads to, in a `ClassificationPolicy`.
+#[derive(Debug, Default, Clone, Copy, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignalVerdict {
+	/// The signal is ignored, and doesn't count in the score.
+	Safe,
+	/// The email is Risky, whatever its score.
+	#[default]
+	Risky,
+	/// The email is Invalid, whatever its score.
+	Invalid,
+}
+
+/// How the signals which don't say anything about the deliverability of the
+/// email affect the `Reachable` verdict. For example, B2B users may want
+/// catch-all emails to be Safe, while marketing users may want role accounts
+/// to be Invalid.
+///
+/// The default is to classify all of them as Risky.
+#[derive(Debug, Default, Clone, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct ClassificationPolicy {
+	pub catch_all: SignalVerdict,
+	pub role_account: SignalVerdict,
+	pub disposable: SignalVerdict,
+	pub full_inbox: SignalVerdict,
+}
+
+impl ClassificationPolicy {
+	/// Get the verdict of a signal, if it's covered by the policy.
+	pub fn get(&self, signal: ScoreSignal) -> Option<SignalVerdict> {
+		match signal {
+			ScoreSignal::CatchAll => Some(self.catch_all),
+			ScoreSignal::RoleAccount => Some(self.role_account),
+			ScoreSignal::Disposable => Some(self.disposable),
+			ScoreSignal::FullInbox => Some(self.full_inbox),
+			_ => None,
+		}
+	}
+
+	/// Apply the policy to the verdict derived from the score. A signal
+	/// classified as Invalid always wins, even if the SMTP verification
+	/// could not complete. Otherwise, a signal classified as Risky makes the
+	/// email Risky, unless the verdict is Unknown.
+	pub fn apply(&self, reachable: Reachable, score: &Score) -> Reachable {
+		let verdicts: Vec<SignalVerdict> = score
+			.signals
+			.iter()
+			.filter_map(|s| self.get(s.signal))
+			.collect();
+
+		if verdicts.contains(&SignalVerdict::Invalid) {
+			Reachable::Invalid
+		} else if reachable == Reachable::Unknown {
+			Reachable::Unknown
+		} else if verdicts.contains(&SignalVerdict::Risky) {
+			Reachable::Risky
+		} else {
+			reachable
+		}
+	}
+}
+
+/// A signal with the weight it contributed to the score.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct WeightedSignal {
+	pub signal: ScoreSignal,
+	pub weight: i32,
+}
+
+/// The confidence score of an email verification.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Score {
+	/// The score, between 0 and 100. Higher is more confident that the email
+	/// is reachable.
+	pub value: u8,
+	/// The signals which contributed to the score.
+	pub signals: Vec<WeightedSignal>,
+}
+
+/// Find all the signals of an email verification.
+fn find_signals(
+	misc: &MiscDetails,
+	smtp: &Result<SmtpDetails, SmtpError>,
+	smtp_debug: &SmtpDebug,
+) -> Vec<ScoreSignal> {
+	let mut signals = vec![];
+
+	match smtp {
+		Ok(smtp) => {
+			// A full inbox means the mailbox exists, so we don't count it as
+			// undeliverable.
+			if (!smtp.is_deliverable && !smtp.has_full_inbox)
+				|| !smtp.can_connect_smtp
+				|| smtp.is_disabled
+			{
+				signals.push(ScoreSignal::Undeliverable);
+			}
+			if smtp.is_catch_all {
+				signals.push(ScoreSignal::CatchAll);
+			}
+			if smtp.has_full_inbox {
+				signals.push(ScoreSignal::FullInbox);
+			}
+		}
+		Err(_) => signals.push(ScoreSignal::SmtpUnknown),
+	}
+
+	if misc.is_role_account {
+		signals.push(ScoreSignal::RoleAccount);
+	}
+	if misc.is_disposable {
+		signals.push(ScoreSignal::Disposable);
+	}
+
+	match smtp_debug.verif_method {
+		SmtpDebugVerifMethod::Smtp(_) => {
+			let provider = smtp_debug
+				.attempted_hosts
+				.last()
+				.map(|host| EmailProvider::from_mx_host(host));
+			if matches!(
+				provider,
+				Some(EmailProvider::Yahoo) | Some(EmailProvider::HotmailB2C)
+			) {
+				signals.push(ScoreSignal::UnreliableProvider);
+			}
+		}
+		SmtpDebugVerifMethod::Headless => signals.push(ScoreSignal::HeadlessVerification),
+		SmtpDebugVerifMethod::Api | SmtpDebugVerifMethod::Skipped => {}
+	}
+
+	signals
+}
+
+/// Calculate the confidence score of an email verification from its misc
+/// and SMTP details. Signals classified as Safe by the policy don't count.
+pub fn calculate_score(
+	misc: &MiscDetails,
+	smtp: &Result<SmtpDetails, SmtpError>,
+	smtp_debug: &SmtpDebug,
+	config: &ScoreConfig,
+	policy: &ClassificationPolicy,
+) -> Score {
+	let signals: Vec<WeightedSignal> = find_signals(misc, smtp, smtp_debug)
+		.into_iter()
+		.map(|signal| WeightedSignal {
+			signal,
+			weight: match policy.get(signal) {
+				Some(SignalVerdict::Safe) => 0,
+				_ => config.weights.get(signal),
+			},
+		})
+		.collect();
+	let total = signals
+		.iter()
+		.fold(MAX_SCORE as i32, |acc, s| acc.saturating_add(s.weight));
+
+	Score {
+		value: total.clamp(0, MAX_SCORE as i32) as u8,
+		signals,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::smtp::verif_method::VerifMethodSmtpConfig;
+	use crate::smtp::SmtpDebugVerifMethodSmtp;
+
+	fn smtp_debug(host: &str) -> SmtpDebug {
+		SmtpDebug {
+			verif_method: SmtpDebugVerifMethod::Smtp(Box::new(SmtpDebugVerifMethodSmtp {
+				host: host.to_string(),
+				verif_method: VerifMethodSmtpConfig::default(),
+				catch_all_cache_hit: false,
+				port: 25,
+				source_ip: None,
+				proxy: None,
+			})),
+			attempted_hosts: vec![host.to_string()],
+			transcript: vec![],
+			durations: Default::default(),
+		}
+	}
+
+	fn deliverable() -> Result<SmtpDetails, SmtpError> {
+		Ok(SmtpDetails {
+			can_connect_smtp: true,
+			is_deliverable: true,
+			..Default::default()
+		})
+	}
+
+	#[test]
+	fn should_be_safe_without_signals() {
+		let config = ScoreConfig::default();
+		let score = calculate_score(
+			&MiscDetails::default(),
+			&deliverable(),
+			&smtp_debug("mx.example.com."),
+			&config,
+			&ClassificationPolicy::default(),
+		);
+
+		assert_eq!(score.value, MAX_SCORE);
+		assert!(score.signals.is_empty());
+		assert_eq!(config.reachable(&score, true), Reachable::Safe);
+	}
+
+	#[test]
+	fn should_be_risky_with_catch_all() {
+		let config = ScoreConfig::default();
+		let smtp = Ok(SmtpDetails {
+			can_connect_smtp: true,
+			is_deliverable: true,
+			is_catch_all: true,
+			..Default::default()
+		});
+		let score = calculate_score(
+			&MiscDetails::default(),
+			&smtp,
+			&smtp_debug("mx.example.com."),
+			&config,
+			&ClassificationPolicy::default(),
+		);
+
+		assert_eq!(score.value, 75);
+		assert_eq!(
+			score.signals,
+			vec![WeightedSignal {
+				signal: ScoreSignal::CatchAll,
+				weight: -25
+			}]
+		);
+		assert_eq!(config.reachable(&score, true), Reachable::Risky);
+	}
+
+	#[test]
+	fn should_be_invalid_when_undeliverable() {
+		let config = ScoreConfig::default();
+		let smtp = Ok(SmtpDetails {
+			can_connect_smtp: true,
+			..Default::default()
+		});
+		let score = calculate_score(
+			&MiscDetails::default(),
+			&smtp,
+			&smtp_debug("mx.example.com."),
+			&config,
+			&ClassificationPolicy::default(),
+		);
+
+		assert_eq!(score.value, 0);
+		assert_eq!(config.reachable(&score, true), Reachable::Invalid);
+	}
+
+	#[test]
+	fn should_use_custom_weights() {
+		let mut config = ScoreConfig::default();
+		config.weights.unreliable_provider = -30;
+		let score = calculate_score(
+			&MiscDetails::default(),
+			&deliverable(),
+			&smtp_debug("mta5.am0.yahoodns.net."),
+			&config,
+			&ClassificationPolicy::default(),
+		);
+
+		assert_eq!(score.value, 70);
+		assert_eq!(config.reachable(&score, true), Reachable::Risky);
+		assert_eq!(config.reachable(&score, false), Reachable::Unknown);
+	}
+
+	#[test]
+	fn should_apply_classification_policy() {
+		let smtp = Ok(SmtpDetails {
+			can_connect_smtp: true,
+			is_deliverable: true,
+			is_catch_all: true,
+			..Default::default()
+		});
+		let misc = MiscDetails {
+			is_role_account: true,
+			..Default::default()
+		};
+		let config = ScoreConfig::default();
+
+		let policy = ClassificationPolicy {
+			catch_all: SignalVerdict::Safe,
+			role_account: SignalVerdict::Safe,
+			..Default::default()
+		};
+		let score = calculate_score(
+			&misc,
+			&smtp,
+			&smtp_debug("mx.example.com."),
+			&config,
+			&policy,
+		);
+		assert_eq!(score.value, MAX_SCORE);
+		assert_eq!(
+			policy.apply(config.reachable(&score, true), &score),
+			Reachable::Safe
+		);
+
+		let policy = ClassificationPolicy {
+			catch_all: SignalVerdict::Safe,
+			role_account: SignalVerdict::Invalid,
+			..Default::default()
+		};
+		let score = calculate_score(
+			&misc,
+			&smtp,
+			&smtp_debug("mx.example.com."),
+			&config,
+			&policy,
+		);
+		assert_eq!(
+			policy.apply(config.reachable(&score, true), &score),
+			Reachable::Invalid
+		);
+	}
+
+	/// The verdict of the system before the score, which the default score
+	/// configuration and classification policy must keep.
+	fn previous_reachable(misc: &MiscDetails, smtp: &SmtpDetails) -> Reachable {
+		if misc.is_disposable || misc.is_role_account || smtp.is_catch_all || smtp.has_full_inbox {
+			Reachable::Risky
+		} else if !smtp.is_deliverable || !smtp.can_connect_smtp || smtp.is_disabled {
+			Reachable::Invalid
+		} else {
+			Reachable::Safe
+		}
+	}
+
+	#[test]
+	fn should_keep_previous_verdicts_by_default() {
+		let config = ScoreConfig::default();
+		let policy = ClassificationPolicy::default();
+		for bits in 0..(1 << 7) {
+			let bit = |i: u32| bits & (1 << i) != 0;
+			let misc = MiscDetails {
+				is_disposable: bit(0),
+				is_role_account: bit(1),
+				..Default::default()
+			};
+			let smtp = Ok(SmtpDetails {
+				can_connect_smtp: bit(2),
+				is_deliverable: bit(3),
+				is_disabled: bit(4),
+				is_catch_all: bit(5),
+				has_full_inbox: bit(6),
+				..Default::default()
+			});
+			let score = calculate_score(
+				&misc,
+				&smtp,
+				&smtp_debug("mx.example.com."),
+				&config,
+				&policy,
+			);
+
+			assert_eq!(
+				policy.apply(config.reachable(&score, true), &score),
+				previous_reachable(&misc, smtp.as_ref().unwrap()),
+				"{:?} {:?}",
+				misc,
+				smtp
+			);
+		}
+	}
+
+	#[test]
+	fn should_keep_risky_by_default() {
+		// Undeliverable, but disposable: the previous system returned Risky.
+		let smtp = Ok(SmtpDetails {
+			can_connect_smtp: true,
+			..Default::default()
+		});
+		let misc = MiscDetails {
+			is_disposable: true,
+			..Default::default()
+		};
+		let config = ScoreConfig::default();
+		let policy = ClassificationPolicy::default();
+		let score = calculate_score(
+			&misc,
+			&smtp,
+			&smtp_debug("mx.example.com."),
+			&config,
+			&policy,
+		);
+
+		assert_eq!(config.reachable(&score, true), Reachable::Invalid);
+		assert_eq!(
+			policy.apply(config.reachable(&score, true), &score),
+			Reachable::Risky
+		);
+	}
+}
@@ -0,0 +1,100 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Cache of the catch-all status of domains, so that we don't need to probe
+//! a random address on each verification.
+
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A cache of whether domains are catch-all. It's consulted before probing
+/// the SMTP server with a random address, and filled with the results of
+/// successful probes.
+pub trait CatchAllCache: Debug + Send + Sync {
+	/// Get the cached catch-all status of the domain, if any and not expired.
+	fn get<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Option<bool>>;
+
+	/// Store the catch-all status of the domain.
+	fn set<'a>(&'a self, domain: &'a str, is_catch_all: bool) -> BoxFuture<'a, ()>;
+}
+
+/// Maximum number of entries in the in-memory cache, above which expired
+/// entries are pruned.
+const MAX_IN_MEMORY_ENTRIES: usize = 10_000;
+
+/// An in-memory catch-all cache, whose entries expire after a TTL.
+#[derive(Debug)]
+pub struct InMemoryCatchAllCache {
+	ttl: Duration,
+	entries: Mutex<HashMap<String, (bool, Instant)>>,
+}
+
+impl InMemoryCatchAllCache {
+	pub fn new(ttl: Duration) -> Self {
+		Self {
+			ttl,
+			entries: Mutex::new(HashMap::new()),
+		}
+	}
+}
+
+impl CatchAllCache for InMemoryCatchAllCache {
+	fn get<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Option<bool>> {
+		let entries = self
+			.entries
+			.lock()
+			.expect("Catch-all cache mutex is not poisoned. qed.");
+		let result = entries
+			.get(&domain.to_lowercase())
+			.filter(|(_, inserted_at)| inserted_at.elapsed() < self.ttl)
+			.map(|(is_catch_all, _)| *is_catch_all);
+
+		Box::pin(futures::future::ready(result))
+	}
+
+	fn set<'a>(&'a self, domain: &'a str, is_catch_all: bool) -> BoxFuture<'a, ()> {
+		let mut entries = self
+			.entries
+			.lock()
+			.expect("Catch-all cache mutex is not poisoned. qed.");
+		if entries.len() >= MAX_IN_MEMORY_ENTRIES {
+			entries.retain(|_, (_, inserted_at)| inserted_at.elapsed() < self.ttl);
+		}
+		entries.insert(domain.to_lowercase(), (is_catch_all, Instant::now()));
+
+		Box::pin(futures::future::ready(()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn should_cache_until_expired() {
+		let cache = InMemoryCatchAllCache::new(Duration::from_millis(50));
+		assert_eq!(cache.get("example.com").await, None);
+
+		cache.set("Example.com", true).await;
+		assert_eq!(cache.get("example.com").await, Some(true));
+
+		tokio::time::sleep(Duration::from_millis(60)).await;
+		assert_eq!(cache.get("example.com").await, None);
+	}
+}
@@ -0,0 +1,252 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Circuit breaker per MX host and proxy. After too many consecutive
+//! connection or rate limiting failures on an MX host, the circuit opens:
+//! the next verifications on this host fail right away, instead of each
+//! waiting for its own timeout. After a cool-down period, the circuit is
+//! half-open, and one verification probes the host again, closing the
+//! circuit if it succeeds.
+//!
+//! The state of the circuits is shared by all verifications of the process.
+
+use super::{SmtpError, SmtpErrorDesc};
+use crate::LOG_TARGET;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Configuration of the circuit breaker.
+#[derive(Debug, Clone, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+	/// Number of consecutive connection or rate limiting failures on an MX
+	/// host after which the circuit opens.
+	///
+	/// Defaults to 5.
+	pub failure_threshold: u32,
+	/// How long the circuit stays open, in seconds, before a verification
+	/// probes the MX host again.
+	///
+	/// Defaults to 60.
+	pub cooldown_secs: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+	fn default() -> Self {
+		Self {
+			failure_threshold: 5,
+			cooldown_secs: 60,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CircuitState {
+	Closed,
+	Open {
+		until: Instant,
+	},
+	/// One verification is probing the MX host. If it never reports back,
+	/// e.g. because it was cancelled, another one probes after the cool-down.
+	HalfOpen {
+		probe_started: Instant,
+	},
+}
+
+#[derive(Debug)]
+struct Circuit {
+	consecutive_failures: u32,
+	state: CircuitState,
+}
+
+impl Default for Circuit {
+	fn default() -> Self {
+		Self {
+			consecutive_failures: 0,
+			state: CircuitState::Closed,
+		}
+	}
+}
+
+/// The MX host, and the ID of the proxy used to reach it, if any.
+type CircuitKey = (String, Option<String>);
+
+static CIRCUITS: Lazy<Mutex<HashMap<CircuitKey, Circuit>>> =
+	Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Whether the error counts as a failure of the MX host.
+fn is_failure(err: &SmtpError) -> bool {
+	err.is_connection_error()
+		|| matches!(
+			err.get_description(),
+			Some(SmtpErrorDesc::TooManyConnections | SmtpErrorDesc::RateLimited)
+		)
+}
+
+fn key(mx_host: &str, proxy_id: Option<&str>) -> CircuitKey {
+	(mx_host.to_lowercase(), proxy_id.map(str::to_string))
+}
+
+impl CircuitBreakerConfig {
+	fn cooldown(&self) -> Duration {
+		Duration::from_secs(self.cooldown_secs)
+	}
+
+	/// Check whether we can verify an email on the MX host. Returns
+	/// `SmtpError::CircuitOpen` if the circuit is open, or if it's half-open
+	/// and another verification is already probing the host.
+	pub(crate) fn acquire(&self, mx_host: &str, proxy_id: Option<&str>) -> Result<(), SmtpError> {
+		let mut circuits = CIRCUITS
+			.lock()
+			.expect("Circuit breaker mutex is not poisoned. qed.");
+		let circuit = match circuits.get_mut(&key(mx_host, proxy_id)) {
+			Some(circuit) => circuit,
+			None => return Ok(()),
+		};
+		let now = Instant::now();
+
+		match circuit.state {
+			CircuitState::Closed => Ok(()),
+			CircuitState::Open { until } if until > now => {
+				Err(SmtpError::CircuitOpen(mx_host.to_string()))
+			}
+			CircuitState::HalfOpen { probe_started }
+				if now.duration_since(probe_started) < self.cooldown() =>
+			{
+				Err(SmtpError::CircuitOpen(mx_host.to_string()))
+			}
+			_ => {
+				tracing::debug!(
+					target: LOG_TARGET,
+					mx_host=mx_host,
+					proxy=?proxy_id,
+					"Circuit half-open, probing MX host"
+				);
+				circuit.state = CircuitState::HalfOpen { probe_started: now };
+				Ok(())
+			}
+		}
+	}
+
+	/// Record the result of a verification on the MX host, opening the
+	/// circuit after too many consecutive failures, and closing it on
+	/// success.
+	pub(crate) fn record<T>(
+		&self,
+		mx_host: &str,
+		proxy_id: Option<&str>,
+		result: &Result<T, SmtpError>,
+	) {
+		let mut circuits = CIRCUITS
+			.lock()
+			.expect("Circuit breaker mutex is not poisoned. qed.");
+
+		match result {
+			Err(err) if is_failure(err) => {
+				let circuit = circuits.entry(key(mx_host, proxy_id)).or_default();
+				circuit.consecutive_failures += 1;
+				let was_probing = matches!(circuit.state, CircuitState::HalfOpen { .. });
+				if was_probing || circuit.consecutive_failures >= self.failure_threshold {
+					tracing::warn!(
+						target: LOG_TARGET,
+						mx_host=mx_host,
+						proxy=?proxy_id,
+						failures=circuit.consecutive_failures,
+						cooldown_secs=self.cooldown_secs,
+						"Opening circuit on MX host"
+					);
+					circuit.state = CircuitState::Open {
+						until: Instant::now() + self.cooldown(),
+					};
+				}
+			}
+			_ => {
+				circuits.remove(&key(mx_host, proxy_id));
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// The circuits are global, so each test uses its own MX hosts.
+	fn timeout() -> Result<(), SmtpError> {
+		Err(SmtpError::Timeout(Duration::from_secs(1)))
+	}
+
+	#[test]
+	fn should_open_after_consecutive_failures() {
+		let config = CircuitBreakerConfig {
+			failure_threshold: 2,
+			cooldown_secs: 60,
+		};
+
+		config.record("open.example.com.", None, &timeout());
+		assert!(config.acquire("open.example.com.", None).is_ok());
+		config.record("open.example.com.", None, &timeout());
+		assert!(matches!(
+			config.acquire("open.example.com.", None),
+			Err(SmtpError::CircuitOpen(_))
+		));
+
+		// The circuit is per proxy.
+		assert!(config.acquire("open.example.com.", Some("proxy1")).is_ok());
+	}
+
+	#[test]
+	fn should_reset_on_success() {
+		let config = CircuitBreakerConfig {
+			failure_threshold: 2,
+			cooldown_secs: 60,
+		};
+
+		config.record("reset.example.com.", None, &timeout());
+		config.record("reset.example.com.", None, &Ok(()));
+		config.record("reset.example.com.", None, &timeout());
+		assert!(config.acquire("reset.example.com.", None).is_ok());
+	}
+
+	#[test]
+	fn should_half_open_after_cooldown() {
+		let config = CircuitBreakerConfig::default();
+		CIRCUITS.lock().unwrap().insert(
+			key("half.example.com.", None),
+			Circuit {
+				consecutive_failures: 5,
+				state: CircuitState::Open {
+					until: Instant::now() - Duration::from_secs(1),
+				},
+			},
+		);
+
+		// Only one verification probes the host.
+		assert!(config.acquire("half.example.com.", None).is_ok());
+		assert!(config.acquire("half.example.com.", None).is_err());
+
+		// A failed probe opens the circuit again.
+		config.record("half.example.com.", None, &timeout());
+		assert!(config.acquire("half.example.com.", None).is_err());
+		assert_eq!(
+			CIRCUITS.lock().unwrap()[&key("half.example.com.", None)].consecutive_failures,
+			6
+		);
+	}
+}
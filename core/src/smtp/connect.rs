@@ -15,23 +15,35 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use async_recursion::async_recursion;
-use async_smtp::commands::{MailCommand, RcptCommand};
-use async_smtp::extension::ClientId;
+use async_smtp::commands::{MailCommand, RcptCommand, RsetCommand, StarttlsCommand};
+use async_smtp::error::Error as AsyncSmtpError;
+use async_smtp::extension::{ClientId, Extension, ServerInfo};
+use async_smtp::response::Response;
 use async_smtp::{SmtpClient, SmtpTransport};
 use fast_socks5::client::Config;
 use fast_socks5::{client::Socks5Stream, Result};
 use rand::rngs::SmallRng;
 use rand::{distributions::Alphanumeric, Rng, SeedableRng};
+use std::collections::HashMap;
+use std::fmt;
 use std::iter;
+use std::net::IpAddr;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, BufStream};
-use tokio::net::TcpStream;
 
-use super::parser;
-use super::verif_method::VerifMethodSmtp;
-use super::{SmtpDetails, SmtpError};
+use super::http_connect;
+use super::parser::{self, ReplyClass, SmtpReply};
+use super::source_ip;
+use super::tls::{self, SmtpTlsDetails, StarttlsMode};
+use super::transcript::{SmtpTranscriptEntry, TranscriptRecorder, TranscriptStream};
+use super::verif_method::{VerifMethodSmtp, VerifMethodSmtpConfig};
+use super::{CatchAllCache, SmtpDebugVerifMethodSmtp, SmtpDetails, SmtpDurations, SmtpError};
+use crate::events::{emit, CheckEvent, CheckEventSender};
 use crate::rules::{has_rule, Rule};
-use crate::{EmailAddress, LOG_TARGET};
+use crate::util::input_output::add_elapsed;
+use crate::{CheckEmailInputProxy, EmailAddress, ProxyType, LOG_TARGET};
 
 // Define a new trait that combines AsyncRead, AsyncWrite, and Unpin
 trait AsyncReadWrite: AsyncRead + AsyncWrite + Unpin + Send {}
@@ -57,22 +69,56 @@ macro_rules! try_smtp (
     })
 );
 
-/// Connect to an SMTP host and return the configured client transport.
+type SmtpStream = SmtpTransport<BufStream<TranscriptStream<Box<dyn AsyncReadWrite>>>>;
+
+/// Connect to an SMTP host and return the configured client transport, with
+/// details about its TLS encryption and the local IP it's bound to, if set.
+/// The conversation is recorded in `recorder`, and the time spent connecting
+/// and until the server is ready for RCPT TO in `durations`.
 async fn connect_to_smtp_host(
 	to_email: &EmailAddress,
 	mx_host: &str,
 	verif_method: &VerifMethodSmtp,
-) -> Result<SmtpTransport<BufStream<Box<dyn AsyncReadWrite>>>, SmtpError> {
+	recorder: Arc<Mutex<TranscriptRecorder>>,
+	durations: &mut SmtpDurations,
+) -> Result<(SmtpStream, SmtpTlsDetails, Option<IpAddr>), SmtpError> {
 	// hostname verification fails if it ends with '.', for example, using
 	// SOCKS5 proxies we can `io: incomplete` error.
 	let clean_host = mx_host.trim_end_matches('.').to_string();
-	let smtp_client = SmtpClient::new()
-		.hello_name(ClientId::Domain(verif_method.config.hello_name.to_string()))
-		// Sometimes, using socks5 proxy, we get an `io: incomplete` error
-		// when using pipelining and sending two consecutive RCPT TO commands.
-		.pipelining(false);
 
-	let stream: BufStream<Box<dyn AsyncReadWrite>> = match &verif_method.proxy {
+	let start = Instant::now();
+	let stream = open_stream(to_email, &clean_host, verif_method).await;
+	add_elapsed(&mut durations.connect, start);
+	let (stream, source_ip) = stream?;
+
+	let start = Instant::now();
+	let smtp_transport = start_smtp_session(
+		stream,
+		to_email,
+		mx_host,
+		&clean_host,
+		verif_method,
+		recorder,
+	)
+	.await;
+	add_elapsed(&mut durations.greeting, start);
+	let (smtp_transport, tls_details) = smtp_transport?;
+
+	Ok((smtp_transport, tls_details, source_ip))
+}
+
+/// Open a TCP connection to the SMTP host, directly or through the proxy,
+/// and return it with the local IP it's bound to, if set.
+async fn open_stream(
+	to_email: &EmailAddress,
+	clean_host: &str,
+	verif_method: &VerifMethodSmtp,
+) -> Result<(Box<dyn AsyncReadWrite>, Option<IpAddr>), SmtpError> {
+	let mut source_ip = None;
+	let stream: Box<dyn AsyncReadWrite> = match &verif_method.proxy {
+		Some(proxy) if proxy.proxy_type == ProxyType::HttpConnect => {
+			Box::new(http_connect::connect(proxy, clean_host, verif_method.config.smtp_port).await?)
+		}
 		Some(proxy) => {
 			let mut config = Config::default();
 			if let Some(timeout_ms) = proxy.timeout_ms {
@@ -83,7 +129,7 @@ async fn connect_to_smtp_host(
 				if let (Some(username), Some(password)) = (&proxy.username, &proxy.password) {
 					Socks5Stream::connect_with_password(
 						(proxy.host.as_ref(), proxy.port),
-						clean_host.clone(),
+						clean_host.to_string(),
 						verif_method.config.smtp_port,
 						username.clone(),
 						password.clone(),
@@ -93,25 +139,156 @@ async fn connect_to_smtp_host(
 				} else {
 					Socks5Stream::connect(
 						(proxy.host.as_ref(), proxy.port),
-						clean_host.clone(),
+						clean_host.to_string(),
 						verif_method.config.smtp_port,
 						config,
 					)
 					.await?
 				};
-			BufStream::new(Box::new(socks_stream) as Box<dyn AsyncReadWrite>)
+			Box::new(socks_stream)
 		}
 		None => {
-			let tcp_stream =
-				TcpStream::connect(format!("{}:{}", clean_host, verif_method.config.smtp_port))
-					.await?;
-			BufStream::new(Box::new(tcp_stream) as Box<dyn AsyncReadWrite>)
+			let domain = to_email.to_string();
+			let domain = domain.rsplit('@').next().unwrap_or_default();
+			let (tcp_stream, ip) = source_ip::connect_tcp(
+				clean_host,
+				verif_method.config.smtp_port,
+				domain,
+				&verif_method.config,
+			)
+			.await?;
+			source_ip = ip;
+			Box::new(tcp_stream)
+		}
+	};
+
+	Ok((stream, source_ip))
+}
+
+/// Whether the last reply recorded, i.e. the reply to EHLO, advertises
+/// STARTTLS. `SmtpTransport` doesn't expose the server's extensions, so we
+/// parse them again from the transcript.
+fn advertises_starttls(recorder: &Mutex<TranscriptRecorder>) -> bool {
+	let mut reply = recorder
+		.lock()
+		.expect("Transcript mutex is not poisoned. qed.")
+		.last_reply()
+		.join("\r\n");
+	reply.push_str("\r\n");
+
+	Response::from_str(&reply)
+		.and_then(|response| ServerInfo::from_response(&response))
+		.is_ok_and(|info| info.supports_feature(Extension::StartTls))
+}
+
+/// Start the SMTP session on the stream: read the greeting, send EHLO,
+/// upgrade to TLS if configured, and send MAIL FROM.
+async fn start_smtp_session(
+	stream: Box<dyn AsyncReadWrite>,
+	to_email: &EmailAddress,
+	mx_host: &str,
+	clean_host: &str,
+	verif_method: &VerifMethodSmtp,
+	recorder: Arc<Mutex<TranscriptRecorder>>,
+) -> Result<(SmtpStream, SmtpTlsDetails), SmtpError> {
+	let smtp_client = || {
+		SmtpClient::new()
+			.hello_name(ClientId::Domain(verif_method.config.hello_name.to_string()))
+			// Sometimes, using socks5 proxy, we get an `io: incomplete` error
+			// when using pipelining and sending two consecutive RCPT TO commands.
+			.pipelining(false)
+	};
+
+	let (stream, mut tls_details) = if verif_method.config.implicit_tls {
+		let (tls_stream, details) = tls::connect_tls(stream, clean_host).await?;
+		let stream: Box<dyn AsyncReadWrite> = Box::new(tls_stream);
+		(stream, details)
+	} else {
+		(stream, SmtpTlsDetails::default())
+	};
+
+	let mut smtp_transport = SmtpTransport::new(
+		smtp_client(),
+		BufStream::new(TranscriptStream::new(stream, Arc::clone(&recorder))),
+	)
+	.await?;
+
+	let starttls = verif_method.config.starttls;
+	let upgrade = if starttls == StarttlsMode::Disabled || tls_details.negotiated {
+		false
+	} else if !advertises_starttls(&recorder) {
+		tracing::debug!(
+			target: LOG_TARGET,
+			email=to_email.to_string(),
+			mx_host=mx_host,
+			"STARTTLS not advertised"
+		);
+		if starttls == StarttlsMode::Required {
+			let _ = smtp_transport.quit().await;
+			return Err(SmtpError::StarttlsNotSupported);
+		}
+		false
+	} else {
+		// `SmtpTransport::starttls` consumes the transport, so we couldn't
+		// continue in plaintext when the server refuses STARTTLS. Instead, we
+		// send it ourselves, and the connection stays usable after a
+		// negative reply.
+		match smtp_transport.get_mut().command(StarttlsCommand).await {
+			Ok(_) => true,
+			Err(AsyncSmtpError::Transient(response) | AsyncSmtpError::Permanent(response))
+				if starttls == StarttlsMode::Opportunistic =>
+			{
+				tracing::debug!(
+					target: LOG_TARGET,
+					email=to_email.to_string(),
+					mx_host=mx_host,
+					reply=?response,
+					"STARTTLS refused, continuing in plaintext"
+				);
+				false
+			}
+			Err(err) => return Err(err.into()),
 		}
 	};
 
-	let mut smtp_transport = SmtpTransport::new(smtp_client, stream).await?;
+	if upgrade {
+		let stream = smtp_transport
+			.into_inner()
+			.into_inner()
+			.into_inner()
+			.into_inner();
+		let (tls_stream, details) = tls::connect_tls(stream, clean_host).await?;
+		tls_details = details;
+		tracing::debug!(
+			target: LOG_TARGET,
+			email=to_email.to_string(),
+			mx_host=mx_host,
+			tls=?tls_details,
+			"Upgraded SMTP connection with STARTTLS"
+		);
+
+		// The server doesn't send a new greeting, but we must send EHLO
+		// again (RFC 3207 section 4.2).
+		let stream: Box<dyn AsyncReadWrite> = Box::new(tls_stream);
+		smtp_transport = SmtpTransport::new(
+			smtp_client().without_greeting(),
+			BufStream::new(TranscriptStream::new(stream, recorder)),
+		)
+		.await?;
+	}
+
+	send_mail_from(&mut smtp_transport, to_email, clean_host, verif_method).await?;
+
+	Ok((smtp_transport, tls_details))
+}
 
-	// Set "MAIL FROM"
+/// Send "MAIL FROM" to start a new mail transaction.
+async fn send_mail_from(
+	smtp_transport: &mut SmtpStream,
+	to_email: &EmailAddress,
+	clean_host: &str,
+	verif_method: &VerifMethodSmtp,
+) -> Result<(), SmtpError> {
 	let from_email = EmailAddress::from_str(&verif_method.config.from_email).unwrap_or_else(|_| {
 		tracing::warn!(
 			target: LOG_TARGET,
@@ -131,7 +308,7 @@ async fn connect_to_smtp_host(
 		verif_method.config.smtp_port
 	);
 
-	Ok(smtp_transport)
+	Ok(())
 }
 
 /// Description of the deliverability information we can gather from
@@ -143,12 +320,26 @@ struct Deliverability {
 	is_deliverable: bool,
 	/// Is the email blocked or disabled by the provider?
 	is_disabled: bool,
+	/// The reply of the server to RCPT TO.
+	reply: Option<SmtpReply>,
+}
+
+impl Deliverability {
+	fn new(reply: Option<SmtpReply>) -> Self {
+		Self {
+			has_full_inbox: false,
+			is_deliverable: false,
+			is_disabled: false,
+			reply,
+		}
+	}
 }
 
 /// Checks deliverability of a target email address using the provided SMTP transport.
 async fn check_email_deliverability<S: AsyncBufRead + AsyncWrite + Unpin + Send>(
 	smtp_transport: &mut SmtpTransport<S>,
 	to_email: &EmailAddress,
+	mx_host: &str,
 ) -> Result<Deliverability, SmtpError> {
 	match smtp_transport
 		.get_mut()
@@ -166,114 +357,361 @@ async fn check_email_deliverability<S: AsyncBufRead + AsyncWrite + Unpin + Send>
 		// So, if `response.is_positive()` (which is a condition for
 		// returning `Ok` from the `command()` method above), then delivery
 		// succeeds, accordingly to RFC 5321.
-		Ok(_) => Ok(Deliverability {
-			has_full_inbox: false,
+		Ok(response) => Ok(Deliverability {
 			is_deliverable: true, // response.is_positive()
-			is_disabled: false,
+			..Deliverability::new(Some(SmtpReply::from_response(&response)))
 		}),
 		Err(err) => {
-			// We cast to lowercase, because our matched strings below are all
-			// lowercase.
+			let reply = SmtpReply::from_error(&err);
+
+			// First classify by the status codes, which are standard.
+			match reply.as_ref().and_then(SmtpReply::classify) {
+				Some(ReplyClass::Disabled) => {
+					return Ok(Deliverability {
+						is_disabled: true,
+						..Deliverability::new(reply)
+					});
+				}
+				Some(ReplyClass::FullInbox) => {
+					return Ok(Deliverability {
+						has_full_inbox: true,
+						..Deliverability::new(reply)
+					});
+				}
+				Some(ReplyClass::Invalid) => return Ok(Deliverability::new(reply)),
+				None => {}
+			}
+
+			// Then fall back on the text of the reply. We cast to lowercase,
+			// because our matched strings below are all lowercase.
 			let err_string = err.to_string().to_lowercase();
 
 			// Check if the email account has been disabled or blocked.
-			if parser::is_disabled_account(&err_string) {
+			if parser::is_disabled_account(&err_string, to_email, mx_host) {
 				return Ok(Deliverability {
-					has_full_inbox: false,
-					is_deliverable: false,
 					is_disabled: true,
+					..Deliverability::new(reply)
 				});
 			}
 
 			// Check if the email account has a full inbox.
-			if parser::is_full_inbox(&err_string) {
+			if parser::is_full_inbox(&err_string, to_email, mx_host) {
 				return Ok(Deliverability {
 					has_full_inbox: true,
-					is_deliverable: false,
-					is_disabled: false,
+					..Deliverability::new(reply)
 				});
 			}
 
 			// Check error messages that say that user can actually receive
 			// emails.
 			// 4.2.1 The user you are trying to contact is receiving mail at a rate that
-			if err_string
-				.contains("the user you are trying to contact is receiving mail at a rate that")
-			{
+			if parser::is_deliverable(&err_string, to_email, mx_host) {
 				return Ok(Deliverability {
-					has_full_inbox: false,
 					is_deliverable: true,
-					is_disabled: false,
+					..Deliverability::new(reply)
 				});
 			}
 
+			// Greylisting and rate limiting replies often look like invalid
+			// mailboxes, e.g. "Recipient address rejected: Greylisted".
+			let err = SmtpError::AsyncSmtpError(err);
+			if matches!(err.get_description(), Some(desc) if desc.is_retryable()) {
+				return Err(err);
+			}
+
 			// Check that the mailbox doesn't exist.
-			if parser::is_invalid(&err_string, to_email) {
-				return Ok(Deliverability {
-					has_full_inbox: false,
-					is_deliverable: false,
-					is_disabled: false,
-				});
+			if parser::is_invalid(&err_string, to_email, mx_host) {
+				return Ok(Deliverability::new(reply));
 			}
 
 			// Return all unparsable errors,.
-			Err(SmtpError::AsyncSmtpError(err))
+			Err(err)
 		}
 	}
 }
 
-/// Checks if the domain has a catch-all email setup.
-async fn smtp_is_catch_all<S: AsyncBufRead + AsyncWrite + Unpin + Send>(
-	smtp_transport: &mut SmtpTransport<S>,
-	domain: &str,
-	host: &str,
-	to_email: &EmailAddress,
-) -> Result<bool, SmtpError> {
-	if has_rule(domain, host, &Rule::SkipCatchAll) {
-		tracing::debug!(
-			target: LOG_TARGET,
-			email=to_email.to_string(),
-			domain=domain,
-			"Skipping catch-all check"
-		);
-		return Ok(false);
-	}
-
+/// Create a random email on the domain, to probe whether it's catch-all.
+fn random_email(domain: &str) -> Result<EmailAddress, SmtpError> {
 	// Create a random 15-char alphanumerical string.
 	let mut rng = SmallRng::from_entropy();
 	let random_email: String = iter::repeat_with(|| rng.sample(Alphanumeric))
 		.map(char::from)
 		.take(15)
 		.collect();
-	let random_email = EmailAddress::new(format!("{}@{}", random_email, domain))?;
 
-	check_email_deliverability(smtp_transport, &random_email)
-		.await
-		.map(|result| result.is_deliverable)
+	Ok(EmailAddress::new(format!("{}@{}", random_email, domain))?)
 }
 
-/// Creates an SMTP future for email verification.
-async fn create_smtp_future(
-	to_email: &EmailAddress,
-	mx_host: &str,
-	domain: &str,
-	verif_method: &VerifMethodSmtp,
-) -> Result<(bool, Deliverability), SmtpError> {
-	// FIXME If the SMTP is not connectable, we should actually return an
-	// Ok(SmtpDetails { can_connect_smtp: false, ... }).
-	let mut smtp_transport = connect_to_smtp_host(to_email, mx_host, verif_method).await?;
+/// Maximum number of RCPT TO commands sent in one mail transaction. Most
+/// servers accept at least 100 recipients per transaction (RFC 5321 section
+/// 4.5.3.1.8), we stay well below that.
+const MAX_RCPT_PER_TRANSACTION: usize = 50;
 
-	let is_catch_all = smtp_is_catch_all(&mut smtp_transport, domain, mx_host, to_email)
-		.await
-		.unwrap_or(false);
-	let deliverability = if is_catch_all {
-		Deliverability {
-			has_full_inbox: false,
-			is_deliverable: true,
-			is_disabled: false,
+/// State shared by the SMTP functions during one verification.
+pub(crate) struct SmtpContext<'a> {
+	/// Pool of sessions to reuse, if any.
+	pub session_pool: Option<&'a SmtpSessionPool>,
+	/// Cache of the catch-all status of domains, if any.
+	pub catch_all_cache: Option<&'a dyn CatchAllCache>,
+	/// Debug information about the SMTP verification, filled as we go.
+	pub debug: &'a mut SmtpDebugVerifMethodSmtp,
+	/// The SMTP conversation so far, if the transcript is enabled.
+	pub transcript: Option<Vec<SmtpTranscriptEntry>>,
+	/// Recorder of the session currently in use.
+	pub recorder: Option<Arc<Mutex<TranscriptRecorder>>>,
+	/// TLS details of the session currently in use.
+	pub tls: SmtpTlsDetails,
+	/// Time spent in each step of the verification.
+	pub durations: SmtpDurations,
+	/// Channel to emit the progress of the verification on, if any.
+	pub events: Option<&'a CheckEventSender>,
+}
+
+impl SmtpContext<'_> {
+	/// Move the conversation recorded so far by the current session into
+	/// the transcript, or drop it if the transcript is disabled.
+	pub fn flush_transcript(&mut self) {
+		let entries = match &self.recorder {
+			Some(recorder) => recorder
+				.lock()
+				.expect("Transcript mutex is not poisoned. qed.")
+				.drain(),
+			None => return,
+		};
+		if let Some(transcript) = &mut self.transcript {
+			transcript.extend(entries);
+		}
+	}
+}
+
+/// An SMTP session with a MX host, which can be used to verify several
+/// emails, on one or several domains served by that host, over the same
+/// connection.
+pub(crate) struct SmtpSession {
+	smtp_transport: SmtpStream,
+	mx_host: String,
+	/// Records the conversation, see `SmtpContext::flush_transcript`.
+	recorder: Arc<Mutex<TranscriptRecorder>>,
+	/// Details about the TLS encryption of the connection.
+	tls: SmtpTlsDetails,
+	/// The local IP the connection is bound to, if set.
+	source_ip: Option<IpAddr>,
+	/// Whether each domain is catch-all, so that we probe it only once per
+	/// session.
+	catch_all: HashMap<String, bool>,
+	/// Number of RCPT TO sent in the current mail transaction.
+	rcpt_count: usize,
+	/// When the session was last used.
+	last_used: Instant,
+}
+
+impl SmtpSession {
+	/// Connect to the MX host, and start a mail transaction.
+	async fn connect(
+		to_email: &EmailAddress,
+		mx_host: &str,
+		verif_method: &VerifMethodSmtp,
+		ctx: &mut SmtpContext<'_>,
+	) -> Result<Self, SmtpError> {
+		let recorder = Arc::new(Mutex::new(TranscriptRecorder::new()));
+		ctx.recorder = Some(Arc::clone(&recorder));
+		let (smtp_transport, tls, source_ip) = connect_to_smtp_host(
+			to_email,
+			mx_host,
+			verif_method,
+			Arc::clone(&recorder),
+			&mut ctx.durations,
+		)
+		.await?;
+		emit(ctx.events, || CheckEvent::SmtpConnected {
+			mx_host: mx_host.to_string(),
+			port: verif_method.config.smtp_port,
+			reused_session: false,
+		});
+
+		Ok(Self {
+			smtp_transport,
+			mx_host: mx_host.to_string(),
+			recorder,
+			tls,
+			source_ip,
+			catch_all: HashMap::new(),
+			rcpt_count: 0,
+			last_used: Instant::now(),
+		})
+	}
+
+	/// Reconnect to the MX host, keeping the catch-all results.
+	async fn reconnect(
+		&mut self,
+		to_email: &EmailAddress,
+		verif_method: &VerifMethodSmtp,
+		durations: &mut SmtpDurations,
+	) -> Result<(), SmtpError> {
+		let _ = self.smtp_transport.quit().await;
+		let (smtp_transport, tls, source_ip) = connect_to_smtp_host(
+			to_email,
+			&self.mx_host,
+			verif_method,
+			Arc::clone(&self.recorder),
+			durations,
+		)
+		.await?;
+		self.smtp_transport = smtp_transport;
+		self.tls = tls;
+		self.source_ip = source_ip;
+		self.rcpt_count = 0;
+
+		Ok(())
+	}
+
+	/// Abort the current mail transaction with RSET, and start a new one.
+	async fn reset(
+		&mut self,
+		to_email: &EmailAddress,
+		verif_method: &VerifMethodSmtp,
+	) -> Result<(), SmtpError> {
+		tracing::debug!(
+			target: LOG_TARGET,
+			email=to_email.to_string(),
+			mx_host=self.mx_host,
+			rcpt_count=self.rcpt_count,
+			"Resetting SMTP transaction"
+		);
+
+		self.smtp_transport.get_mut().command(RsetCommand).await?;
+		let clean_host = self.mx_host.trim_end_matches('.').to_string();
+		send_mail_from(
+			&mut self.smtp_transport,
+			to_email,
+			&clean_host,
+			verif_method,
+		)
+		.await?;
+		self.rcpt_count = 0;
+
+		Ok(())
+	}
+
+	/// Send a RCPT TO for the email, resetting the mail transaction first if
+	/// it has too many recipients already.
+	async fn rcpt(
+		&mut self,
+		email: &EmailAddress,
+		verif_method: &VerifMethodSmtp,
+	) -> Result<Deliverability, SmtpError> {
+		if self.rcpt_count >= MAX_RCPT_PER_TRANSACTION {
+			self.reset(email, verif_method).await?;
+		}
+		self.rcpt_count += 1;
+		self.last_used = Instant::now();
+
+		let result =
+			check_email_deliverability(&mut self.smtp_transport, email, &self.mx_host).await;
+
+		// The server may have a lower limit than ours.
+		match &result {
+			Err(err) if parser::is_err_too_many_recipients(err) && self.rcpt_count > 1 => {
+				self.reset(email, verif_method).await?;
+				self.rcpt_count += 1;
+				check_email_deliverability(&mut self.smtp_transport, email, &self.mx_host).await
+			}
+			_ => result,
+		}
+	}
+
+	/// Check if the domain has a catch-all email setup. The result is cached
+	/// for the rest of the session, and in the catch-all cache if there's
+	/// one, which is consulted before probing the server.
+	async fn is_catch_all(
+		&mut self,
+		domain: &str,
+		to_email: &EmailAddress,
+		verif_method: &VerifMethodSmtp,
+		ctx: &mut SmtpContext<'_>,
+	) -> bool {
+		if let Some(is_catch_all) = self.catch_all.get(domain) {
+			return *is_catch_all;
+		}
+
+		if has_rule(domain, &self.mx_host, &Rule::SkipCatchAll) {
+			tracing::debug!(
+				target: LOG_TARGET,
+				email=to_email.to_string(),
+				domain=domain,
+				"Skipping catch-all check"
+			);
+			self.catch_all.insert(domain.to_string(), false);
+			return false;
+		}
+
+		if let Some(cache) = ctx.catch_all_cache {
+			if let Some(is_catch_all) = cache.get(domain).await {
+				tracing::debug!(
+					target: LOG_TARGET,
+					email=to_email.to_string(),
+					domain=domain,
+					is_catch_all=is_catch_all,
+					"Got catch-all status from cache"
+				);
+				ctx.debug.catch_all_cache_hit = true;
+				self.catch_all.insert(domain.to_string(), is_catch_all);
+				return is_catch_all;
+			}
+		}
+
+		let start = Instant::now();
+		let probe = match random_email(domain) {
+			Ok(random_email) => self
+				.rcpt(&random_email, verif_method)
+				.await
+				.map(|result| result.is_deliverable),
+			Err(err) => Err(err),
+		};
+		add_elapsed(&mut ctx.durations.catch_all_rcpt, start);
+		// Only cache the status if the probe went through, an error doesn't
+		// tell us anything about the domain: the next email on this session
+		// probes it again.
+		match probe {
+			Ok(is_catch_all) => {
+				if let Some(cache) = ctx.catch_all_cache {
+					cache.set(domain, is_catch_all).await;
+				}
+				self.catch_all.insert(domain.to_string(), is_catch_all);
+				is_catch_all
+			}
+			Err(_) => false,
+		}
+	}
+
+	/// Verify one email over this session.
+	async fn check(
+		&mut self,
+		to_email: &EmailAddress,
+		domain: &str,
+		verif_method: &VerifMethodSmtp,
+		ctx: &mut SmtpContext<'_>,
+	) -> Result<(bool, Deliverability), SmtpError> {
+		ctx.tls = self.tls.clone();
+		ctx.debug.source_ip = self.source_ip;
+		let is_catch_all = self.is_catch_all(domain, to_email, verif_method, ctx).await;
+		emit(ctx.events, || CheckEvent::CatchAllDetermined {
+			domain: domain.to_string(),
+			is_catch_all,
+		});
+		if is_catch_all {
+			return Ok((
+				true,
+				Deliverability {
+					is_deliverable: true,
+					..Deliverability::new(None)
+				},
+			));
 		}
-	} else {
-		let mut result = check_email_deliverability(&mut smtp_transport, to_email).await;
+
+		let start = Instant::now();
+		let mut result = self.rcpt(to_email, verif_method).await;
+		add_elapsed(&mut ctx.durations.rcpt, start);
 
 		// Some SMTP servers automatically close the connection after an error,
 		// so we should reconnect to perform a next command.
@@ -290,21 +728,161 @@ async fn create_smtp_future(
 					"Got `io: incomplete` error, reconnecting"
 				);
 
-				let _ = smtp_transport.quit().await;
-				smtp_transport = connect_to_smtp_host(to_email, mx_host, verif_method).await?;
-				result = check_email_deliverability(&mut smtp_transport, to_email).await;
+				self.reconnect(to_email, verif_method, &mut ctx.durations)
+					.await?;
+				ctx.tls = self.tls.clone();
+				ctx.debug.source_ip = self.source_ip;
+				let start = Instant::now();
+				result = self.rcpt(to_email, verif_method).await;
+				add_elapsed(&mut ctx.durations.rcpt, start);
 			}
 		}
 
-		result?
-	};
+		Ok((false, result?))
+	}
 
-	smtp_transport
-		.quit()
-		.await
-		.map_err(SmtpError::AsyncSmtpError)?;
+	/// Close the session.
+	async fn quit(mut self) -> Result<(), SmtpError> {
+		self.smtp_transport
+			.quit()
+			.await
+			.map(|_| ())
+			.map_err(SmtpError::AsyncSmtpError)
+	}
+}
+
+/// Maximum time a pooled session can stay idle before we stop reusing it.
+/// RFC 5321 section 4.5.3.2.7 lets servers close idle connections after 5
+/// minutes, many do it sooner.
+const MAX_SESSION_IDLE: Duration = Duration::from_secs(30);
+
+/// A pool of SMTP sessions, to verify several emails over the same
+/// connection instead of opening one connection per email. A session is
+/// only reused for the same MX host and the same SMTP configuration.
+#[derive(Default)]
+pub struct SmtpSessionPool {
+	sessions: Mutex<
+		Vec<(
+			VerifMethodSmtpConfig,
+			Option<CheckEmailInputProxy>,
+			SmtpSession,
+		)>,
+	>,
+}
+
+impl fmt::Debug for SmtpSessionPool {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("SmtpSessionPool").finish_non_exhaustive()
+	}
+}
+
+impl SmtpSessionPool {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Take an idle session to the MX host out of the pool, if any.
+	fn take(&self, mx_host: &str, verif_method: &VerifMethodSmtp) -> Option<SmtpSession> {
+		let mut sessions = self
+			.sessions
+			.lock()
+			.expect("Sessions mutex is not poisoned. qed.");
+		// Drop the sessions which have been idle for too long, the server
+		// has probably closed them.
+		sessions.retain(|(_, _, session)| session.last_used.elapsed() < MAX_SESSION_IDLE);
+
+		let index = sessions.iter().position(|(config, proxy, session)| {
+			session.mx_host == mx_host
+				&& *config == verif_method.config
+				&& *proxy == verif_method.proxy
+		})?;
+
+		Some(sessions.swap_remove(index).2)
+	}
+
+	/// Put a session back into the pool.
+	fn put(&self, session: SmtpSession, verif_method: &VerifMethodSmtp) {
+		self.sessions
+			.lock()
+			.expect("Sessions mutex is not poisoned. qed.")
+			.push((
+				verif_method.config.clone(),
+				verif_method.proxy.clone(),
+				session,
+			));
+	}
+
+	/// Verify one email, reusing a pooled session to the MX host if there's
+	/// one. The session is put back into the pool on success, and closed on
+	/// error.
+	async fn check(
+		&self,
+		to_email: &EmailAddress,
+		mx_host: &str,
+		domain: &str,
+		verif_method: &VerifMethodSmtp,
+		ctx: &mut SmtpContext<'_>,
+	) -> Result<(bool, Deliverability), SmtpError> {
+		let mut session = match self.take(mx_host, verif_method) {
+			Some(session) => {
+				ctx.recorder = Some(Arc::clone(&session.recorder));
+				emit(ctx.events, || CheckEvent::SmtpConnected {
+					mx_host: mx_host.to_string(),
+					port: verif_method.config.smtp_port,
+					reused_session: true,
+				});
+				session
+			}
+			None => SmtpSession::connect(to_email, mx_host, verif_method, ctx).await?,
+		};
+
+		let result = session.check(to_email, domain, verif_method, ctx).await;
+		if result.is_ok() {
+			self.put(session, verif_method);
+		} else {
+			let _ = session.quit().await;
+		}
+
+		result
+	}
+
+	/// Close all the sessions of the pool.
+	pub async fn close(&self) {
+		let sessions = std::mem::take(
+			&mut *self
+				.sessions
+				.lock()
+				.expect("Sessions mutex is not poisoned. qed."),
+		);
+		for (_, _, session) in sessions {
+			let _ = session.quit().await;
+		}
+	}
+}
+
+/// Creates an SMTP future for email verification. If a session pool is
+/// given, the connection is reused across verifications, otherwise a new
+/// connection is opened and closed for this email only.
+async fn create_smtp_future(
+	to_email: &EmailAddress,
+	mx_host: &str,
+	domain: &str,
+	verif_method: &VerifMethodSmtp,
+	ctx: &mut SmtpContext<'_>,
+) -> Result<(bool, Deliverability), SmtpError> {
+	if let Some(session_pool) = ctx.session_pool {
+		return session_pool
+			.check(to_email, mx_host, domain, verif_method, ctx)
+			.await;
+	}
+
+	// FIXME If the SMTP is not connectable, we should actually return an
+	// Ok(SmtpDetails { can_connect_smtp: false, ... }).
+	let mut session = SmtpSession::connect(to_email, mx_host, verif_method, ctx).await?;
+	let result = session.check(to_email, domain, verif_method, ctx).await?;
+	session.quit().await?;
 
-	Ok((is_catch_all, deliverability))
+	Ok(result)
 }
 
 /// Get all email details we can from one single `EmailAddress`, without
@@ -314,20 +892,25 @@ async fn check_smtp_without_retry(
 	mx_host: &str,
 	domain: &str,
 	verif_method: &VerifMethodSmtp,
+	ctx: &mut SmtpContext<'_>,
 ) -> Result<SmtpDetails, SmtpError> {
-	let fut = create_smtp_future(to_email, mx_host, domain, verif_method);
+	let fut = create_smtp_future(to_email, mx_host, domain, verif_method, ctx);
 
-	let (is_catch_all, deliverability) = match verif_method.config.smtp_timeout {
+	let result = match verif_method.config.smtp_timeout {
 		Some(smtp_timeout) => {
 			let timeout = tokio::time::timeout(smtp_timeout, fut);
 
 			match timeout.await {
-				Ok(result) => result?,
-				Err(_) => return Err(SmtpError::Timeout(smtp_timeout)),
+				Ok(result) => result,
+				Err(_) => Err(SmtpError::Timeout(smtp_timeout)),
 			}
 		}
-		None => fut.await?,
+		None => fut.await,
 	};
+	// Also flush on errors and timeouts, that's when the transcript is the
+	// most useful.
+	ctx.flush_transcript();
+	let (is_catch_all, deliverability) = result?;
 
 	Ok(SmtpDetails {
 		can_connect_smtp: true,
@@ -335,6 +918,8 @@ async fn check_smtp_without_retry(
 		is_catch_all,
 		is_deliverable: deliverability.is_deliverable,
 		is_disabled: deliverability.is_disabled,
+		rcpt_reply: deliverability.reply,
+		tls: std::mem::take(&mut ctx.tls),
 	})
 }
 
@@ -346,25 +931,26 @@ pub async fn check_smtp_with_retry(
 	mx_host: &str,
 	domain: &str,
 	verif_method: &VerifMethodSmtp,
+	ctx: &mut SmtpContext<'_>,
 	// Number of remaining retries.
 	count: usize,
 ) -> Result<SmtpDetails, SmtpError> {
 	tracing::debug!(
 		target: LOG_TARGET,
 		email=to_email.to_string(),
-		attempt=verif_method.config.retries - count + 1,
+		attempt=verif_method.config.max_attempts() - count + 1,
 		mx_host=mx_host,
 		port=verif_method.config.smtp_port,
 		using_proxy=verif_method.proxy.is_some(),
 		"Check SMTP"
 	);
 
-	let result = check_smtp_without_retry(to_email, mx_host, domain, verif_method).await;
+	let result = check_smtp_without_retry(to_email, mx_host, domain, verif_method, ctx).await;
 
 	tracing::debug!(
 		target: LOG_TARGET,
 		email=to_email.to_string(),
-		attempt=verif_method.config.retries - count + 1,
+		attempt=verif_method.config.max_attempts() - count + 1,
 		mx_host=mx_host,
 		port=verif_method.config.smtp_port,
 		result=?result,
@@ -378,19 +964,377 @@ pub async fn check_smtp_with_retry(
 		Err(SmtpError::HeadlessError(_)) => result,
 		Err(SmtpError::YahooError(_)) => result,
 		Err(SmtpError::GmailError(_)) => result,
-		// Only retry if the SMTP error was unknown.
-		Err(err) if err.get_description().is_none() => {
+		// Only retry on the errors of the retry policy, by default unknown
+		// errors or errors known to be transient, e.g. greylisting.
+		Err(err) if verif_method.config.retry_policy.is_retryable(err) => {
 			if count <= 1 {
 				result
 			} else {
+				let retry = verif_method.config.max_attempts() - count + 1;
+				let backoff = verif_method.config.retry_policy.backoff(retry);
 				tracing::debug!(
 					target: LOG_TARGET,
 					email=to_email.to_string(),
+					description=?err.get_description(),
+					backoff=?backoff,
 					"Potential greylisting detected, retrying"
 				);
-				check_smtp_with_retry(to_email, mx_host, domain, verif_method, count - 1).await
+				if let Some(backoff) = backoff {
+					tokio::time::sleep(backoff).await;
+				}
+				check_smtp_with_retry(to_email, mx_host, domain, verif_method, ctx, count - 1).await
 			}
 		}
 		_ => result,
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::smtp::InMemoryCatchAllCache;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::sync::Arc;
+	use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+	use tokio::net::TcpListener;
+
+	/// Start a fake SMTP server, which only knows "known@example.com", and
+	/// count the connections and RCPT TO it receives. It doesn't support
+	/// STARTTLS.
+	async fn fake_smtp_server() -> (u16, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+		fake_smtp_server_with(|_, cmd| {
+			if cmd.contains("known@example.com") && !cmd.contains("unknown") {
+				b"250 OK\r\n"
+			} else {
+				b"550 5.1.1 User unknown\r\n"
+			}
+		})
+		.await
+	}
+
+	/// Same as `fake_smtp_server`, replying to the n-th RCPT TO (starting at
+	/// 0) with `rcpt_reply(n, command)`.
+	async fn fake_smtp_server_with(
+		rcpt_reply: fn(usize, &str) -> &'static [u8],
+	) -> (u16, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+		fake_smtp_server_replying(b"250 localhost\r\n", rcpt_reply).await
+	}
+
+	/// Same as `fake_smtp_server_with`, replying to EHLO with `ehlo_reply`.
+	/// It answers STARTTLS with 454, even when advertising it.
+	async fn fake_smtp_server_replying(
+		ehlo_reply: &'static [u8],
+		rcpt_reply: fn(usize, &str) -> &'static [u8],
+	) -> (u16, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let connections = Arc::new(AtomicUsize::new(0));
+		let rcpts = Arc::new(AtomicUsize::new(0));
+
+		let (c, r) = (Arc::clone(&connections), Arc::clone(&rcpts));
+		tokio::spawn(async move {
+			loop {
+				let (stream, _) = listener.accept().await.unwrap();
+				c.fetch_add(1, Ordering::SeqCst);
+				let r = Arc::clone(&r);
+				tokio::spawn(async move {
+					let mut stream = BufStream::new(stream);
+					stream.write_all(b"220 localhost\r\n").await.unwrap();
+					stream.flush().await.unwrap();
+					let mut line = String::new();
+					while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+						let cmd = line.to_lowercase();
+						let reply: &[u8] = if cmd.starts_with("rcpt") {
+							rcpt_reply(r.fetch_add(1, Ordering::SeqCst), &cmd)
+						} else if cmd.starts_with("quit") {
+							b"221 Bye\r\n"
+						} else if cmd.starts_with("ehlo") {
+							ehlo_reply
+						} else if cmd.starts_with("starttls") {
+							b"454 4.7.0 TLS not available\r\n"
+						} else {
+							b"250 OK\r\n"
+						};
+						stream.write_all(reply).await.unwrap();
+						stream.flush().await.unwrap();
+						if cmd.starts_with("quit") {
+							break;
+						}
+						line.clear();
+					}
+				});
+			}
+		});
+
+		(port, connections, rcpts)
+	}
+
+	fn verif_method(port: u16) -> VerifMethodSmtp {
+		VerifMethodSmtp::new(
+			VerifMethodSmtpConfig {
+				smtp_port: port,
+				..Default::default()
+			},
+			None,
+		)
+	}
+
+	fn smtp_debug() -> SmtpDebugVerifMethodSmtp {
+		SmtpDebugVerifMethodSmtp {
+			host: "127.0.0.1.".into(),
+			verif_method: VerifMethodSmtpConfig::default(),
+			catch_all_cache_hit: false,
+			port: 25,
+			source_ip: None,
+			proxy: None,
+		}
+	}
+
+	fn ctx<'a>(
+		pool: Option<&'a SmtpSessionPool>,
+		cache: Option<&'a dyn CatchAllCache>,
+		debug: &'a mut SmtpDebugVerifMethodSmtp,
+	) -> SmtpContext<'a> {
+		SmtpContext {
+			session_pool: pool,
+			catch_all_cache: cache,
+			debug,
+			transcript: None,
+			recorder: None,
+			tls: SmtpTlsDetails::default(),
+			durations: SmtpDurations::default(),
+			events: None,
+		}
+	}
+
+	#[tokio::test]
+	async fn should_reuse_session() {
+		let (port, connections, rcpts) = fake_smtp_server().await;
+		let verif_method = verif_method(port);
+		let pool = SmtpSessionPool::new();
+		let mut debug = smtp_debug();
+		let (events, mut events_rx) = tokio::sync::mpsc::unbounded_channel();
+		let mut ctx = ctx(Some(&pool), None, &mut debug);
+		ctx.transcript = Some(vec![]);
+		ctx.events = Some(&events);
+
+		let known = EmailAddress::from_str("known@example.com").unwrap();
+		let (is_catch_all, deliverability) =
+			create_smtp_future(&known, "127.0.0.1.", "example.com", &verif_method, &mut ctx)
+				.await
+				.unwrap();
+		assert!(!is_catch_all);
+		assert!(deliverability.is_deliverable);
+
+		let unknown = EmailAddress::from_str("unknown@example.com").unwrap();
+		let (_, deliverability) = create_smtp_future(
+			&unknown,
+			"127.0.0.1.",
+			"example.com",
+			&verif_method,
+			&mut ctx,
+		)
+		.await
+		.unwrap();
+		assert!(!deliverability.is_deliverable);
+
+		ctx.flush_transcript();
+		let transcript = ctx.transcript.take().unwrap();
+		assert_eq!(transcript[0].code, Some(220));
+		assert_eq!(
+			transcript
+				.iter()
+				.filter(|entry| matches!(&entry.command, Some(c) if c.starts_with("RCPT")))
+				.count(),
+			3
+		);
+
+		pool.close().await;
+
+		// The session was reused, so we only connected once, but both
+		// emails spent time in RCPT TO.
+		assert!(ctx.durations.connect.is_some());
+		assert!(ctx.durations.greeting.is_some());
+		assert!(ctx.durations.catch_all_rcpt.is_some());
+		assert!(ctx.durations.rcpt.is_some());
+		assert!(ctx.durations.headless.is_none());
+
+		let reused = iter::from_fn(|| events_rx.try_recv().ok())
+			.filter_map(|event| match event {
+				CheckEvent::SmtpConnected { reused_session, .. } => Some(reused_session),
+				_ => None,
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(reused, vec![false, true]);
+
+		// One connection, one catch-all probe and two RCPT TO.
+		assert_eq!(connections.load(Ordering::SeqCst), 1);
+		assert_eq!(rcpts.load(Ordering::SeqCst), 3);
+	}
+
+	#[tokio::test]
+	async fn should_probe_catch_all_again_after_error() {
+		// The first catch-all probe is greylisted.
+		let (port, _, rcpts) = fake_smtp_server_with(|n, cmd| match n {
+			0 => b"451 4.7.1 Greylisted, try again later\r\n",
+			_ if cmd.contains("known@example.com") && !cmd.contains("unknown") => b"250 OK\r\n",
+			_ => b"550 5.1.1 User unknown\r\n",
+		})
+		.await;
+		let verif_method = verif_method(port);
+		let pool = SmtpSessionPool::new();
+		let mut debug = smtp_debug();
+		let mut ctx = ctx(Some(&pool), None, &mut debug);
+
+		for email in ["known@example.com", "unknown@example.com"] {
+			let email = EmailAddress::from_str(email).unwrap();
+			let (is_catch_all, _) =
+				create_smtp_future(&email, "127.0.0.1.", "example.com", &verif_method, &mut ctx)
+					.await
+					.unwrap();
+			assert!(!is_catch_all);
+		}
+		pool.close().await;
+
+		// The failed probe isn't remembered, so the second email probes again.
+		assert_eq!(rcpts.load(Ordering::SeqCst), 4);
+	}
+
+	#[tokio::test]
+	async fn should_use_catch_all_cache() {
+		let (port, _, rcpts) = fake_smtp_server().await;
+		let verif_method = verif_method(port);
+		let cache = InMemoryCatchAllCache::new(Duration::from_secs(60));
+		let known = EmailAddress::from_str("known@example.com").unwrap();
+
+		// The first verification probes the server and fills the cache.
+		let mut debug = smtp_debug();
+		create_smtp_future(
+			&known,
+			"127.0.0.1.",
+			"example.com",
+			&verif_method,
+			&mut ctx(None, Some(&cache), &mut debug),
+		)
+		.await
+		.unwrap();
+		assert!(!debug.catch_all_cache_hit);
+		assert_eq!(rcpts.load(Ordering::SeqCst), 2);
+
+		// The second one skips the probe.
+		let mut debug = smtp_debug();
+		create_smtp_future(
+			&known,
+			"127.0.0.1.",
+			"example.com",
+			&verif_method,
+			&mut ctx(None, Some(&cache), &mut debug),
+		)
+		.await
+		.unwrap();
+		assert!(debug.catch_all_cache_hit);
+		assert_eq!(rcpts.load(Ordering::SeqCst), 3);
+	}
+
+	#[tokio::test]
+	async fn should_require_starttls() {
+		// The fake server doesn't advertise STARTTLS.
+		let (port, _, rcpts) = fake_smtp_server().await;
+		let known = EmailAddress::from_str("known@example.com").unwrap();
+		let mut debug = smtp_debug();
+		let mut ctx = ctx(None, None, &mut debug);
+		ctx.transcript = Some(vec![]);
+
+		let mut verif_method = verif_method(port);
+		verif_method.config.starttls = StarttlsMode::Opportunistic;
+		let details =
+			check_smtp_without_retry(&known, "127.0.0.1.", "example.com", &verif_method, &mut ctx)
+				.await
+				.unwrap();
+		assert!(details.is_deliverable);
+		assert!(!details.tls.negotiated);
+
+		verif_method.config.starttls = StarttlsMode::Required;
+		let result =
+			check_smtp_without_retry(&known, "127.0.0.1.", "example.com", &verif_method, &mut ctx)
+				.await;
+		assert!(matches!(result, Err(SmtpError::StarttlsNotSupported)));
+		assert_eq!(rcpts.load(Ordering::SeqCst), 2);
+
+		// STARTTLS was never sent, as the server doesn't advertise it.
+		ctx.flush_transcript();
+		let transcript = ctx.transcript.take().unwrap();
+		assert!(transcript
+			.iter()
+			.any(|entry| matches!(&entry.command, Some(c) if c.starts_with("RCPT"))));
+		assert!(transcript
+			.iter()
+			.all(|entry| entry.command.as_deref() != Some("STARTTLS")));
+	}
+
+	#[tokio::test]
+	async fn should_continue_in_plaintext_when_starttls_refused() {
+		// The fake server advertises STARTTLS, but replies 454 to it.
+		let (port, _, rcpts) =
+			fake_smtp_server_replying(b"250-localhost\r\n250 STARTTLS\r\n", |_, cmd| {
+				if cmd.contains("known@example.com") && !cmd.contains("unknown") {
+					b"250 OK\r\n"
+				} else {
+					b"550 5.1.1 User unknown\r\n"
+				}
+			})
+			.await;
+		let known = EmailAddress::from_str("known@example.com").unwrap();
+		let mut debug = smtp_debug();
+		let mut ctx = ctx(None, None, &mut debug);
+		ctx.transcript = Some(vec![]);
+
+		let mut verif_method = verif_method(port);
+		verif_method.config.starttls = StarttlsMode::Opportunistic;
+		let details =
+			check_smtp_without_retry(&known, "127.0.0.1.", "example.com", &verif_method, &mut ctx)
+				.await
+				.unwrap();
+		assert!(details.is_deliverable);
+		assert!(!details.tls.negotiated);
+
+		ctx.flush_transcript();
+		let transcript = ctx.transcript.take().unwrap();
+		let starttls = transcript
+			.iter()
+			.position(|entry| entry.command.as_deref() == Some("STARTTLS"))
+			.unwrap();
+		assert_eq!(transcript[starttls].code, Some(454));
+		assert!(transcript[starttls..]
+			.iter()
+			.any(|entry| matches!(&entry.command, Some(c) if c.starts_with("RCPT"))));
+
+		// With STARTTLS required, the transient reply is kept as is.
+		verif_method.config.starttls = StarttlsMode::Required;
+		let result =
+			check_smtp_without_retry(&known, "127.0.0.1.", "example.com", &verif_method, &mut ctx)
+				.await;
+		assert!(matches!(
+			result,
+			Err(SmtpError::AsyncSmtpError(AsyncSmtpError::Transient(_)))
+		));
+		assert_eq!(rcpts.load(Ordering::SeqCst), 2);
+	}
+
+	#[tokio::test]
+	async fn should_fail_implicit_tls_on_plaintext_server() {
+		let (port, _, rcpts) = fake_smtp_server().await;
+		let known = EmailAddress::from_str("known@example.com").unwrap();
+		let mut debug = smtp_debug();
+		let mut ctx = ctx(None, None, &mut debug);
+
+		let mut verif_method = verif_method(port);
+		verif_method.config.implicit_tls = true;
+		let result =
+			check_smtp_without_retry(&known, "127.0.0.1.", "example.com", &verif_method, &mut ctx)
+				.await;
+		// The plaintext greeting is not a TLS handshake.
+		assert!(result.unwrap_err().is_connection_error());
+		assert_eq!(rcpts.load(Ordering::SeqCst), 0);
+	}
+}
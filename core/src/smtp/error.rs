@@ -16,12 +16,14 @@
 
 use super::gmail::GmailError;
 use super::headless::HeadlessError;
+use super::http_connect;
 use super::outlook::microsoft365::Microsoft365Error;
 use super::parser;
 use super::yahoo::YahooError;
 use crate::util::ser_with_display::ser_with_display;
 use async_smtp::error::Error as AsyncSmtpError;
-use serde::Serialize;
+use fast_socks5::{ReplyError, SocksError};
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
 use thiserror::Error;
 
@@ -52,10 +54,20 @@ pub enum SmtpError {
 	/// Timeout error.
 	#[error("Timeout error: {0:?}")]
 	Timeout(Duration),
+	/// The server doesn't advertise STARTTLS, but we required it.
+	#[error("STARTTLS is required, but not supported by the server")]
+	StarttlsNotSupported,
 	/// SOCKS5 proxy error.
 	#[error("SOCKS5 error: {0}")]
 	#[serde(serialize_with = "ser_with_display")]
 	Socks5(fast_socks5::SocksError),
+	/// HTTP CONNECT proxy error.
+	#[error("HTTP CONNECT proxy error: {0}")]
+	HttpConnect(String),
+	/// The circuit breaker of the MX host is open, because of too many
+	/// recent failures on it.
+	#[error("Circuit breaker open for MX host {0}")]
+	CircuitOpen(String),
 	/// Anyhow error.
 	/// This is a catch-all error type for any error that can't be categorized
 	/// into the above types.
@@ -117,6 +129,11 @@ impl SmtpError {
 	/// SmtpErrorDesc. This only parses the following known errors:
 	/// - IP blacklisted
 	/// - IP needs reverse DNS
+	/// - too many connections
+	/// - greylisting
+	/// - rate limiting
+	/// - TLS required
+	/// - sender policy (SPF, DMARC...) rejection
 	pub fn get_description(&self) -> Option<SmtpErrorDesc> {
 		match self {
 			SmtpError::AsyncSmtpError(_) => {
@@ -124,6 +141,16 @@ impl SmtpError {
 					Some(SmtpErrorDesc::IpBlacklisted)
 				} else if parser::is_err_needs_rdns(self) {
 					Some(SmtpErrorDesc::NeedsRDNS)
+				} else if parser::is_err_too_many_connections(self) {
+					Some(SmtpErrorDesc::TooManyConnections)
+				} else if parser::is_err_greylisted(self) {
+					Some(SmtpErrorDesc::Greylisted)
+				} else if parser::is_err_rate_limited(self) {
+					Some(SmtpErrorDesc::RateLimited)
+				} else if parser::is_err_tls_required(self) {
+					Some(SmtpErrorDesc::TlsRequired)
+				} else if parser::is_err_policy_rejected(self) {
+					Some(SmtpErrorDesc::PolicyRejected)
 				} else {
 					None
 				}
@@ -131,14 +158,100 @@ impl SmtpError {
 			_ => None,
 		}
 	}
+
+	/// Whether retrying the verification later might give a different
+	/// result. Unknown errors are considered retryable, unless permanent.
+	pub fn is_retryable(&self) -> bool {
+		self.get_description()
+			.map_or(!self.is_permanent(), |desc| desc.is_retryable())
+	}
+
+	/// Whether the error will happen again if we retry with the same input,
+	/// e.g. a permanent SMTP reply, or a proxy refusing our credentials.
+	pub fn is_permanent(&self) -> bool {
+		match self {
+			SmtpError::AsyncSmtpError(AsyncSmtpError::Permanent(_))
+			| SmtpError::StarttlsNotSupported
+			| SmtpError::CircuitOpen(_) => true,
+			SmtpError::HttpConnect(message) => http_connect::is_auth_refused(message),
+			_ => false,
+		}
+	}
+
+	/// Whether the error is caused by the proxy itself, e.g. we couldn't
+	/// connect or authenticate to it, and not by the host behind it, e.g. a
+	/// proxy replying that the host refused the connection.
+	pub fn is_proxy_fault(&self) -> bool {
+		match self {
+			SmtpError::Socks5(SocksError::ReplyError(
+				ReplyError::ConnectionRefused
+				| ReplyError::HostUnreachable
+				| ReplyError::NetworkUnreachable
+				| ReplyError::TtlExpired
+				| ReplyError::ConnectionTimeout,
+			)) => false,
+			SmtpError::Socks5(_) => true,
+			SmtpError::HttpConnect(message) => !http_connect::is_target_unreachable(message),
+			_ => self.get_description() == Some(SmtpErrorDesc::IpBlacklisted),
+		}
+	}
+
+	/// Whether the error happened at the connection level, i.e. we couldn't
+	/// establish or keep a working SMTP session with the host. On such errors,
+	/// it makes sense to try the next MX host.
+	pub fn is_connection_error(&self) -> bool {
+		matches!(
+			self,
+			SmtpError::IOError(_)
+				| SmtpError::Timeout(_)
+				| SmtpError::Socks5(_)
+				| SmtpError::HttpConnect(_)
+				| SmtpError::CircuitOpen(_)
+				| SmtpError::AsyncSmtpError(AsyncSmtpError::Io(_))
+		)
+	}
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
 /// SmtpErrorDesc describes a description of which category the error belongs
-/// to.
+/// to. It's serialized with the variant names, and also deserialized from
+/// snake_case, like the other config enums.
 pub enum SmtpErrorDesc {
 	/// The IP is blacklisted.
+	#[serde(alias = "ip_blacklisted")]
 	IpBlacklisted,
 	/// The IP needs a reverse DNS entry.
+	#[serde(alias = "needs_rdns")]
 	NeedsRDNS,
+	/// The server refused the connection because we opened too many.
+	#[serde(alias = "too_many_connections")]
+	TooManyConnections,
+	/// The server temporarily deferred us, and expects us to come back later.
+	#[serde(alias = "greylisted")]
+	Greylisted,
+	/// The server rate limits us.
+	#[serde(alias = "rate_limited")]
+	RateLimited,
+	/// The server requires TLS.
+	#[serde(alias = "tls_required")]
+	TlsRequired,
+	/// The server refused our MAIL FROM because of its sender policy, e.g.
+	/// SPF or DMARC.
+	#[serde(alias = "policy_rejected")]
+	PolicyRejected,
+}
+
+impl SmtpErrorDesc {
+	/// Whether retrying later, or from another connection, might succeed.
+	pub fn is_retryable(&self) -> bool {
+		match self {
+			SmtpErrorDesc::TooManyConnections
+			| SmtpErrorDesc::Greylisted
+			| SmtpErrorDesc::RateLimited => true,
+			SmtpErrorDesc::IpBlacklisted
+			| SmtpErrorDesc::NeedsRDNS
+			| SmtpErrorDesc::TlsRequired
+			| SmtpErrorDesc::PolicyRejected => false,
+		}
+	}
 }
@@ -15,12 +15,11 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use super::SmtpDetails;
+use crate::util::{
+	http_client::create_client, input_output::CheckEmailInput, ser_with_display::ser_with_display,
+};
 use crate::EmailAddress;
 use crate::LOG_TARGET;
-use crate::{
-	smtp::http_api::create_client,
-	util::{input_output::CheckEmailInput, ser_with_display::ser_with_display},
-};
 use reqwest::Error as ReqwestError;
 use serde::Serialize;
 use thiserror::Error;
@@ -0,0 +1,262 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Tunnel the SMTP connection through an HTTP proxy, with the CONNECT
+//! method (RFC 9110 section 9.3.6).
+
+use super::SmtpError;
+use crate::CheckEmailInputProxy;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Maximum length of the proxy's response head we accept.
+const MAX_RESPONSE_HEAD_LEN: usize = 8192;
+/// Start of the error message when the proxy refused our credentials.
+const AUTH_REFUSED: &str = "Proxy refused the credentials";
+
+/// Start of the error message when the proxy couldn't reach the target host.
+const TARGET_UNREACHABLE: &str = "Proxy cannot reach the host";
+
+/// Whether the message of an `SmtpError::HttpConnect` means that the proxy
+/// refused our credentials.
+pub(crate) fn is_auth_refused(message: &str) -> bool {
+	message.starts_with(AUTH_REFUSED)
+}
+
+/// Whether the message of an `SmtpError::HttpConnect` means that the proxy
+/// works, but the target host didn't answer, i.e. a 502 or 504 reply.
+pub(crate) fn is_target_unreachable(message: &str) -> bool {
+	message.starts_with(TARGET_UNREACHABLE)
+}
+
+/// Build the CONNECT request to the given host and port, with basic auth if
+/// the proxy has credentials.
+fn connect_request(proxy: &CheckEmailInputProxy, host: &str, port: u16) -> String {
+	let authority = if host.contains(':') {
+		// IPv6 addresses must be enclosed in brackets.
+		format!("[{}]:{}", host, port)
+	} else {
+		format!("{}:{}", host, port)
+	};
+
+	let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+	if let (Some(username), Some(password)) = (&proxy.username, &proxy.password) {
+		request.push_str(&format!(
+			"Proxy-Authorization: Basic {}\r\n",
+			STANDARD.encode(format!("{}:{}", username, password))
+		));
+	}
+	request.push_str("\r\n");
+
+	request
+}
+
+/// Read the response head of the proxy. We read it byte by byte, so that we
+/// don't consume the first bytes sent by the SMTP server through the tunnel.
+async fn read_response_head<S: AsyncRead + Unpin>(stream: &mut S) -> Result<String, SmtpError> {
+	let mut head = Vec::new();
+	while !head.ends_with(b"\r\n\r\n") {
+		if head.len() >= MAX_RESPONSE_HEAD_LEN {
+			return Err(SmtpError::HttpConnect(
+				"Response head of the proxy is too long".into(),
+			));
+		}
+		head.push(stream.read_u8().await.map_err(|err| {
+			SmtpError::HttpConnect(format!("Cannot read the response of the proxy: {}", err))
+		})?);
+	}
+
+	Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// Check that the proxy established the tunnel, i.e. replied with a 2xx
+/// status code.
+fn check_response_head(head: &str) -> Result<(), SmtpError> {
+	let status_line = head.lines().next().unwrap_or_default();
+	let mut parts = status_line.split(' ');
+	match (parts.next(), parts.next()) {
+		(Some(version), Some(code)) if version.starts_with("HTTP/1.") && code.len() == 3 => {
+			if code.starts_with('2') {
+				Ok(())
+			} else if code == "407" {
+				Err(SmtpError::HttpConnect(format!(
+					"{}: {}",
+					AUTH_REFUSED, status_line
+				)))
+			} else if code == "502" || code == "504" {
+				Err(SmtpError::HttpConnect(format!(
+					"{}: {}",
+					TARGET_UNREACHABLE, status_line
+				)))
+			} else {
+				Err(SmtpError::HttpConnect(format!(
+					"Proxy refused the tunnel: {}",
+					status_line
+				)))
+			}
+		}
+		_ => Err(SmtpError::HttpConnect(format!(
+			"Invalid response from proxy: {}",
+			status_line
+		))),
+	}
+}
+
+async fn open_tunnel(
+	proxy: &CheckEmailInputProxy,
+	host: &str,
+	port: u16,
+) -> Result<TcpStream, SmtpError> {
+	let mut stream = TcpStream::connect((proxy.host.as_str(), proxy.port))
+		.await
+		.map_err(|err| SmtpError::HttpConnect(format!("Cannot connect to proxy: {}", err)))?;
+	stream
+		.write_all(connect_request(proxy, host, port).as_bytes())
+		.await
+		.map_err(|err| SmtpError::HttpConnect(format!("Cannot send CONNECT request: {}", err)))?;
+	let head = read_response_head(&mut stream).await?;
+	check_response_head(&head)?;
+
+	Ok(stream)
+}
+
+/// Open a tunnel to the given host and port through the HTTP proxy. The
+/// proxy's `timeout_ms` applies to the whole tunnel setup. All the errors
+/// are `SmtpError::HttpConnect`, as they're caused by the proxy.
+pub(crate) async fn connect(
+	proxy: &CheckEmailInputProxy,
+	host: &str,
+	port: u16,
+) -> Result<TcpStream, SmtpError> {
+	match proxy.timeout_ms {
+		Some(timeout_ms) => {
+			let timeout = Duration::from_millis(timeout_ms);
+			tokio::time::timeout(timeout, open_tunnel(proxy, host, port))
+				.await
+				.map_err(|_| {
+					SmtpError::HttpConnect(format!("Tunnel setup timed out after {:?}", timeout))
+				})?
+		}
+		None => open_tunnel(proxy, host, port).await,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::ProxyType;
+	use tokio::io::AsyncBufReadExt;
+	use tokio::net::TcpListener;
+
+	/// Start a fake HTTP proxy, which replies to the CONNECT request with the
+	/// given response, and returns the request it received.
+	async fn fake_proxy(
+		response: &'static str,
+	) -> (CheckEmailInputProxy, tokio::task::JoinHandle<String>) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let proxy = CheckEmailInputProxy {
+			proxy_type: ProxyType::HttpConnect,
+			host: "127.0.0.1".into(),
+			port: listener.local_addr().unwrap().port(),
+			username: Some("user".into()),
+			password: Some("pass".into()),
+			timeout_ms: Some(5000),
+		};
+		let handle = tokio::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+			let mut stream = tokio::io::BufStream::new(stream);
+			let mut request = String::new();
+			while !request.ends_with("\r\n\r\n") {
+				stream.read_line(&mut request).await.unwrap();
+			}
+			stream.write_all(response.as_bytes()).await.unwrap();
+			stream.flush().await.unwrap();
+			request
+		});
+
+		(proxy, handle)
+	}
+
+	#[tokio::test]
+	async fn should_open_tunnel() {
+		let (proxy, handle) =
+			fake_proxy("HTTP/1.1 200 Connection established\r\n\r\n220 mx.example.com ESMTP\r\n")
+				.await;
+
+		let mut stream = connect(&proxy, "mx.example.com", 25).await.unwrap();
+		let request = handle.await.unwrap();
+		assert_eq!(
+			request,
+			"CONNECT mx.example.com:25 HTTP/1.1\r\nHost: mx.example.com:25\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n"
+		);
+
+		// The SMTP greeting is left untouched in the stream.
+		let mut greeting = String::new();
+		stream.read_to_string(&mut greeting).await.unwrap();
+		assert_eq!(greeting, "220 mx.example.com ESMTP\r\n");
+	}
+
+	#[tokio::test]
+	async fn should_fail_when_proxy_refuses() {
+		let (proxy, _) = fake_proxy("HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").await;
+
+		match connect(&proxy, "mx.example.com", 25).await {
+			Err(err @ SmtpError::HttpConnect(_)) => {
+				assert!(err.is_connection_error());
+				assert!(err.is_proxy_fault());
+			}
+			res => panic!("Expected an HTTP CONNECT error, got {:?}", res),
+		}
+	}
+
+	#[tokio::test]
+	async fn should_blame_host_on_bad_gateway() {
+		let (proxy, _) = fake_proxy("HTTP/1.1 502 Bad Gateway\r\n\r\n").await;
+
+		match connect(&proxy, "mx.example.com", 25).await {
+			Err(err @ SmtpError::HttpConnect(_)) => assert!(!err.is_proxy_fault()),
+			res => panic!("Expected an HTTP CONNECT error, got {:?}", res),
+		}
+	}
+
+	#[tokio::test]
+	async fn should_fail_when_proxy_hangs_up_or_times_out() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let proxy = CheckEmailInputProxy {
+			proxy_type: ProxyType::HttpConnect,
+			host: "127.0.0.1".into(),
+			port: listener.local_addr().unwrap().port(),
+			username: None,
+			password: None,
+			timeout_ms: Some(200),
+		};
+		tokio::spawn(async move {
+			// Hang up on the first connection, and never reply on the second.
+			drop(listener.accept().await.unwrap());
+			let _stream = listener.accept().await.unwrap();
+			tokio::time::sleep(Duration::from_secs(5)).await;
+		});
+
+		for _ in 0..2 {
+			match connect(&proxy, "mx.example.com", 25).await {
+				Err(err @ SmtpError::HttpConnect(_)) => assert!(err.is_proxy_fault()),
+				res => panic!("Expected an HTTP CONNECT error, got {:?}", res),
+			}
+		}
+	}
+}
@@ -14,22 +14,46 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+mod catch_all_cache;
+mod circuit_breaker;
 mod connect;
 mod error;
 mod gmail;
 mod headless;
-mod http_api;
+mod http_connect;
 mod outlook;
 mod parser;
+mod patterns;
+mod proxy_pool;
+mod retry;
+mod source_ip;
+mod tls;
+mod transcript;
 pub mod verif_method;
 mod yahoo;
 
+use crate::events::{emit, CheckEvent};
 use crate::util::input_output::CheckEmailInput;
-use crate::EmailAddress;
-use connect::check_smtp_with_retry;
+use crate::{EmailAddress, LOG_TARGET};
+pub use catch_all_cache::{CatchAllCache, InMemoryCatchAllCache};
+pub use circuit_breaker::CircuitBreakerConfig;
+pub use connect::SmtpSessionPool;
+use connect::{check_smtp_with_retry, SmtpContext};
 use hickory_proto::rr::Name;
+pub use parser::{EnhancedStatusCode, SmtpReply};
+pub use patterns::{
+	load_smtp_patterns, SmtpPatternCategory, SmtpPatternsError, SMTP_PATTERNS_VERSION,
+};
+pub use proxy_pool::{ProxyPool, ProxyPoolMember, ProxySelection};
+pub use retry::RetryPolicy;
 use serde::{Deserialize, Serialize};
+pub use source_ip::{IpVersionPreference, SourceIpSelection};
 use std::default::Default;
+use std::iter;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+pub use tls::{SmtpTlsDetails, StarttlsMode};
+pub use transcript::{SmtpTranscriptConfig, SmtpTranscriptEntry};
 use verif_method::{
 	EmailProvider, EverythingElseVerifMethod, GmailVerifMethod, HotmailB2BVerifMethod,
 	HotmailB2CVerifMethod, VerifMethodSmtp, VerifMethodSmtpConfig, YahooVerifMethod,
@@ -44,13 +68,29 @@ pub struct SmtpDebugVerifMethodSmtp {
 	pub host: String,
 	/// The proxy used for the SMTP connection.
 	pub verif_method: VerifMethodSmtpConfig,
+	/// Whether the catch-all status of the domain came from the catch-all
+	/// cache, instead of probing the SMTP server.
+	#[serde(default)]
+	pub catch_all_cache_hit: bool,
+	/// The SMTP port of the last connection attempt, i.e. the one which
+	/// worked if any, see `VerifMethodSmtpConfig.fallback_smtp_ports`.
+	#[serde(default)]
+	pub port: u16,
+	/// The local IP the last SMTP connection was bound to, see
+	/// `VerifMethodSmtpConfig.source_ips`. None if the OS picked it.
+	#[serde(default)]
+	pub source_ip: Option<IpAddr>,
+	/// The ID of the proxy used for the SMTP connection, if any, see
+	/// `VerifMethodSmtpConfig.proxy` and `VerifMethodSmtpConfig.proxy_pool`.
+	#[serde(default)]
+	pub proxy: Option<String>,
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
 #[serde(tag = "type")]
 pub enum SmtpDebugVerifMethod {
 	/// Email verification was done via SMTP.
-	Smtp(SmtpDebugVerifMethodSmtp),
+	Smtp(Box<SmtpDebugVerifMethodSmtp>),
 	/// Email verification was done via an HTTP API.
 	Api,
 	/// Email verification was done via a headless browser.
@@ -73,6 +113,51 @@ pub struct SmtpDetails {
 	pub is_deliverable: bool,
 	/// Is the email blocked or disabled by the provider?
 	pub is_disabled: bool,
+	/// The reply of the server to RCPT TO for this email, with its parsed
+	/// status codes. None if the reply wasn't needed, e.g. on catch-all
+	/// domains.
+	#[serde(default)]
+	pub rcpt_reply: Option<SmtpReply>,
+	/// Details about the TLS encryption of the SMTP connection, see
+	/// `VerifMethodSmtpConfig.starttls` and `VerifMethodSmtpConfig.implicit_tls`.
+	#[serde(default)]
+	pub tls: SmtpTlsDetails,
+}
+
+/// Time spent in each step of the SMTP stage, summed over all the attempts,
+/// MX hosts and ports. A step is None if it didn't happen.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct SmtpDurations {
+	/// Opening the TCP connection to the MX host, or to the proxy and
+	/// through it.
+	pub connect: Option<Duration>,
+	/// Everything until the server is ready for RCPT TO: the greeting, EHLO,
+	/// TLS and MAIL FROM.
+	pub greeting: Option<Duration>,
+	/// The RCPT TO of a random email, to check if the domain is catch-all.
+	pub catch_all_rcpt: Option<Duration>,
+	/// The RCPT TO of the email.
+	pub rcpt: Option<Duration>,
+	/// The verification via headless browser.
+	pub headless: Option<Duration>,
+}
+
+impl SmtpDurations {
+	/// Add the durations of another attempt.
+	fn add(&mut self, other: &SmtpDurations) {
+		fn sum(a: Option<Duration>, b: Option<Duration>) -> Option<Duration> {
+			match (a, b) {
+				(Some(a), Some(b)) => Some(a + b),
+				(a, b) => a.or(b),
+			}
+		}
+
+		self.connect = sum(self.connect, other.connect);
+		self.greeting = sum(self.greeting, other.greeting);
+		self.catch_all_rcpt = sum(self.catch_all_rcpt, other.catch_all_rcpt);
+		self.rcpt = sum(self.rcpt, other.rcpt);
+		self.headless = sum(self.headless, other.headless);
+	}
 }
 
 /// Debug information on how the SMTP verification went.
@@ -80,6 +165,17 @@ pub struct SmtpDetails {
 pub struct SmtpDebug {
 	/// The verification method used for the email.
 	pub verif_method: SmtpDebugVerifMethod,
+	/// All the MX hosts we tried, in order. The last one is the one which
+	/// gave the final result.
+	#[serde(default)]
+	pub attempted_hosts: Vec<String>,
+	/// The SMTP conversation with all the attempted hosts, if enabled with
+	/// `CheckEmailInput.smtp_transcript`.
+	#[serde(default)]
+	pub transcript: Vec<SmtpTranscriptEntry>,
+	/// Time spent in each step of the SMTP verification.
+	#[serde(default)]
+	pub durations: SmtpDurations,
 }
 
 /// Get all email details we can from one single `EmailAddress`, without
@@ -93,22 +189,34 @@ pub async fn check_smtp(
 	let host_str = host.to_string();
 	let to_email_str = to_email.to_string();
 	let email_provider = EmailProvider::from_mx_host(&host_str);
+	emit(input.events.as_ref(), || CheckEvent::ProviderDetected {
+		mx_host: host_str.clone(),
+		provider: email_provider,
+	});
 
 	// Handle all non-SMTP verifications first, and return early. For the rest,
 	// we'll use SMTP, and return the config.
 	let smtp_verif_method_config = match &email_provider {
 		EmailProvider::HotmailB2C => match &input.verif_method.hotmailb2c {
 			HotmailB2CVerifMethod::Headless => {
+				let start = Instant::now();
+				let result = outlook::headless::check_password_recovery(
+					&to_email_str,
+					&input.webdriver_addr,
+					&input.webdriver_config,
+				)
+				.await
+				.map_err(Into::into);
 				return (
-					outlook::headless::check_password_recovery(
-						&to_email_str,
-						&input.webdriver_addr,
-						&input.webdriver_config,
-					)
-					.await
-					.map_err(Into::into),
+					result,
 					SmtpDebug {
 						verif_method: SmtpDebugVerifMethod::Headless,
+						attempted_hosts: vec![host_str],
+						durations: SmtpDurations {
+							headless: Some(start.elapsed()),
+							..Default::default()
+						},
+						..Default::default()
 					},
 				);
 			}
@@ -122,20 +230,30 @@ pub async fn check_smtp(
 						.map_err(Into::into),
 					SmtpDebug {
 						verif_method: SmtpDebugVerifMethod::Api,
+						attempted_hosts: vec![host_str],
+						..Default::default()
 					},
 				);
 			}
 			YahooVerifMethod::Headless => {
+				let start = Instant::now();
+				let result = yahoo::check_headless(
+					&to_email_str,
+					&input.webdriver_addr,
+					&input.webdriver_config,
+				)
+				.await
+				.map_err(Into::into);
 				return (
-					yahoo::check_headless(
-						&to_email_str,
-						&input.webdriver_addr,
-						&input.webdriver_config,
-					)
-					.await
-					.map_err(Into::into),
+					result,
 					SmtpDebug {
 						verif_method: SmtpDebugVerifMethod::Headless,
+						attempted_hosts: vec![host_str],
+						durations: SmtpDurations {
+							headless: Some(start.elapsed()),
+							..Default::default()
+						},
+						..Default::default()
 					},
 				);
 			}
@@ -159,30 +277,208 @@ pub async fn check_smtp(
 	}
 	.clone();
 
-	// TODO: There's surely a way to not clone here.
-	let verif_method = VerifMethodSmtp::new(
-		smtp_verif_method_config.clone(),
-		input.verif_method.get_proxy(email_provider).cloned(),
-	);
+	let proxy_pool = smtp_verif_method_config
+		.proxy_pool
+		.as_ref()
+		.and_then(|pool_id| input.verif_method.proxy_pools.get(pool_id));
+	let mut proxy = input.verif_method.select_proxy(&smtp_verif_method_config);
+
+	let ports: Vec<u16> = iter::once(smtp_verif_method_config.smtp_port)
+		.chain(smtp_verif_method_config.fallback_smtp_ports.iter().copied())
+		.collect();
+	let mut smtp_debug = SmtpDebugVerifMethodSmtp {
+		host: host_str.clone(),
+		verif_method: smtp_verif_method_config,
+		catch_all_cache_hit: false,
+		port: ports[0],
+		source_ip: None,
+		proxy: None,
+	};
+	let mut ctx = SmtpContext {
+		session_pool: input.smtp_session_pool.as_deref(),
+		catch_all_cache: input.catch_all_cache.as_deref(),
+		debug: &mut smtp_debug,
+		transcript: input.smtp_transcript.enable.then(Vec::new),
+		recorder: None,
+		tls: SmtpTlsDetails::default(),
+		durations: SmtpDurations::default(),
+		events: input.events.as_ref(),
+	};
+
+	let mut tried_proxies = Vec::new();
+	let result = loop {
+		let proxy_id = proxy.map(|(id, _)| id.clone());
+		ctx.debug.proxy = proxy_id.clone();
+
+		// TODO: There's surely a way to not clone here.
+		let mut verif_method = VerifMethodSmtp::new(
+			ctx.debug.verif_method.clone(),
+			proxy.map(|(_, proxy)| proxy.clone()),
+		);
+
+		// Don't even connect if the MX host failed too often lately.
+		let circuit_breaker = verif_method.config.circuit_breaker.clone();
+		let result = match circuit_breaker
+			.as_ref()
+			.map_or(Ok(()), |cb| cb.acquire(&host_str, proxy_id.as_deref()))
+		{
+			Err(err) => {
+				tracing::debug!(
+					target: LOG_TARGET,
+					email=%to_email,
+					mx_host=%host,
+					"Circuit open, skipping SMTP verification"
+				);
+				Err(err)
+			}
+			Ok(()) => {
+				let result = check_smtp_with_port_fallback(
+					to_email,
+					&host_str,
+					domain,
+					&mut verif_method,
+					&mut ctx,
+					ports.clone(),
+				)
+				.await;
+				if let Some(cb) = &circuit_breaker {
+					cb.record(&host_str, proxy_id.as_deref(), &result);
+				}
+				result
+			}
+		};
+
+		// Quarantine the proxy of the pool if it's the cause of the error,
+		// and try again with the next healthy proxy of the pool, if it's one
+		// we haven't tried yet.
+		match (&result, proxy_id, proxy_pool) {
+			(Err(err), Some(proxy_id), Some(pool)) if pool.record_error(&proxy_id, err) => {
+				tried_proxies.push(proxy_id);
+				proxy = input.verif_method.select_proxy(&ctx.debug.verif_method);
+				match proxy {
+					Some((next_id, _)) if !tried_proxies.contains(next_id) => {
+						tracing::debug!(
+							target: LOG_TARGET,
+							email=%to_email,
+							mx_host=%host,
+							proxy=%next_id,
+							error=?err,
+							"Proxy failed, trying the next one of the pool"
+						);
+					}
+					_ => break result,
+				}
+			}
+			_ => break result,
+		}
+	};
+
+	// Release the borrow of `smtp_debug` held by the context.
+	let SmtpContext {
+		transcript,
+		durations,
+		..
+	} = ctx;
+	let mut transcript = transcript.unwrap_or_default();
+	if input.smtp_transcript.redact {
+		transcript.iter_mut().for_each(SmtpTranscriptEntry::redact);
+	}
 
 	(
-		check_smtp_with_retry(
-			to_email,
-			&host_str,
-			domain,
-			&verif_method,
-			verif_method.config.retries,
-		)
-		.await,
+		result,
 		SmtpDebug {
-			verif_method: SmtpDebugVerifMethod::Smtp(SmtpDebugVerifMethodSmtp {
-				host: host_str,
-				verif_method: smtp_verif_method_config,
-			}),
+			verif_method: SmtpDebugVerifMethod::Smtp(Box::new(smtp_debug)),
+			attempted_hosts: vec![host_str],
+			transcript,
+			durations,
 		},
 	)
 }
 
+/// Try each port in order, moving on to the next one only when the
+/// connection fails.
+async fn check_smtp_with_port_fallback(
+	to_email: &EmailAddress,
+	host: &str,
+	domain: &str,
+	verif_method: &mut VerifMethodSmtp,
+	ctx: &mut SmtpContext<'_>,
+	ports: Vec<u16>,
+) -> Result<SmtpDetails, SmtpError> {
+	let mut ports = ports.into_iter().peekable();
+	loop {
+		let port = ports.next().expect("There's at least one port. qed.");
+		verif_method.config.smtp_port = port;
+		ctx.debug.port = port;
+		let result = check_smtp_with_retry(
+			to_email,
+			host,
+			domain,
+			verif_method,
+			ctx,
+			verif_method.config.max_attempts(),
+		)
+		.await;
+
+		match &result {
+			Err(err) if ports.peek().is_some() && err.is_connection_error() => {
+				tracing::debug!(
+					target: LOG_TARGET,
+					email=%to_email,
+					mx_host=%host,
+					port=port,
+					error=?err,
+					"Connection to SMTP port failed, trying next one"
+				);
+			}
+			_ => return result,
+		}
+	}
+}
+
+/// Same as `check_smtp`, but tries each of the given MX hosts in order, and
+/// moves on to the next one when the current one fails at the connection
+/// level (timeout, refused connection...). The hosts should already be
+/// ordered, see `MxDetails::hosts`.
+pub async fn check_smtp_with_mx_fallback(
+	to_email: &EmailAddress,
+	mx_hosts: &[Name],
+	domain: &str,
+	input: &CheckEmailInput,
+) -> (Result<SmtpDetails, SmtpError>, SmtpDebug) {
+	let mut attempted_hosts = Vec::with_capacity(mx_hosts.len());
+	let mut transcript = vec![];
+	let mut durations = SmtpDurations::default();
+
+	for (i, host) in mx_hosts.iter().enumerate() {
+		let (result, mut smtp_debug) = check_smtp(to_email, host, domain, input).await;
+		attempted_hosts.push(host.to_string());
+		transcript.append(&mut smtp_debug.transcript);
+		durations.add(&smtp_debug.durations);
+
+		let is_last = i + 1 == mx_hosts.len();
+		match &result {
+			Err(err) if !is_last && err.is_connection_error() => {
+				tracing::debug!(
+					target: LOG_TARGET,
+					email=%to_email,
+					mx_host=%host,
+					error=?err,
+					"Connection to MX host failed, trying next one"
+				);
+			}
+			_ => {
+				smtp_debug.attempted_hosts = attempted_hosts;
+				smtp_debug.transcript = transcript;
+				smtp_debug.durations = durations;
+				return (result, smtp_debug);
+			}
+		}
+	}
+
+	unreachable!("There should be at least one MX host. qed.")
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -191,8 +487,12 @@ mod tests {
 	use crate::smtp::verif_method::VerifMethodSmtpConfig;
 	use crate::CheckEmailInputBuilder;
 	use crate::EmailAddress;
+	use crate::{CheckEmailInputProxy, ProxyType};
 	use hickory_proto::rr::Name;
+	use std::collections::HashMap;
 	use std::{str::FromStr, time::Duration};
+	use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufStream};
+	use tokio::net::TcpListener;
 	use tokio::runtime::Runtime;
 
 	#[test]
@@ -216,7 +516,10 @@ mod tests {
 
 		let (res, smtp_debug) = runtime.block_on(check_smtp(&to_email, &host, "gmail.com", &input));
 		match smtp_debug.verif_method {
-			SmtpDebugVerifMethod::Smtp(SmtpDebugVerifMethodSmtp { host, verif_method }) => {
+			SmtpDebugVerifMethod::Smtp(smtp) => {
+				let SmtpDebugVerifMethodSmtp {
+					host, verif_method, ..
+				} = *smtp;
 				assert_eq!(host, "alt4.aspmx.l.google.com.");
 				assert_eq!(verif_method.smtp_port, 25);
 				assert_eq!(verif_method.smtp_timeout, Some(Duration::from_millis(1)));
@@ -231,4 +534,151 @@ mod tests {
 			_ => panic!("check_smtp did not time out"),
 		}
 	}
+
+	#[tokio::test]
+	async fn should_fall_back_on_next_port() {
+		// Two ports on which nothing listens.
+		let closed_port = || {
+			std::net::TcpListener::bind("127.0.0.1:0")
+				.unwrap()
+				.local_addr()
+				.unwrap()
+				.port()
+		};
+		let (port1, port2) = (closed_port(), closed_port());
+
+		let to_email = EmailAddress::from_str("foo@example.com").unwrap();
+		let host = Name::from_str("127.0.0.1.").unwrap();
+		let input = CheckEmailInputBuilder::default()
+			.to_email("foo@example.com".into())
+			.verif_method(VerifMethod {
+				everything_else: EverythingElseVerifMethod::Smtp(VerifMethodSmtpConfig {
+					smtp_port: port1,
+					fallback_smtp_ports: vec![port2],
+					..Default::default()
+				}),
+				..Default::default()
+			})
+			.build()
+			.unwrap();
+
+		let (res, smtp_debug) = check_smtp(&to_email, &host, "example.com", &input).await;
+		assert!(res.unwrap_err().is_connection_error());
+		match smtp_debug.verif_method {
+			SmtpDebugVerifMethod::Smtp(smtp) => {
+				assert_eq!(smtp.port, port2);
+				assert_eq!(smtp.verif_method.smtp_port, port1);
+			}
+			_ => panic!("Expected SmtpDebugVerifMethod::Smtp"),
+		}
+	}
+
+	/// A fake HTTP CONNECT proxy, which opens the tunnel to a fake SMTP
+	/// server accepting all emails. Returns its port.
+	async fn fake_http_connect_smtp_server() -> u16 {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		tokio::spawn(async move {
+			loop {
+				let (stream, _) = listener.accept().await.unwrap();
+				tokio::spawn(async move {
+					let mut stream = BufStream::new(stream);
+					let mut line = String::new();
+					while stream.read_line(&mut line).await.unwrap_or(0) > 0 && line != "\r\n" {
+						line.clear();
+					}
+					stream
+						.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n220 localhost\r\n")
+						.await
+						.unwrap();
+					stream.flush().await.unwrap();
+					line.clear();
+					while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+						let cmd = line.to_lowercase();
+						let reply: &[u8] = if cmd.starts_with("quit") {
+							b"221 Bye\r\n"
+						} else if cmd.starts_with("starttls") {
+							b"502 5.5.1 Command not implemented\r\n"
+						} else {
+							b"250 OK\r\n"
+						};
+						stream.write_all(reply).await.unwrap();
+						stream.flush().await.unwrap();
+						if cmd.starts_with("quit") {
+							break;
+						}
+						line.clear();
+					}
+				});
+			}
+		});
+
+		port
+	}
+
+	#[tokio::test]
+	async fn should_try_next_proxy_of_pool() {
+		let closed_port = std::net::TcpListener::bind("127.0.0.1:0")
+			.unwrap()
+			.local_addr()
+			.unwrap()
+			.port();
+		let proxy = |port: u16| CheckEmailInputProxy {
+			proxy_type: ProxyType::HttpConnect,
+			host: "127.0.0.1".into(),
+			port,
+			username: None,
+			password: None,
+			timeout_ms: None,
+		};
+
+		// The health state of the pools is global, so the proxy IDs are only
+		// used by this test.
+		let mut proxies = HashMap::new();
+		proxies.insert("failover_dead".to_string(), proxy(closed_port));
+		proxies.insert(
+			"failover_alive".to_string(),
+			proxy(fake_http_connect_smtp_server().await),
+		);
+		let mut proxy_pools = HashMap::new();
+		proxy_pools.insert(
+			"failover".to_string(),
+			ProxyPool {
+				proxies: ["failover_dead", "failover_alive"]
+					.iter()
+					.map(|id| ProxyPoolMember {
+						proxy: id.to_string(),
+						weight: 1,
+					})
+					.collect(),
+				selection: ProxySelection::RoundRobin,
+				quarantine_secs: 300,
+			},
+		);
+
+		let to_email = EmailAddress::from_str("foo@example.com").unwrap();
+		let host = Name::from_str("127.0.0.1.").unwrap();
+		let input = CheckEmailInputBuilder::default()
+			.to_email("foo@example.com".into())
+			.verif_method(VerifMethod {
+				proxies,
+				proxy_pools,
+				everything_else: EverythingElseVerifMethod::Smtp(VerifMethodSmtpConfig {
+					proxy_pool: Some("failover".into()),
+					..Default::default()
+				}),
+				..Default::default()
+			})
+			.build()
+			.unwrap();
+
+		let (res, smtp_debug) = check_smtp(&to_email, &host, "example.com", &input).await;
+		assert!(res.unwrap().is_deliverable);
+		match smtp_debug.verif_method {
+			SmtpDebugVerifMethod::Smtp(smtp) => {
+				assert_eq!(smtp.proxy.as_deref(), Some("failover_alive"));
+			}
+			_ => panic!("Expected SmtpDebugVerifMethod::Smtp"),
+		}
+	}
 }
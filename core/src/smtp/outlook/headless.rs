@@ -122,6 +122,7 @@ pub async fn check_password_recovery(
 		is_catch_all: false,
 		is_deliverable,
 		is_disabled: false,
+		..Default::default()
 	})
 }
 
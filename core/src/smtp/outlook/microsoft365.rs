@@ -15,8 +15,8 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::{
-	smtp::{http_api::create_client, SmtpDetails},
-	util::ser_with_display::ser_with_display,
+	smtp::SmtpDetails,
+	util::{http_client::create_client, ser_with_display::ser_with_display},
 	CheckEmailInput, LOG_TARGET,
 };
 use async_smtp::EmailAddress;
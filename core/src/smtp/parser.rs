@@ -17,126 +17,184 @@
 //! Parse the SMTP responses to get information about the email address.
 
 use super::error::SmtpError;
+use super::patterns::{self, SmtpPatternCategory};
 use crate::EmailAddress;
 use async_smtp::error::Error as AsyncSmtpError;
+use async_smtp::response::Response;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// An RFC 3463 enhanced status code, e.g. 5.1.1, serialized as a string.
+#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq, Deserialize, Serialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct EnhancedStatusCode {
+	/// 2 (success), 4 (persistent transient failure) or 5 (permanent
+	/// failure).
+	pub class: u8,
+	/// The subject, e.g. 1 for addressing status, 2 for mailbox status.
+	pub subject: u16,
+	/// The detail, whose meaning depends on the subject.
+	pub detail: u16,
+}
+
+impl EnhancedStatusCode {
+	/// Parse the enhanced status code at the start of a reply text, as in
+	/// "5.1.1 User unknown".
+	pub fn parse_prefix(text: &str) -> Option<Self> {
+		text.split_whitespace().next()?.parse().ok()
+	}
+
+	fn is(&self, class: u8, subject: u16, detail: u16) -> bool {
+		self.class == class && self.subject == subject && self.detail == detail
+	}
+}
+
+impl fmt::Display for EnhancedStatusCode {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}.{}.{}", self.class, self.subject, self.detail)
+	}
+}
+
+impl FromStr for EnhancedStatusCode {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = || format!("Invalid enhanced status code: {}", s);
+		let mut parts = s.split('.');
+		let (class, subject, detail) =
+			match (parts.next(), parts.next(), parts.next(), parts.next()) {
+				(Some(class), Some(subject), Some(detail), None) => (class, subject, detail),
+				_ => return Err(invalid()),
+			};
+		let class: u8 = class.parse().map_err(|_| invalid())?;
+		if !matches!(class, 2 | 4 | 5) || subject.len() > 3 || detail.len() > 3 {
+			return Err(invalid());
+		}
+
+		Ok(Self {
+			class,
+			subject: subject.parse().map_err(|_| invalid())?,
+			detail: detail.parse().map_err(|_| invalid())?,
+		})
+	}
+}
+
+impl From<EnhancedStatusCode> for String {
+	fn from(code: EnhancedStatusCode) -> Self {
+		code.to_string()
+	}
+}
+
+impl TryFrom<String> for EnhancedStatusCode {
+	type Error = String;
+
+	fn try_from(s: String) -> Result<Self, Self::Error> {
+		s.parse()
+	}
+}
+
+/// A reply of the SMTP server, with its basic and enhanced status codes.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct SmtpReply {
+	/// The 3-digit reply code, e.g. 550.
+	pub code: u16,
+	/// The RFC 3463 enhanced status code, if the server sent one.
+	pub enhanced_code: Option<EnhancedStatusCode>,
+	/// The text of the reply, with multiline replies joined by "; ".
+	pub message: String,
+}
+
+/// What a RCPT TO reply tells us about the mailbox.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ReplyClass {
+	/// The mailbox doesn't exist.
+	Invalid,
+	/// The mailbox exists, but is full.
+	FullInbox,
+	/// The mailbox is disabled.
+	Disabled,
+}
+
+impl SmtpReply {
+	pub fn from_response(response: &Response) -> Self {
+		let code = response.code;
+		let message = response.message.join("; ");
+
+		Self {
+			code: code.severity as u16 * 100 + code.category as u16 * 10 + code.detail as u16,
+			enhanced_code: EnhancedStatusCode::parse_prefix(&message),
+			message,
+		}
+	}
+
+	/// The reply of the server in the error, if the error is a negative
+	/// reply.
+	pub fn from_error(err: &AsyncSmtpError) -> Option<Self> {
+		match err {
+			AsyncSmtpError::Transient(r) | AsyncSmtpError::Permanent(r) => {
+				Some(Self::from_response(r))
+			}
+			_ => None,
+		}
+	}
+
+	/// Classify a negative reply to RCPT TO from its status codes only.
+	/// Returns None if the codes are not conclusive, in which case the text
+	/// of the reply should be checked instead.
+	pub fn classify(&self) -> Option<ReplyClass> {
+		match self.enhanced_code {
+			Some(code) => {
+				// X.2.2 Mailbox full, transient or permanent.
+				if code.class != 2 && code.subject == 2 && code.detail == 2 {
+					Some(ReplyClass::FullInbox)
+				// 5.2.1 Mailbox disabled, not accepting messages. Note that
+				// 4.2.1 is also used for rate limiting.
+				} else if code.is(5, 2, 1) {
+					Some(ReplyClass::Disabled)
+				// 5.1.1 Bad destination mailbox address
+				// 5.1.2 Bad destination system address
+				// 5.1.3 Bad destination mailbox address syntax
+				// 5.1.6 Destination mailbox has moved, no forwarding address
+				// 5.1.10 Recipient address has null MX (RFC 7505)
+				} else if code.class == 5
+					&& code.subject == 1
+					&& matches!(code.detail, 1 | 2 | 3 | 6 | 10)
+				{
+					Some(ReplyClass::Invalid)
+				} else {
+					None
+				}
+			}
+			// A bare 552 is not conclusive: RFC 5321 section 4.5.3.1.10 allows it
+			// for too many recipients, so we leave it to the text patterns.
+			None => None,
+		}
+	}
+}
 
 /// is_invalid checks for SMTP responses meaning that the email is invalid,
-/// i.e. that the mailbox doesn't exist.
-pub fn is_invalid(e: &str, email: &EmailAddress) -> bool {
-	// 550 Address rejected
-	// 550 5.1.1 : Recipient address rejected
-	// 550 5.1.1 : Recipient address rejected: User unknown in virtual alias table
-	// 550 5.1.1 <EMAIL: Recipient address rejected: User unknown in relay recipient table
-	e.contains("address rejected")
-	// 550 5.1.1 : Unrouteable address
-	|| e.contains("unrouteable")
-	// 550 5.1.1 : The email account that you tried to reach does not exist
-	|| e.contains("does not exist")
-	// 550 invalid address
-	// 550 User not local or invalid address – Relay denied
-	|| e.contains("invalid address")
-	// 5.1.1 Invalid email address
-	|| e.contains("invalid email address")
-	// 550 Invalid recipient
-	|| e.contains("invalid recipient")
-	|| e.contains("may not exist")
-	|| e.contains("recipient invalid")
-	// 550 5.1.1 : Recipient rejected
-	|| e.contains("recipient rejected")
-	// permanent: 5.1.1 Unknown recipient address
-	|| e.contains("unknown recipient address")
-	// 554 Unknown Recipient (#5.1.1) (on @parkwayhonda.com)
-	|| e.contains("unknown recipient") 
-	|| e.contains("undeliverable")
-	// 550 User unknown
-	// 550 5.1.1 <EMAIL> User unknown
-	// 550 recipient address rejected: user unknown in local recipient table
-	|| e.contains("user unknown")
-	// 550 Unknown user
-	|| e.contains("unknown user")
-	// 5.1.1 Recipient unknown <EMAIL>
-	|| e.contains("recipient unknown")
-	// 550 5.1.1 No such user - pp
-	// 550 No such user here
-	|| e.contains("no such user")
-	// permanent: 5.1.1 MXIN501 mailbox <EMAIL> unknown (on @virginmedia.com)
-	|| e.contains(format!("mailbox {email} unknown").as_str())
-	// 550 5.1.1 : Mailbox not found
-	// 550 Unknown address error ‘MAILBOX NOT FOUND’
-	|| e.contains("mailbox not found")
-	// 550 5.1.1 : Invalid mailbox
-	|| e.contains("invalid mailbox")
-	// 550 5.1.1 Sorry, no mailbox here by that name
-	|| e.contains("no mailbox")
-	// 5.2.0 No such mailbox
-	|| e.contains("no such mailbox")
-	// 550 Requested action not taken: mailbox unavailable
-	|| e.contains("mailbox unavailable")
-	// 5.5.0 Requested actions not taken as the mailbox is unavailable (on @etu.uca.fr)
-	|| e.contains("mailbox is unavailable")
-	// 550 5.1.1 Is not a valid mailbox
-	|| e.contains("not a valid mailbox")
-	// No such recipient here
-	|| e.contains("no such recipient")
-	// 554 delivery error: This user doesn’t have an account
-	|| e.contains("have an account")
-	// permanent: Unknown local part <USER> in <USER@flabeg.com> (on @flabeg.com)
-	|| e.contains("unknown local part")
-	// 5.1.1 RCP-P1 Domain facebook.com no longer available https://www.facebook.com/postmaster/response_codes?ip=3.80.111.155#RCP-P1
-	|| e.contains("no longer available")
-	// permanent: RCPT (<EMAIL>) dosn't exist (on @hgy.ooo, @stigpods.com.cn)
-	|| e.contains("dosn't exist") // sic! typo is intentional
-	// 5.1.1 <EMAIL>: Email address could not be found, or was misspelled (G8) (on @biotech-calendar.com, @invoicefactoring.com)
-	|| e.contains("could not be found") 
-	// No such person at this address (on @aconsa.com.mx)
-	|| e.contains("no such person")
-	// Callout verification failed: 550 No Such User Here (on @medipro.co.uk)
-	|| e.contains("no such user")
-	// 5.1.1 <EMAIL> Address Error (on @lucidity.co.za)
-	|| e.contains("address error")
-	// E-mail address is not handled by this system (on @kaimayfair.co.uk)
-	|| e.contains("address is not handled")
-	// permanent: 5.1.1 recipient is not exist (on @sim.com)
-	|| e.contains("recipient is not exist")
-	// permanent: 5.1.1 <EMAIL>  Recipient not found. (on @4polymer.com)
-	|| e.contains("recipient not found")
-	// permanent: 5.7.1 Email doesn't exist. Please forward it or send it to contact@magency.f; 5.7.1 r - gcdp 38308e7fff4ca-30344241c74si13773751fa.513 - gsmtp"
-	|| e.contains("email doesn't exist")
-	// permanent: verify address failed, User not found ***@salemall.vn (on @salemall.vn)
-	|| e.contains("verify address failed")
-	// transient: unable to verify user (on @computan.net)
-	|| e.contains("unable to verify user")
-	// permanent: 5.1.1 Utilisateur inconnu (on @sante.fr)
-	|| e.contains("utilisateur inconnu")
-	// permanent: 5.1.1
-	|| e.contains("permanent: 5.1.1")
-	// permanent: 5.7.1
-	|| e.contains("permanent: 5.7.1")
+/// i.e. that the mailbox doesn't exist. The patterns are in `patterns.json`.
+pub fn is_invalid(e: &str, email: &EmailAddress, mx_host: &str) -> bool {
+	patterns::matches(SmtpPatternCategory::Invalid, e, email, mx_host)
 }
 
 /// Check that the mailbox has a full inbox.
-pub fn is_full_inbox(e: &str) -> bool {
-	e.contains("insufficient")
-	// https://answers.microsoft.com/en-us/outlook_com/forum/all/how-do-i-interpret-the-delivery-failure-message/2f1bf9c0-8b03-4f8f-aacc-5f6ba60a73f3
-	|| e.contains("mailbox full")
-	// https://answers.microsoft.com/en-us/outlook_com/forum/all/how-do-i-interpret-the-delivery-failure-message/2f1bf9c0-8b03-4f8f-aacc-5f6ba60a73f3
-	|| e.contains("quote exceeded")
-	|| e.contains("over quota")
-	// 550 user has too many messages on the server
-	|| e.contains("too many messages")
-	// transient: 4.2.2 The recipient's inbox is out of storage space. Please direct the; 4.2.2 recipient to; 4.2.2  https://support.google.com/mail/?p=OverQuotaTemp 41be03b00d2f7-801d5c00011si4287631a12.311 - gsmtp (on @gmail.com)
-	|| e.contains("out of storage space")
+pub fn is_full_inbox(e: &str, email: &EmailAddress, mx_host: &str) -> bool {
+	patterns::matches(SmtpPatternCategory::FullInbox, e, email, mx_host)
 }
 
 /// Check if the email account has been disabled or blocked by the email
 /// provider.
-pub fn is_disabled_account(e: &str) -> bool {
-	// 554 The email account that you tried to reach is disabled. Learn more at https://support.google.com/mail/?p=DisabledUser"
-	e.contains("disabled")
-	// 554 delivery error: Sorry your message to <EMAIL> cannot be delivered. This account has been disabled or discontinued
- || e.contains("discontinued")
- //550 5.2.1 RACT MY.IP: Mailbox is inactive: <USER@hanmail.net><CRLF> (on hanmail.net)
- || e.contains("inactive")
+pub fn is_disabled_account(e: &str, email: &EmailAddress, mx_host: &str) -> bool {
+	patterns::matches(SmtpPatternCategory::Disabled, e, email, mx_host)
+}
+
+/// Check if the error says that the user can actually receive emails, e.g.
+/// when the server rate limits the mailbox.
+pub fn is_deliverable(e: &str, email: &EmailAddress, mx_host: &str) -> bool {
+	patterns::matches(SmtpPatternCategory::Deliverable, e, email, mx_host)
 }
 
 /// Check if the error is an IO "incomplete" error.
@@ -147,17 +205,37 @@ pub fn is_err_io_errors(e: &SmtpError) -> bool {
 	}
 }
 
-/// Check if the IP is blacklisted.
-pub fn is_err_ip_blacklisted(e: &SmtpError) -> bool {
-	let e = match e {
+/// Check if the server refused a RCPT TO because the mail transaction
+/// already has too many recipients.
+pub fn is_err_too_many_recipients(e: &SmtpError) -> bool {
+	match e {
+		SmtpError::AsyncSmtpError(AsyncSmtpError::Transient(r) | AsyncSmtpError::Permanent(r)) => {
+			let e = r.message.join("; ").to_lowercase();
+			// 452 4.5.3 Too many recipients
+			// 452 Too many recipients received this hour
+			e.contains("too many recipients")
+		}
+		_ => false,
+	}
+}
+
+/// The lowercase text of the server's negative reply in the error, if any.
+fn negative_reply_text(e: &SmtpError) -> Option<String> {
+	match e {
 		SmtpError::AsyncSmtpError(AsyncSmtpError::Transient(r) | AsyncSmtpError::Permanent(r)) => {
 			// TODO We can use .to_string() after:
 			// https://github.com/async-email/async-smtp/pull/53
-			r.message.join("; ").to_lowercase()
-		}
-		_ => {
-			return false;
+			Some(r.message.join("; ").to_lowercase())
 		}
+		_ => None,
+	}
+}
+
+/// Check if the IP is blacklisted.
+pub fn is_err_ip_blacklisted(e: &SmtpError) -> bool {
+	let e = match negative_reply_text(e) {
+		Some(e) => e,
+		None => return false,
 	};
 
 	// Permanent errors
@@ -219,15 +297,9 @@ pub fn is_err_ip_blacklisted(e: &SmtpError) -> bool {
 
 /// Check if the IP needs a reverse DNS.
 pub fn is_err_needs_rdns(e: &SmtpError) -> bool {
-	let e = match e {
-		SmtpError::AsyncSmtpError(AsyncSmtpError::Transient(r) | AsyncSmtpError::Permanent(r)) => {
-			// TODO We can use .to_string() after:
-			// https://github.com/async-email/async-smtp/pull/53
-			r.message.join("; ").to_lowercase()
-		}
-		_ => {
-			return false;
-		}
+	let e = match negative_reply_text(e) {
+		Some(e) => e,
+		None => return false,
 	};
 
 	// 4.7.25 Client host rejected: cannot find your hostname, [147.75.45.223]
@@ -238,10 +310,117 @@ pub fn is_err_needs_rdns(e: &SmtpError) -> bool {
 	|| e.contains("reverse dns entry")
 }
 
+/// Check if the server refused us because we opened too many connections.
+pub fn is_err_too_many_connections(e: &SmtpError) -> bool {
+	let e = match negative_reply_text(e) {
+		Some(e) => e,
+		None => return false,
+	};
+
+	// 421 4.7.0 Too many connections from your IP
+	// 421 Too many concurrent SMTP connections; please try again later.
+	e.contains("too many connections")
+	|| e.contains("too many concurrent")
+	|| e.contains("too many simultaneous")
+	// 421 4.7.0 Too many sessions, closing transmission channel
+	|| e.contains("too many sessions")
+	// 421 4.3.2 Connection limit exceeded
+	|| e.contains("connection limit")
+	|| e.contains("maximum number of connections")
+}
+
+/// Check if the server temporarily deferred us because of greylisting.
+pub fn is_err_greylisted(e: &SmtpError) -> bool {
+	let e = match negative_reply_text(e) {
+		Some(e) => e,
+		None => return false,
+	};
+
+	// 450 4.2.0 <EMAIL>: Recipient address rejected: Greylisted, see http://postgrey.schweikert.ch/help/example.com.html
+	// 451 4.7.1 Greylisting in action, please come back later
+	e.contains("greylist")
+		|| e.contains("graylist")
+		|| e.contains("grey list")
+		|| e.contains("gray list")
+		|| e.contains("grey-list")
+		|| e.contains("gray-list")
+}
+
+/// Check if the server rate limits us.
+pub fn is_err_rate_limited(e: &SmtpError) -> bool {
+	let e = match negative_reply_text(e) {
+		Some(e) => e,
+		None => return false,
+	};
+
+	// 450 4.7.1 Rate limit exceeded, try again later
+	e.contains("rate limit")
+	|| e.contains("ratelimit")
+	|| e.contains("rate-limit")
+	// 421 4.7.28 Our system has detected an unusual rate of unsolicited mail originating from your IP address.
+	|| e.contains("unusual rate")
+	|| e.contains("sending rate")
+	|| e.contains("too many requests")
+	// 421 4.7.0 Try again later, closing connection. (EHLO)
+	|| e.contains("try again later, closing connection")
+}
+
+/// Check if the server requires TLS, which we don't use.
+pub fn is_err_tls_required(e: &SmtpError) -> bool {
+	let e = match negative_reply_text(e) {
+		Some(e) => e,
+		None => return false,
+	};
+
+	// 530 5.7.0 Must issue a STARTTLS command first
+	e.contains("starttls")
+	// 530 5.7.0 TLS required
+	|| e.contains("tls required")
+	|| e.contains("requires tls")
+	|| e.contains("tls is required")
+	// 523 5.7.10 Encryption needed
+	|| e.contains("encryption required")
+	|| e.contains("encryption needed")
+}
+
+/// Check if the server refused our MAIL FROM because of its sender policy,
+/// e.g. SPF or DMARC.
+pub fn is_err_policy_rejected(e: &SmtpError) -> bool {
+	let e = match negative_reply_text(e) {
+		Some(e) => e,
+		None => return false,
+	};
+
+	// A reply mentioning SPF isn't a rejection by itself, e.g. a temporary
+	// SPF lookup error, so we look for a failure.
+	let code = EnhancedStatusCode::parse_prefix(&e);
+	// 550 5.7.23 SPF validation failed (RFC 7372)
+	let is_spf_failure = code.is_some_and(|code| code.is(5, 7, 23))
+		// 550 5.7.1 SPF check failed
+		|| (e.contains("spf")
+			&& (code.is_some_and(|code| code.is(5, 7, 1))
+				|| e.contains("fail")
+				|| e.contains("violation")
+				|| e.contains("not permitted")
+				|| e.contains("not authorized")));
+
+	// 550 5.7.23 The message was rejected because of Sender Policy Framework violation
+	is_spf_failure
+	|| e.contains("sender policy")
+	|| e.contains("dmarc")
+	// 553 5.7.1 <EMAIL>: Sender address rejected: not owned by user
+	|| e.contains("sender address rejected")
+	|| e.contains("sender rejected")
+	// 550 5.7.1 Sender verify failed
+	|| e.contains("sender verify failed")
+	|| e.contains("sender verification failed")
+}
+
 #[cfg(test)]
 mod tests {
 
-	use super::{is_err_ip_blacklisted, is_invalid};
+	use super::{is_err_ip_blacklisted, is_invalid, EnhancedStatusCode, ReplyClass, SmtpReply};
+	use crate::smtp::SmtpErrorDesc;
 	use crate::EmailAddress;
 	use crate::SmtpError::AsyncSmtpError;
 	use async_smtp::{
@@ -256,20 +435,87 @@ mod tests {
 
 		assert!(!is_invalid(
 			"554 5.7.1 <mta.voipdir.net[]>: Client host rejected: Access denied",
-			&email
+			&email,
+			"mta.voipdir.net."
 		));
 
 		assert!(is_invalid(
 			"RCPT (***@stigpods.com.cn) dosn't exist",
-			&email
+			&email,
+			"mx.stigpods.com.cn."
 		));
 
 		assert!(is_invalid(
 			"permanent: 5.1.1 MXIN501 mailbox foo@bar.baz unknown (on @virginmedia.com)",
-			&email
+			&email,
+			"mx.bar.baz."
 		));
 	}
 
+	#[test]
+	fn test_parse_reply() {
+		let response = Response::new(
+			Code::new(
+				Severity::PermanentNegativeCompletion,
+				Category::MailSystem,
+				Detail::Zero,
+			),
+			vec![
+				"5.1.1 The email account that you tried to reach does not exist.".to_string(),
+				"5.1.1 https://support.google.com/mail/?p=NoSuchUser".to_string(),
+			],
+		);
+		let reply = SmtpReply::from_response(&response);
+
+		assert_eq!(reply.code, 550);
+		assert_eq!(reply.enhanced_code, Some("5.1.1".parse().unwrap()));
+		assert_eq!(reply.classify(), Some(ReplyClass::Invalid));
+		assert_eq!(
+			serde_json::to_value(reply.enhanced_code).unwrap(),
+			serde_json::json!("5.1.1")
+		);
+	}
+
+	#[test]
+	fn test_classify_reply() {
+		let reply = |code: u16, message: &str| SmtpReply {
+			code,
+			enhanced_code: EnhancedStatusCode::parse_prefix(message),
+			message: message.to_string(),
+		};
+
+		assert_eq!(
+			reply(
+				452,
+				"4.2.2 The email account that you tried to reach is over quota"
+			)
+			.classify(),
+			Some(ReplyClass::FullInbox)
+		);
+		assert_eq!(
+			reply(
+				550,
+				"5.2.1 The email account that you tried to reach is disabled"
+			)
+			.classify(),
+			Some(ReplyClass::Disabled)
+		);
+		// Rate limiting, not a disabled mailbox.
+		assert_eq!(
+			reply(
+				450,
+				"4.2.1 The user you are trying to contact is receiving mail at a rate that"
+			)
+			.classify(),
+			None
+		);
+		// Policy rejections are left to the text patterns.
+		assert_eq!(reply(550, "5.7.1 Relaying denied").classify(), None);
+		assert_eq!(reply(550, "User unknown").classify(), None);
+		assert_eq!(reply(552, "Too many recipients").classify(), None);
+		assert_eq!(EnhancedStatusCode::parse_prefix("1.2.3.4 is blocked"), None);
+	}
+
 	#[test]
 	fn test_is_err_ip_blacklisted() {
 		let err = Error::Permanent(Response::new(
@@ -288,4 +534,62 @@ mod tests {
 
 		assert!(is_err_ip_blacklisted(&AsyncSmtpError(err)))
 	}
+
+	#[test]
+	fn test_describe_errors() {
+		let err = |message: &str| {
+			AsyncSmtpError(Error::Transient(Response::new(
+				Code::new(
+					Severity::TransientNegativeCompletion,
+					Category::MailSystem,
+					Detail::Zero,
+				),
+				vec![message.to_string()],
+			)))
+		};
+
+		let cases = [
+			(
+				"4.7.0 Too many connections from your IP",
+				Some(SmtpErrorDesc::TooManyConnections),
+			),
+			(
+				"4.2.0 <foo@bar.baz>: Recipient address rejected: Greylisted",
+				Some(SmtpErrorDesc::Greylisted),
+			),
+			(
+				"4.7.28 Unusual rate of unsolicited mail",
+				Some(SmtpErrorDesc::RateLimited),
+			),
+			(
+				"5.7.0 Must issue a STARTTLS command first",
+				Some(SmtpErrorDesc::TlsRequired),
+			),
+			(
+				"5.7.1 SPF check failed",
+				Some(SmtpErrorDesc::PolicyRejected),
+			),
+			(
+				"5.7.23 Message rejected, see https://example.com/help",
+				Some(SmtpErrorDesc::PolicyRejected),
+			),
+			(
+				"Rejected: SPF validation failed for the sender",
+				Some(SmtpErrorDesc::PolicyRejected),
+			),
+			("4.4.3 Temporary SPF lookup error", None),
+			(
+				"5.7.1 IP address blacklisted",
+				Some(SmtpErrorDesc::IpBlacklisted),
+			),
+			("foobar", None),
+		];
+		for (message, desc) in cases.iter() {
+			assert_eq!(err(message).get_description(), *desc, "{}", message);
+		}
+
+		assert!(err("4.7.1 Greylisting in action").is_retryable());
+		assert!(err("foobar").is_retryable());
+		assert!(!err("5.7.0 TLS required").is_retryable());
+	}
 }
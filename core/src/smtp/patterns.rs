@@ -0,0 +1,265 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Catalogue of the SMTP reply texts we recognize, read from a versioned
+//! JSON file. The built-in catalogue is `patterns.json`, and additional
+//! patterns can be loaded at startup with `load_smtp_patterns`, e.g. for
+//! servers replying in other languages.
+//!
+//! Each pattern has a category, and either a `contains` substring or a
+//! `regex`, both matched case-insensitively against the reply. A `contains`
+//! substring may include the `{email}` placeholder, replaced by the verified
+//! email. Patterns can be scoped to some email providers (`providers`, e.g.
+//! `["gmail"]`) or to MX hosts (`mx_suffixes`, e.g. `[".gmx.net"]`).
+
+use super::verif_method::EmailProvider;
+use crate::EmailAddress;
+use once_cell::sync::Lazy;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::RwLock;
+
+/// The version of the patterns file format supported by this crate.
+pub const SMTP_PATTERNS_VERSION: u32 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SmtpPatternsError {
+	#[error("Cannot read SMTP patterns file: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("Invalid SMTP patterns file: {0}")]
+	Json(#[from] serde_json::Error),
+	#[error(
+		"Unsupported SMTP patterns version {0}, expected {expected}",
+		expected = SMTP_PATTERNS_VERSION
+	)]
+	UnsupportedVersion(u32),
+	#[error("Invalid regex in SMTP pattern: {0}")]
+	Regex(#[from] regex::Error),
+	#[error("Each SMTP pattern must have exactly one of \"contains\" or \"regex\"")]
+	MissingMatcher,
+}
+
+/// What a matching reply to RCPT TO tells us about the mailbox.
+#[derive(Debug, Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpPatternCategory {
+	/// The mailbox doesn't exist.
+	Invalid,
+	/// The mailbox exists, but is full.
+	FullInbox,
+	/// The mailbox is disabled or blocked by the provider.
+	Disabled,
+	/// The mailbox exists, even though the server refused the RCPT TO.
+	Deliverable,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawPattern {
+	category: SmtpPatternCategory,
+	contains: Option<String>,
+	regex: Option<String>,
+	#[serde(default)]
+	providers: Vec<EmailProvider>,
+	#[serde(default)]
+	mx_suffixes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawPatterns {
+	version: u32,
+	patterns: Vec<RawPattern>,
+}
+
+#[derive(Debug)]
+enum Matcher {
+	/// A lowercase substring, possibly with the `{email}` placeholder.
+	Contains(String),
+	Regex(Regex),
+}
+
+#[derive(Debug)]
+struct Pattern {
+	category: SmtpPatternCategory,
+	matcher: Matcher,
+	providers: Vec<EmailProvider>,
+	mx_suffixes: Vec<String>,
+}
+
+impl TryFrom<RawPattern> for Pattern {
+	type Error = SmtpPatternsError;
+
+	fn try_from(raw: RawPattern) -> Result<Self, Self::Error> {
+		let matcher = match (raw.contains, raw.regex) {
+			(Some(contains), None) => Matcher::Contains(contains.to_lowercase()),
+			(None, Some(regex)) => {
+				Matcher::Regex(RegexBuilder::new(&regex).case_insensitive(true).build()?)
+			}
+			_ => return Err(SmtpPatternsError::MissingMatcher),
+		};
+
+		Ok(Self {
+			category: raw.category,
+			matcher,
+			providers: raw.providers,
+			mx_suffixes: raw
+				.mx_suffixes
+				.iter()
+				.map(|suffix| format!("{}.", suffix.trim_end_matches('.').to_lowercase()))
+				.collect(),
+		})
+	}
+}
+
+impl Pattern {
+	/// `reply` and `mx_host` must be lowercase, the latter being fully
+	/// qualified, i.e. with a trailing dot.
+	fn matches(&self, reply: &str, email: &str, mx_host: &str) -> bool {
+		if !self.providers.is_empty()
+			&& !self
+				.providers
+				.contains(&EmailProvider::from_mx_host(mx_host))
+		{
+			return false;
+		}
+		if !self.mx_suffixes.is_empty()
+			&& !self
+				.mx_suffixes
+				.iter()
+				.any(|suffix| mx_host.ends_with(suffix.as_str()))
+		{
+			return false;
+		}
+
+		match &self.matcher {
+			Matcher::Contains(s) if s.contains("{email}") => {
+				reply.contains(s.replace("{email}", email).as_str())
+			}
+			Matcher::Contains(s) => reply.contains(s.as_str()),
+			Matcher::Regex(re) => re.is_match(reply),
+		}
+	}
+}
+
+fn parse_patterns(json: &str) -> Result<Vec<Pattern>, SmtpPatternsError> {
+	let raw: RawPatterns = serde_json::from_str(json)?;
+	if raw.version != SMTP_PATTERNS_VERSION {
+		return Err(SmtpPatternsError::UnsupportedVersion(raw.version));
+	}
+
+	raw.patterns.into_iter().map(Pattern::try_from).collect()
+}
+
+static BUILTIN_PATTERNS: Lazy<Vec<Pattern>> = Lazy::new(|| {
+	parse_patterns(include_str!("patterns.json")).expect("Correct built-in patterns. qed.")
+});
+
+static USER_PATTERNS: Lazy<RwLock<Vec<Pattern>>> = Lazy::new(|| RwLock::new(vec![]));
+
+/// Load additional SMTP reply patterns from the JSON file at the given path,
+/// with the same format as the built-in `patterns.json`. They are checked
+/// after the built-in ones, and replace any previously loaded user patterns.
+pub fn load_smtp_patterns(path: impl AsRef<Path>) -> Result<usize, SmtpPatternsError> {
+	let patterns = parse_patterns(&std::fs::read_to_string(path)?)?;
+	let count = patterns.len();
+	*USER_PATTERNS
+		.write()
+		.expect("SMTP patterns lock is not poisoned. qed.") = patterns;
+
+	Ok(count)
+}
+
+/// Check if the reply of the MX host to the RCPT TO for the email matches
+/// any pattern of the given category. The reply must be lowercase.
+pub(crate) fn matches(
+	category: SmtpPatternCategory,
+	reply: &str,
+	email: &EmailAddress,
+	mx_host: &str,
+) -> bool {
+	let email = email.to_string().to_lowercase();
+	let mx_host = format!("{}.", mx_host.trim_end_matches('.').to_lowercase());
+	let is_match = |p: &Pattern| p.category == category && p.matches(reply, &email, &mx_host);
+
+	BUILTIN_PATTERNS.iter().any(is_match)
+		|| USER_PATTERNS
+			.read()
+			.expect("SMTP patterns lock is not poisoned. qed.")
+			.iter()
+			.any(is_match)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::str::FromStr;
+
+	#[test]
+	fn should_parse_builtin_patterns() {
+		assert!(!BUILTIN_PATTERNS.is_empty());
+	}
+
+	#[test]
+	fn should_scope_and_match_patterns() {
+		let patterns = parse_patterns(
+			r#"{
+				"version": 1,
+				"patterns": [
+					{ "category": "invalid", "contains": "Empfänger unbekannt" },
+					{ "category": "full_inbox", "regex": "postfach (ist )?voll", "mx_suffixes": [".gmx.net."] },
+					{ "category": "disabled", "contains": "アカウントは無効", "providers": ["gmail"] }
+				]
+			}"#,
+		)
+		.unwrap();
+
+		assert!(patterns[0].matches("550 5.1.1 empfänger unbekannt", "", "mx.example.com."));
+		assert!(patterns[1].matches("452 postfach ist voll", "", "mx00.gmx.net."));
+		assert!(!patterns[1].matches("452 postfach ist voll", "", "mx.example.com."));
+		assert!(patterns[2].matches("550 アカウントは無効です", "", "alt4.aspmx.l.google.com."));
+		assert!(!patterns[2].matches("550 アカウントは無効です", "", "mx.example.com."));
+	}
+
+	#[test]
+	fn should_substitute_email() {
+		let email = EmailAddress::from_str("Foo@bar.baz").unwrap();
+		assert!(matches(
+			SmtpPatternCategory::Invalid,
+			"permanent: 5.1.1 mxin501 mailbox foo@bar.baz unknown",
+			&email,
+			"mx.bar.baz"
+		));
+	}
+
+	#[test]
+	fn should_reject_invalid_files() {
+		assert!(matches!(
+			parse_patterns(r#"{ "version": 2, "patterns": [] }"#),
+			Err(SmtpPatternsError::UnsupportedVersion(2))
+		));
+		assert!(matches!(
+			parse_patterns(r#"{ "version": 1, "patterns": [{ "category": "invalid" }] }"#),
+			Err(SmtpPatternsError::MissingMatcher)
+		));
+		assert!(matches!(
+			parse_patterns(
+				r#"{ "version": 1, "patterns": [{ "category": "invalid", "regex": "(" }] }"#
+			),
+			Err(SmtpPatternsError::Regex(_))
+		));
+	}
+}
@@ -0,0 +1,281 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Pools of proxies, so that an email provider doesn't depend on a single
+//! proxy. Proxies failing at the proxy level, or whose IP is blacklisted by
+//! the MX host, are quarantined for a while, and the verification is tried
+//! again with another proxy of the pool. A proxy reporting that the MX host
+//! is unreachable isn't quarantined, as the other proxies would fail too.
+//!
+//! The health of the proxies is shared by all verifications of the process.
+//! It's only learnt from the verifications: proxies aren't checked in the
+//! background, so a quarantined proxy is used again once its quarantine
+//! ends, whether it recovered or not.
+
+use super::SmtpError;
+use crate::LOG_TARGET;
+use once_cell::sync::Lazy;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How to pick a proxy among the healthy proxies of a pool.
+#[derive(Debug, Default, Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxySelection {
+	/// Use each proxy in turn.
+	#[default]
+	RoundRobin,
+	/// Pick a random proxy, proportionally to its weight.
+	Weighted,
+	/// Use the proxy with the fewest errors so far.
+	LeastErrors,
+}
+
+fn default_weight() -> u32 {
+	1
+}
+
+/// A proxy of a pool.
+#[derive(Debug, Clone, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ProxyPoolMember {
+	/// The ID of the proxy in `VerifMethod.proxies`.
+	pub proxy: String,
+	/// Weight of the proxy for the weighted selection.
+	///
+	/// Defaults to 1.
+	#[serde(default = "default_weight")]
+	pub weight: u32,
+}
+
+fn default_quarantine_secs() -> u64 {
+	300
+}
+
+/// A group of proxies, which email providers can use through
+/// `VerifMethodSmtpConfig.proxy_pool`.
+#[derive(Debug, Clone, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ProxyPool {
+	/// The proxies of the pool.
+	pub proxies: Vec<ProxyPoolMember>,
+	/// How to pick a proxy in the pool.
+	///
+	/// Defaults to round-robin.
+	#[serde(default)]
+	pub selection: ProxySelection,
+	/// How long a failing proxy is left out of the pool, in seconds.
+	///
+	/// Defaults to 300.
+	#[serde(default = "default_quarantine_secs")]
+	pub quarantine_secs: u64,
+}
+
+#[derive(Debug, Default)]
+struct ProxyHealth {
+	/// Number of errors since the start of the process.
+	errors: u64,
+	quarantined_until: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct PoolsState {
+	/// Health of each proxy, by proxy ID.
+	health: HashMap<String, ProxyHealth>,
+	/// Round-robin counter of each pool, by pool ID.
+	counters: HashMap<String, usize>,
+}
+
+impl PoolsState {
+	fn errors(&self, proxy_id: &str) -> u64 {
+		self.health.get(proxy_id).map_or(0, |h| h.errors)
+	}
+
+	fn is_quarantined(&self, proxy_id: &str, now: Instant) -> bool {
+		self.health
+			.get(proxy_id)
+			.and_then(|h| h.quarantined_until)
+			.is_some_and(|until| until > now)
+	}
+}
+
+static STATE: Lazy<Mutex<PoolsState>> = Lazy::new(|| Mutex::new(PoolsState::default()));
+
+impl ProxyPool {
+	/// Pick a proxy of the pool, leaving out the quarantined ones. If all
+	/// proxies are quarantined, pick among all of them anyway. Returns the
+	/// proxy ID, or None if the pool is empty.
+	pub(crate) fn select(&self, pool_id: &str) -> Option<&String> {
+		let mut state = STATE
+			.lock()
+			.expect("Proxy pools mutex is not poisoned. qed.");
+		let now = Instant::now();
+		let healthy: Vec<&ProxyPoolMember> = self
+			.proxies
+			.iter()
+			.filter(|m| !state.is_quarantined(&m.proxy, now))
+			.collect();
+		let candidates = if healthy.is_empty() && !self.proxies.is_empty() {
+			tracing::warn!(
+				target: LOG_TARGET,
+				pool=pool_id,
+				"All proxies of the pool are quarantined, using them anyway"
+			);
+			self.proxies.iter().collect()
+		} else {
+			healthy
+		};
+		if candidates.is_empty() {
+			return None;
+		}
+
+		let member = match self.selection {
+			ProxySelection::RoundRobin => {
+				let counter = state.counters.entry(pool_id.to_string()).or_default();
+				let member = candidates[*counter % candidates.len()];
+				*counter = counter.wrapping_add(1);
+				member
+			}
+			ProxySelection::Weighted => {
+				let total: u64 = candidates.iter().map(|m| u64::from(m.weight)).sum();
+				if total == 0 {
+					candidates[0]
+				} else {
+					let mut point = rand::thread_rng().gen_range(0..total);
+					*candidates
+						.iter()
+						.find(|m| {
+							let found = point < u64::from(m.weight);
+							point = point.saturating_sub(u64::from(m.weight));
+							found
+						})
+						.expect("The point is below the total weight. qed.")
+				}
+			}
+			ProxySelection::LeastErrors => *candidates
+				.iter()
+				.min_by_key(|m| state.errors(&m.proxy))
+				.expect("There's at least one candidate. qed."),
+		};
+
+		Some(&member.proxy)
+	}
+
+	/// Record the failure of a verification through a proxy of the pool,
+	/// quarantining the proxy if the error is caused by it. Returns whether
+	/// the proxy was quarantined.
+	pub(crate) fn record_error(&self, proxy_id: &str, err: &SmtpError) -> bool {
+		if !err.is_proxy_fault() {
+			return false;
+		}
+
+		tracing::debug!(
+			target: LOG_TARGET,
+			proxy=proxy_id,
+			quarantine_secs=self.quarantine_secs,
+			error=?err,
+			"Quarantining proxy"
+		);
+		let mut state = STATE
+			.lock()
+			.expect("Proxy pools mutex is not poisoned. qed.");
+		let health = state.health.entry(proxy_id.to_string()).or_default();
+		health.errors += 1;
+		health.quarantined_until = Some(Instant::now() + Duration::from_secs(self.quarantine_secs));
+
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// The health state is global, so each test uses its own proxy IDs.
+	fn pool(ids: &[&str], selection: ProxySelection) -> ProxyPool {
+		ProxyPool {
+			proxies: ids
+				.iter()
+				.map(|id| ProxyPoolMember {
+					proxy: id.to_string(),
+					weight: 1,
+				})
+				.collect(),
+			selection,
+			quarantine_secs: 300,
+		}
+	}
+
+	fn socks_error() -> SmtpError {
+		SmtpError::Socks5(fast_socks5::SocksError::ArgumentInputError("test"))
+	}
+
+	#[test]
+	fn should_rotate_and_skip_quarantined_proxies() {
+		let pool = pool(&["rr1", "rr2", "rr3"], ProxySelection::RoundRobin);
+		let picks: Vec<&String> = (0..3).map(|_| pool.select("rr").unwrap()).collect();
+		assert_eq!(picks, vec!["rr1", "rr2", "rr3"]);
+
+		assert!(pool.record_error("rr2", &socks_error()));
+		for _ in 0..4 {
+			assert_ne!(pool.select("rr").unwrap(), "rr2");
+		}
+
+		// Errors which aren't caused by the proxy don't quarantine it.
+		assert!(!pool.record_error("rr1", &SmtpError::Timeout(Duration::from_secs(1))));
+		assert!((0..2).any(|_| pool.select("rr").unwrap() == "rr1"));
+	}
+
+	#[test]
+	fn should_not_quarantine_when_mx_host_is_down() {
+		let pool = pool(&["down1"], ProxySelection::RoundRobin);
+		let refused = SmtpError::Socks5(fast_socks5::SocksError::ReplyError(
+			fast_socks5::ReplyError::ConnectionRefused,
+		));
+		assert!(!pool.record_error("down1", &refused));
+		let bad_gateway =
+			SmtpError::HttpConnect("Proxy cannot reach the host: HTTP/1.1 502 Bad Gateway".into());
+		assert!(!pool.record_error("down1", &bad_gateway));
+
+		// But the proxy's own failures do.
+		let general = SmtpError::Socks5(fast_socks5::SocksError::ReplyError(
+			fast_socks5::ReplyError::GeneralFailure,
+		));
+		assert!(pool.record_error("down1", &general));
+	}
+
+	#[test]
+	fn should_use_quarantined_proxies_when_all_are() {
+		let pool = pool(&["all1"], ProxySelection::RoundRobin);
+		pool.record_error("all1", &socks_error());
+		assert_eq!(pool.select("all").unwrap(), "all1");
+	}
+
+	#[test]
+	fn should_pick_least_errors_and_weighted() {
+		// Without quarantine, to only test the error counts.
+		let mut least = pool(&["le1", "le2"], ProxySelection::LeastErrors);
+		least.quarantine_secs = 0;
+		assert_eq!(least.select("le").unwrap(), "le1");
+		least.record_error("le1", &socks_error());
+		assert_eq!(least.select("le").unwrap(), "le2");
+
+		let mut weighted = pool(&["w1", "w2"], ProxySelection::Weighted);
+		weighted.proxies[0].weight = 0;
+		assert!((0..10).all(|_| weighted.select("w").unwrap() == "w2"));
+	}
+}
@@ -0,0 +1,197 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! When and how to retry an SMTP verification which failed with a transient
+//! error, e.g. greylisting.
+
+use super::parser::SmtpReply;
+use super::{SmtpError, SmtpErrorDesc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Policy to retry the SMTP verification of an email on an MX host.
+#[derive(Debug, Clone, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+	/// Total number of SMTP connections to do, including the first one. If
+	/// set, it overrides `VerifMethodSmtpConfig.retries`.
+	///
+	/// Defaults to None.
+	pub max_attempts: Option<usize>,
+	/// Delays to wait before each retry, in ms: the first delay is waited
+	/// before the 2nd attempt, and so on. The last delay is repeated if
+	/// there are more retries than delays. Greylisting windows are generally
+	/// between 1 and 15 minutes.
+	///
+	/// Defaults to none, i.e. retry immediately.
+	pub backoff_ms: Vec<u64>,
+	/// The categories of errors to retry on, in snake_case (e.g.
+	/// "greylisted") or as output in `description` (e.g. "Greylisted").
+	///
+	/// Defaults to TooManyConnections, Greylisted and RateLimited.
+	pub retryable_errors: Vec<SmtpErrorDesc>,
+	/// SMTP reply codes to retry on, whatever the reply's message, e.g.
+	/// [421, 450, 451].
+	///
+	/// Defaults to none.
+	pub retryable_codes: Vec<u16>,
+	/// Whether to retry on errors we can't categorize, e.g. I/O errors.
+	/// Permanent errors, e.g. a 5xx SMTP reply, a missing STARTTLS support, or
+	/// an open circuit breaker, are never retried, unless their reply code is
+	/// in `retryable_codes`.
+	///
+	/// Defaults to true.
+	pub retry_unknown_errors: bool,
+}
+
+impl Default for RetryPolicy {
+	fn default() -> Self {
+		Self {
+			max_attempts: None,
+			backoff_ms: vec![],
+			retryable_errors: vec![
+				SmtpErrorDesc::TooManyConnections,
+				SmtpErrorDesc::Greylisted,
+				SmtpErrorDesc::RateLimited,
+			],
+			retryable_codes: vec![],
+			retry_unknown_errors: true,
+		}
+	}
+}
+
+impl RetryPolicy {
+	/// Whether the SMTP verification should be retried after this error.
+	pub fn is_retryable(&self, err: &SmtpError) -> bool {
+		if let SmtpError::AsyncSmtpError(e) = err {
+			if let Some(reply) = SmtpReply::from_error(e) {
+				if self.retryable_codes.contains(&reply.code) {
+					return true;
+				}
+			}
+		}
+
+		match err.get_description() {
+			Some(desc) => self.retryable_errors.contains(&desc),
+			None => self.retry_unknown_errors && !err.is_permanent(),
+		}
+	}
+
+	/// The delay to wait before the given retry, starting at 1 for the 2nd
+	/// attempt. Returns None if there's no delay to wait.
+	pub fn backoff(&self, retry: usize) -> Option<Duration> {
+		let index = retry
+			.saturating_sub(1)
+			.min(self.backoff_ms.len().checked_sub(1)?);
+		match self.backoff_ms[index] {
+			0 => None,
+			ms => Some(Duration::from_millis(ms)),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use async_smtp::error::Error as AsyncSmtpError;
+	use async_smtp::response::{Category, Code, Detail, Response, Severity};
+
+	fn transient_err(code: Detail, message: &str) -> SmtpError {
+		SmtpError::AsyncSmtpError(AsyncSmtpError::Transient(Response::new(
+			Code::new(
+				Severity::TransientNegativeCompletion,
+				Category::MailSystem,
+				code,
+			),
+			vec![message.to_string()],
+		)))
+	}
+
+	#[test]
+	fn should_retry_configured_errors_and_codes() {
+		let policy = RetryPolicy::default();
+		assert!(policy.is_retryable(&transient_err(
+			Detail::Zero,
+			"4.7.1 Greylisted, try again later"
+		)));
+		assert!(policy.is_retryable(&SmtpError::Timeout(Duration::from_secs(1))));
+		assert!(policy.is_retryable(&transient_err(Detail::One, "foobar")));
+		assert!(!policy.is_retryable(&transient_err(Detail::Zero, "5.7.1 IP address blacklisted")));
+		assert!(!policy.is_retryable(&SmtpError::StarttlsNotSupported));
+		assert!(!policy.is_retryable(&SmtpError::CircuitOpen("mx.example.com".into())));
+		assert!(!policy.is_retryable(&SmtpError::HttpConnect(
+			"Proxy refused the credentials: HTTP/1.1 407 Proxy Authentication Required".into()
+		)));
+		assert!(policy.is_retryable(&SmtpError::HttpConnect(
+			"Cannot connect to proxy: Connection refused".into()
+		)));
+
+		let policy = RetryPolicy {
+			retryable_errors: vec![],
+			retryable_codes: vec![451],
+			retry_unknown_errors: false,
+			..Default::default()
+		};
+		assert!(!policy.is_retryable(&transient_err(
+			Detail::Zero,
+			"4.7.1 Greylisted, try again later"
+		)));
+		assert!(policy.is_retryable(&transient_err(Detail::One, "Greylisted")));
+		assert!(!policy.is_retryable(&SmtpError::Timeout(Duration::from_secs(1))));
+	}
+
+	#[test]
+	fn should_repeat_last_backoff() {
+		let policy = RetryPolicy::default();
+		assert_eq!(policy.backoff(1), None);
+
+		let policy = RetryPolicy {
+			backoff_ms: vec![0, 60_000, 300_000],
+			..Default::default()
+		};
+		assert_eq!(policy.backoff(1), None);
+		assert_eq!(policy.backoff(2), Some(Duration::from_secs(60)));
+		assert_eq!(policy.backoff(3), Some(Duration::from_secs(300)));
+		assert_eq!(policy.backoff(10), Some(Duration::from_secs(300)));
+	}
+
+	#[test]
+	fn should_deserialize_both_error_spellings() {
+		let errors: Vec<SmtpErrorDesc> =
+			serde_json::from_str(r#"["greylisted", "needs_rdns", "Greylisted", "NeedsRDNS"]"#)
+				.unwrap();
+		assert_eq!(
+			errors,
+			vec![
+				SmtpErrorDesc::Greylisted,
+				SmtpErrorDesc::NeedsRDNS,
+				SmtpErrorDesc::Greylisted,
+				SmtpErrorDesc::NeedsRDNS
+			]
+		);
+
+		// The output round-trips.
+		let output = serde_json::to_string(&errors).unwrap();
+		assert_eq!(
+			output,
+			r#"["Greylisted","NeedsRDNS","Greylisted","NeedsRDNS"]"#
+		);
+		assert_eq!(
+			serde_json::from_str::<Vec<SmtpErrorDesc>>(&output).unwrap(),
+			errors
+		);
+	}
+}
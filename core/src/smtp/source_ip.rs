@@ -0,0 +1,234 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Bind outbound SMTP connections to specific local IP addresses.
+
+use super::verif_method::VerifMethodSmtpConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
+
+/// How to pick the source IP among `VerifMethodSmtpConfig.source_ips`.
+#[derive(Debug, Default, Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceIpSelection {
+	/// Use each source IP in turn.
+	#[default]
+	RoundRobin,
+	/// Always use the same source IP for a given email domain.
+	StickyPerDomain,
+}
+
+/// Which IP version to try first when the MX host has both IPv4 and IPv6
+/// addresses.
+#[derive(Debug, Default, Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpVersionPreference {
+	/// Try the addresses in the order returned by the resolver.
+	#[default]
+	Any,
+	/// Try the IPv4 addresses first.
+	Ipv4,
+	/// Try the IPv6 addresses first.
+	Ipv6,
+}
+
+impl IpVersionPreference {
+	fn prefers(&self, ip: &IpAddr) -> bool {
+		match self {
+			Self::Any => true,
+			Self::Ipv4 => ip.is_ipv4(),
+			Self::Ipv6 => ip.is_ipv6(),
+		}
+	}
+}
+
+static ROUND_ROBIN_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Pick a source IP of the same version as the destination IP, or None if
+/// there's none.
+fn pick_source_ip(
+	source_ips: &[IpAddr],
+	selection: SourceIpSelection,
+	domain: &str,
+	destination: &IpAddr,
+) -> Option<IpAddr> {
+	let candidates: Vec<&IpAddr> = source_ips
+		.iter()
+		.filter(|ip| ip.is_ipv4() == destination.is_ipv4())
+		.collect();
+	if candidates.is_empty() {
+		return None;
+	}
+
+	let index = match selection {
+		SourceIpSelection::RoundRobin => ROUND_ROBIN_COUNTER.fetch_add(1, Ordering::Relaxed),
+		SourceIpSelection::StickyPerDomain => {
+			let mut hasher = DefaultHasher::new();
+			domain.to_lowercase().hash(&mut hasher);
+			hasher.finish() as usize
+		}
+	};
+
+	Some(*candidates[index % candidates.len()])
+}
+
+async fn connect_from(
+	destination: SocketAddr,
+	source_ip: Option<IpAddr>,
+) -> Result<TcpStream, io::Error> {
+	let socket = match destination {
+		SocketAddr::V4(_) => TcpSocket::new_v4()?,
+		SocketAddr::V6(_) => TcpSocket::new_v6()?,
+	};
+	if let Some(source_ip) = source_ip {
+		socket.bind(SocketAddr::new(source_ip, 0))?;
+	}
+
+	socket.connect(destination).await
+}
+
+/// Open a TCP connection to the MX host, from one of the configured source
+/// IPs if any. The host should not end with a '.'. Returns the stream and
+/// the source IP it's bound to.
+pub(crate) async fn connect_tcp(
+	host: &str,
+	port: u16,
+	domain: &str,
+	config: &VerifMethodSmtpConfig,
+) -> Result<(TcpStream, Option<IpAddr>), io::Error> {
+	if config.source_ips.is_empty() && config.ip_version_preference == IpVersionPreference::Any {
+		let stream = TcpStream::connect(format!("{}:{}", host, port)).await?;
+		return Ok((stream, None));
+	}
+
+	let mut destinations: Vec<SocketAddr> = lookup_host((host, port)).await?.collect();
+	// Stable sort, so that the resolver order is kept within each version.
+	destinations.sort_by_key(|addr| !config.ip_version_preference.prefers(&addr.ip()));
+
+	let mut last_err = None;
+	for destination in destinations {
+		let source_ip = if config.source_ips.is_empty() {
+			None
+		} else {
+			match pick_source_ip(
+				&config.source_ips,
+				config.source_ip_selection,
+				domain,
+				&destination.ip(),
+			) {
+				Some(ip) => Some(ip),
+				// No source IP of this version, try the next address.
+				None => continue,
+			}
+		};
+
+		match connect_from(destination, source_ip).await {
+			Ok(stream) => return Ok((stream, source_ip)),
+			Err(err) => last_err = Some(err),
+		}
+	}
+
+	Err(last_err.unwrap_or_else(|| {
+		io::Error::new(
+			io::ErrorKind::AddrNotAvailable,
+			format!(
+				"No source IP of the same version as the addresses of {}",
+				host
+			),
+		)
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::str::FromStr;
+
+	fn ip(s: &str) -> IpAddr {
+		IpAddr::from_str(s).unwrap()
+	}
+
+	#[test]
+	fn should_pick_source_ip_of_same_version() {
+		let source_ips = vec![ip("192.0.2.1"), ip("2001:db8::1")];
+
+		assert_eq!(
+			pick_source_ip(
+				&source_ips,
+				SourceIpSelection::RoundRobin,
+				"",
+				&ip("198.51.100.1")
+			),
+			Some(ip("192.0.2.1"))
+		);
+		assert_eq!(
+			pick_source_ip(
+				&source_ips,
+				SourceIpSelection::RoundRobin,
+				"",
+				&ip("2001:db8::25")
+			),
+			Some(ip("2001:db8::1"))
+		);
+		assert_eq!(
+			pick_source_ip(
+				&source_ips[..1],
+				SourceIpSelection::RoundRobin,
+				"",
+				&ip("2001:db8::25")
+			),
+			None
+		);
+	}
+
+	#[test]
+	fn should_stick_to_source_ip_per_domain() {
+		let source_ips = vec![ip("192.0.2.1"), ip("192.0.2.2"), ip("192.0.2.3")];
+		let destination = ip("198.51.100.1");
+		let pick = |domain| {
+			pick_source_ip(
+				&source_ips,
+				SourceIpSelection::StickyPerDomain,
+				domain,
+				&destination,
+			)
+		};
+
+		assert_eq!(pick("gmail.com"), pick("GMAIL.com"));
+		assert_eq!(pick("yahoo.com"), pick("yahoo.com"));
+	}
+
+	#[tokio::test]
+	async fn should_bind_to_source_ip() {
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let config = VerifMethodSmtpConfig {
+			source_ips: vec![ip("127.0.0.1")],
+			..Default::default()
+		};
+
+		let (stream, source_ip) = connect_tcp("127.0.0.1", port, "example.com", &config)
+			.await
+			.unwrap();
+		assert_eq!(source_ip, Some(ip("127.0.0.1")));
+		assert_eq!(stream.local_addr().unwrap().ip(), ip("127.0.0.1"));
+	}
+}
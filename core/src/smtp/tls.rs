@@ -0,0 +1,162 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Encrypt the SMTP connection with TLS.
+
+use once_cell::sync::Lazy;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+	ClientConfig, DigitallySignedStruct, ProtocolVersion, RootCertStore, SignatureScheme,
+};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+/// Whether to upgrade the SMTP connection to TLS with STARTTLS.
+#[derive(Debug, Default, Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StarttlsMode {
+	/// Never use STARTTLS.
+	#[default]
+	Disabled,
+	/// Use STARTTLS if the server advertises it, otherwise, or if the server
+	/// refuses it, continue in plaintext.
+	Opportunistic,
+	/// Use STARTTLS, and fail if the server doesn't advertise it.
+	Required,
+}
+
+/// Details about the TLS encryption of the SMTP connection.
+#[derive(Debug, Default, Clone, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SmtpTlsDetails {
+	/// Whether the SMTP connection was encrypted with TLS.
+	pub negotiated: bool,
+	/// The negotiated TLS version, e.g. "TLSv1.3".
+	pub protocol_version: Option<String>,
+	/// Whether the server's certificate is signed by a trusted authority and
+	/// valid for the MX host. None if TLS wasn't negotiated.
+	pub certificate_valid: Option<bool>,
+}
+
+static ROOT_CERTS: Lazy<Arc<RootCertStore>> = Lazy::new(|| {
+	Arc::new(RootCertStore {
+		roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+	})
+});
+
+/// A certificate verifier which records whether the certificate is valid,
+/// but accepts it anyway. Many MX hosts use self-signed or mismatched
+/// certificates, and RFC 3207 lets the sender deliver anyway, so we only
+/// report the validity.
+#[derive(Debug)]
+struct RecordingVerifier {
+	inner: Arc<WebPkiServerVerifier>,
+	is_valid: AtomicBool,
+}
+
+impl ServerCertVerifier for RecordingVerifier {
+	fn verify_server_cert(
+		&self,
+		end_entity: &CertificateDer<'_>,
+		intermediates: &[CertificateDer<'_>],
+		server_name: &ServerName<'_>,
+		ocsp_response: &[u8],
+		now: UnixTime,
+	) -> Result<ServerCertVerified, rustls::Error> {
+		let is_valid = self
+			.inner
+			.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+			.is_ok();
+		self.is_valid.store(is_valid, Ordering::SeqCst);
+
+		Ok(ServerCertVerified::assertion())
+	}
+
+	fn verify_tls12_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		self.inner.verify_tls12_signature(message, cert, dss)
+	}
+
+	fn verify_tls13_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		self.inner.verify_tls13_signature(message, cert, dss)
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+		self.inner.supported_verify_schemes()
+	}
+}
+
+fn format_protocol_version(version: ProtocolVersion) -> String {
+	match version {
+		ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+		ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+		v => format!("{:?}", v),
+	}
+}
+
+/// Perform the TLS handshake with the MX host over the given stream. The
+/// host should not end with a '.'.
+pub(crate) async fn connect_tls<S: AsyncRead + AsyncWrite + Unpin>(
+	stream: S,
+	host: &str,
+) -> Result<(TlsStream<S>, SmtpTlsDetails), io::Error> {
+	crate::initialize_crypto_provider();
+
+	let inner = WebPkiServerVerifier::builder(Arc::clone(&ROOT_CERTS))
+		.build()
+		.map_err(io::Error::other)?;
+	let verifier = Arc::new(RecordingVerifier {
+		inner,
+		is_valid: AtomicBool::new(false),
+	});
+	let config = ClientConfig::builder()
+		.dangerous()
+		.with_custom_certificate_verifier(Arc::clone(&verifier) as Arc<dyn ServerCertVerifier>)
+		.with_no_client_auth();
+	let server_name = ServerName::try_from(host.to_string())
+		.map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+	let tls_stream = TlsConnector::from(Arc::new(config))
+		.connect(server_name, stream)
+		.await?;
+	let details = SmtpTlsDetails {
+		negotiated: true,
+		protocol_version: tls_stream
+			.get_ref()
+			.1
+			.protocol_version()
+			.map(format_protocol_version),
+		certificate_valid: Some(verifier.is_valid.load(Ordering::SeqCst)),
+	};
+
+	Ok((tls_stream, details))
+}
@@ -0,0 +1,275 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Record the SMTP conversation with the server, for debugging.
+
+use super::parser::EnhancedStatusCode;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Configuration of the SMTP transcript.
+#[derive(Debug, Default, Clone, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct SmtpTranscriptConfig {
+	/// Whether to add the SMTP conversation to the debug output.
+	///
+	/// Defaults to false.
+	pub enable: bool,
+	/// Whether to replace the local part of all email addresses in the
+	/// transcript with "***".
+	///
+	/// Defaults to false.
+	pub redact: bool,
+}
+
+/// One step of the SMTP conversation: a command we sent, and the server's
+/// reply to it.
+#[derive(Debug, Clone, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SmtpTranscriptEntry {
+	/// The command we sent, e.g. "RCPT TO:<foo@bar.com>". None for the
+	/// server's greeting.
+	pub command: Option<String>,
+	/// The 3-digit reply code, or None if the server didn't reply.
+	pub code: Option<u16>,
+	/// The RFC 3463 enhanced status code, e.g. "5.1.1", if any.
+	pub enhanced_code: Option<String>,
+	/// The text of the reply, with multiline replies joined by "; ".
+	pub text: String,
+	/// Time between sending the command and receiving the full reply.
+	pub latency: Duration,
+}
+
+static EMAIL_LOCAL_PART: Lazy<Regex> =
+	Lazy::new(|| Regex::new(r#"[^\s<>:;,"'()\[\]]+@"#).expect("Correct regex. qed."));
+
+impl SmtpTranscriptEntry {
+	/// Replace the local part of all email addresses with "***".
+	pub fn redact(&mut self) {
+		if let Some(command) = &self.command {
+			self.command = Some(EMAIL_LOCAL_PART.replace_all(command, "***@").into_owned());
+		}
+		self.text = EMAIL_LOCAL_PART
+			.replace_all(&self.text, "***@")
+			.into_owned();
+	}
+}
+
+/// Builds the transcript entries from the raw bytes sent and received.
+#[derive(Debug)]
+pub(crate) struct TranscriptRecorder {
+	/// Bytes written, not yet forming a full line.
+	written: Vec<u8>,
+	/// Bytes read, not yet forming a full line.
+	read: Vec<u8>,
+	/// The last command sent, and when.
+	command: Option<String>,
+	sent_at: Instant,
+	/// The lines of the current, possibly multiline, reply.
+	lines: Vec<String>,
+	/// The lines of the last complete reply.
+	last_reply: Vec<String>,
+	entries: Vec<SmtpTranscriptEntry>,
+}
+
+impl TranscriptRecorder {
+	pub fn new() -> Self {
+		Self {
+			written: vec![],
+			read: vec![],
+			command: None,
+			// The greeting's latency is counted from the connection.
+			sent_at: Instant::now(),
+			lines: vec![],
+			last_reply: vec![],
+			entries: vec![],
+		}
+	}
+
+	fn on_write(&mut self, bytes: &[u8]) {
+		self.written.extend_from_slice(bytes);
+		while let Some(line) = take_line(&mut self.written) {
+			self.command = Some(line);
+			self.sent_at = Instant::now();
+		}
+	}
+
+	fn on_read(&mut self, bytes: &[u8]) {
+		self.read.extend_from_slice(bytes);
+		while let Some(line) = take_line(&mut self.read) {
+			// The last line of a reply has a space (or nothing) after the
+			// code, the others have a dash.
+			let is_last = line.as_bytes().get(3) != Some(&b'-');
+			self.lines.push(line);
+			if is_last {
+				self.finish_entry();
+			}
+		}
+	}
+
+	fn finish_entry(&mut self) {
+		let lines = std::mem::take(&mut self.lines);
+		let code = lines
+			.first()
+			.and_then(|line| line.get(..3))
+			.and_then(|code| code.parse::<u16>().ok());
+		let text = lines
+			.iter()
+			.map(|line| line.get(4..).unwrap_or_default())
+			.collect::<Vec<_>>()
+			.join("; ");
+		let enhanced_code = EnhancedStatusCode::parse_prefix(&text).map(|code| code.to_string());
+
+		self.entries.push(SmtpTranscriptEntry {
+			command: self.command.take(),
+			code,
+			enhanced_code,
+			text,
+			latency: self.sent_at.elapsed(),
+		});
+		self.last_reply = lines;
+	}
+
+	/// The raw lines of the last complete reply, e.g. to read the extensions
+	/// advertised in reply to EHLO.
+	pub fn last_reply(&self) -> &[String] {
+		&self.last_reply
+	}
+
+	/// Take the recorded entries. A command still waiting for its reply,
+	/// e.g. because of a timeout, is returned without a code.
+	pub fn drain(&mut self) -> Vec<SmtpTranscriptEntry> {
+		if self.command.is_some() {
+			self.finish_entry();
+		}
+		std::mem::take(&mut self.entries)
+	}
+}
+
+/// Take the first full line out of the buffer, without its line ending.
+fn take_line(buffer: &mut Vec<u8>) -> Option<String> {
+	let end = buffer.iter().position(|b| *b == b'\n')?;
+	let line: Vec<u8> = buffer.drain(..=end).collect();
+
+	Some(
+		String::from_utf8_lossy(&line)
+			.trim_end_matches(&['\r', '\n'][..])
+			.to_string(),
+	)
+}
+
+/// A stream which records the SMTP conversation going through it.
+pub(crate) struct TranscriptStream<S> {
+	inner: S,
+	recorder: Arc<Mutex<TranscriptRecorder>>,
+}
+
+impl<S> TranscriptStream<S> {
+	pub fn new(inner: S, recorder: Arc<Mutex<TranscriptRecorder>>) -> Self {
+		Self { inner, recorder }
+	}
+
+	/// Stop recording, e.g. to wrap the stream in TLS.
+	pub fn into_inner(self) -> S {
+		self.inner
+	}
+
+	fn recorder(&self) -> std::sync::MutexGuard<'_, TranscriptRecorder> {
+		self.recorder
+			.lock()
+			.expect("Transcript mutex is not poisoned. qed.")
+	}
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TranscriptStream<S> {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let before = buf.filled().len();
+		let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+		if let Poll::Ready(Ok(())) = poll {
+			self.recorder().on_read(&buf.filled()[before..]);
+		}
+
+		poll
+	}
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TranscriptStream<S> {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+		if let Poll::Ready(Ok(n)) = poll {
+			self.recorder().on_write(&buf[..n]);
+		}
+
+		poll
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_shutdown(cx)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn should_record_conversation() {
+		let mut recorder = TranscriptRecorder::new();
+		recorder.on_read(b"220 mx.example.com ESMTP\r\n");
+		recorder.on_write(b"EHLO gmail.com\r\n");
+		recorder.on_read(b"250-mx.example.com\r\n250-SIZE 1000");
+		recorder.on_read(b"\r\n250 8BITMIME\r\n");
+		recorder.on_write(b"RCPT TO:<foo.bar@example.com>\r\n");
+		recorder.on_read(b"550 5.1.1 <foo.bar@example.com> User unknown\r\n");
+		recorder.on_write(b"QUIT\r\n");
+
+		let mut entries = recorder.drain();
+		assert_eq!(entries.len(), 4);
+		assert_eq!(entries[0].command, None);
+		assert_eq!(entries[0].code, Some(220));
+		assert_eq!(entries[1].text, "mx.example.com; SIZE 1000; 8BITMIME");
+		assert_eq!(entries[2].code, Some(550));
+		assert_eq!(entries[2].enhanced_code.as_deref(), Some("5.1.1"));
+		// QUIT never got a reply.
+		assert_eq!(entries[3].command.as_deref(), Some("QUIT"));
+		assert_eq!(entries[3].code, None);
+
+		entries[2].redact();
+		assert_eq!(
+			entries[2].command.as_deref(),
+			Some("RCPT TO:<***@example.com>")
+		);
+		assert_eq!(entries[2].text, "5.1.1 <***@example.com> User unknown");
+	}
+}
@@ -16,15 +16,18 @@
 
 //! TODO: This will ultimately be moved to core.
 
-use std::{collections::HashMap, time::Duration};
+use std::{collections::HashMap, net::IpAddr, time::Duration};
 
 use crate::{
 	mx::{is_mimecast, is_proofpoint},
-	util::input_output::CheckEmailInputProxy,
+	util::input_output::{CheckEmailInputProxy, ProxyType},
 };
 use serde::{Deserialize, Serialize};
 
-use super::{is_gmail, is_hotmail_b2b, is_hotmail_b2c, is_yahoo};
+use super::{
+	is_gmail, is_hotmail_b2b, is_hotmail_b2c, is_yahoo, CircuitBreakerConfig, IpVersionPreference,
+	ProxyPool, RetryPolicy, SourceIpSelection, StarttlsMode,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum VerifMethodError {
@@ -35,6 +38,8 @@ pub enum VerifMethodError {
 /// Reacher categorizes each email into one of the following email providers.
 /// This is used to determine the verification method to use for each email
 /// provider.
+#[derive(Debug, Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum EmailProvider {
 	Gmail,
 	HotmailB2B,
@@ -42,6 +47,7 @@ pub enum EmailProvider {
 	Proofpoint,
 	Mimecast,
 	Yahoo,
+	#[serde(rename = "everything_else")]
 	EverythingElse,
 }
 
@@ -80,6 +86,14 @@ pub struct VerifMethod {
 	/// the proxy, and the value is the proxy itself. For names, we recommend
 	/// "proxy1", "proxy2", etc.
 	pub proxies: HashMap<ProxyID, CheckEmailInputProxy>,
+	/// Pools of proxies to use for email verification, referenced by
+	/// `VerifMethodSmtpConfig.proxy_pool`. The key is any unique name for the
+	/// pool, and its members are IDs of `proxies`.
+	pub proxy_pools: HashMap<String, ProxyPool>,
+	/// Proxy to use for each HTTP API, by API name: "yahoo", "gmail",
+	/// "microsoft365", "haveibeenpwned" or "gravatar". The value is the ID of
+	/// a proxy in `proxies`.
+	pub http_proxies: HashMap<String, ProxyID>,
 	/// Verification method for Gmail.
 	pub gmail: GmailVerifMethod,
 	/// Verification method for Hotmail B2B.
@@ -125,6 +139,7 @@ impl VerifMethod {
 				smtp_port,
 				smtp_timeout,
 				retries,
+				..Default::default()
 			}),
 			hotmailb2b: HotmailB2BVerifMethod::Smtp(VerifMethodSmtpConfig {
 				proxy: proxy_id.clone(),
@@ -133,6 +148,7 @@ impl VerifMethod {
 				smtp_port,
 				smtp_timeout,
 				retries,
+				..Default::default()
 			}),
 			mimecast: MimecastVerifMethod::Smtp(VerifMethodSmtpConfig {
 				proxy: proxy_id.clone(),
@@ -141,6 +157,7 @@ impl VerifMethod {
 				smtp_port,
 				smtp_timeout,
 				retries,
+				..Default::default()
 			}),
 			proofpoint: ProofpointVerifMethod::Smtp(VerifMethodSmtpConfig {
 				proxy: proxy_id.clone(),
@@ -149,6 +166,7 @@ impl VerifMethod {
 				smtp_port,
 				smtp_timeout,
 				retries,
+				..Default::default()
 			}),
 			everything_else: EverythingElseVerifMethod::Smtp(VerifMethodSmtpConfig {
 				proxy: proxy_id,
@@ -157,12 +175,72 @@ impl VerifMethod {
 				smtp_port,
 				smtp_timeout,
 				retries,
+				..Default::default()
 			}),
 			..Default::default()
 		}
 	}
 
 	pub fn validate_proxies(&self) -> Result<(), VerifMethodError> {
+		for (proxy_id, proxy) in &self.proxies {
+			if proxy.proxy_type == ProxyType::HttpConnect {
+				match (&proxy.username, &proxy.password) {
+					(Some(_), None) | (None, Some(_)) => {
+						return Err(VerifMethodError::InvalidProxies(format!(
+							"HTTP CONNECT proxy {proxy_id} needs both a username and a password"
+						)));
+					}
+					// Basic auth separates the username from the password
+					// with ':' (RFC 7617).
+					(Some(username), Some(_)) if username.contains(':') => {
+						return Err(VerifMethodError::InvalidProxies(format!(
+							"Username of HTTP CONNECT proxy {proxy_id} cannot contain ':'"
+						)));
+					}
+					_ => {}
+				}
+			}
+		}
+
+		for (pool_id, pool) in &self.proxy_pools {
+			if pool.proxies.is_empty() {
+				return Err(VerifMethodError::InvalidProxies(format!(
+					"Proxy pool {pool_id} is empty"
+				)));
+			}
+			if let Some(member) = pool
+				.proxies
+				.iter()
+				.find(|m| !self.proxies.contains_key(&m.proxy))
+			{
+				return Err(VerifMethodError::InvalidProxies(format!(
+					"Invalid proxy {} in proxy pool {pool_id}",
+					member.proxy
+				)));
+			}
+		}
+
+		for (api_name, proxy_id) in &self.http_proxies {
+			self.proxies.get(proxy_id).ok_or_else(|| {
+				VerifMethodError::InvalidProxies(format!(
+					"Invalid proxy {proxy_id} for the {api_name} HTTP API"
+				))
+			})?;
+		}
+
+		for c in self.smtp_configs() {
+			if let Some(pool_id) = &c.proxy_pool {
+				if c.proxy.is_some() {
+					return Err(VerifMethodError::InvalidProxies(format!(
+						"Cannot set both a proxy and the proxy pool {pool_id}"
+					)));
+				}
+				self.proxy_pools.get(pool_id).ok_or_else(|| {
+					VerifMethodError::InvalidProxies(format!("Invalid proxy pool {pool_id}"))
+				})?;
+			}
+		}
+
 		match &self.gmail {
 			GmailVerifMethod::Smtp(c) => {
 				if let Some(proxy_id) = &c.proxy {
@@ -218,6 +296,44 @@ impl VerifMethod {
 		Ok(())
 	}
 
+	/// All the SMTP configurations of the email providers.
+	fn smtp_configs(&self) -> Vec<&VerifMethodSmtpConfig> {
+		let mut configs = vec![];
+		let GmailVerifMethod::Smtp(c) = &self.gmail;
+		configs.push(c);
+		let HotmailB2BVerifMethod::Smtp(c) = &self.hotmailb2b;
+		configs.push(c);
+		if let HotmailB2CVerifMethod::Smtp(c) = &self.hotmailb2c {
+			configs.push(c);
+		}
+		let MimecastVerifMethod::Smtp(c) = &self.mimecast;
+		configs.push(c);
+		let ProofpointVerifMethod::Smtp(c) = &self.proofpoint;
+		configs.push(c);
+		if let YahooVerifMethod::Smtp(c) = &self.yahoo {
+			configs.push(c);
+		}
+		let EverythingElseVerifMethod::Smtp(c) = &self.everything_else;
+		configs.push(c);
+
+		configs
+	}
+
+	/// Select the proxy to use with the given SMTP configuration, i.e. either
+	/// its `proxy`, or a healthy proxy of its `proxy_pool`. Returns the proxy
+	/// along with its ID.
+	pub fn select_proxy(
+		&self,
+		config: &VerifMethodSmtpConfig,
+	) -> Option<(&ProxyID, &CheckEmailInputProxy)> {
+		let proxy_id = match &config.proxy_pool {
+			Some(pool_id) => self.proxy_pools.get(pool_id)?.select(pool_id)?,
+			None => config.proxy.as_ref()?,
+		};
+
+		self.proxies.get_key_value(proxy_id)
+	}
+
 	/// Get the proxy to use for the email provider. If there is a
 	/// configuration error, such as an unconfigured proxy, this will return
 	/// an error.
@@ -308,7 +424,7 @@ pub enum HotmailB2CVerifMethod {
 	#[default]
 	Headless,
 	/// Use Hotmail's SMTP servers to check if an email exists.
-	Smtp(VerifMethodSmtpConfig),
+	Smtp(Box<VerifMethodSmtpConfig>),
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq, Serialize)]
@@ -351,7 +467,7 @@ pub enum YahooVerifMethod {
 	#[default]
 	Headless,
 	/// Use Yahoo's SMTP servers to check if an email exists.
-	Smtp(VerifMethodSmtpConfig),
+	Smtp(Box<VerifMethodSmtpConfig>),
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq, Serialize)]
@@ -385,13 +501,24 @@ pub struct VerifMethodSmtpConfig {
 	/// testing purposes. You should set this to a domain you own.
 	pub hello_name: String,
 	/// Use a proxy to check if an email exists. This proxy must exist in the
-	/// `VerifMethod.proxies` field, and it must be a SOCKS5 proxy.
+	/// `VerifMethod.proxies` field.
 	pub proxy: Option<ProxyID>,
+	/// Use a proxy of a pool to check if an email exists, instead of a single
+	/// `proxy`. This pool must exist in the `VerifMethod.proxy_pools` field.
+	///
+	/// Defaults to none.
+	pub proxy_pool: Option<String>,
 	/// SMTP port to use for email validation. Generally, ports 25, 465, 587
 	/// and 2525 are used.
 	///
 	/// Defaults to 25.
 	pub smtp_port: u16,
+	/// Other SMTP ports to try, in order, when connecting to `smtp_port`
+	/// fails at the connection level (timeout, refused connection...), e.g.
+	/// [587, 2525].
+	///
+	/// Defaults to none.
+	pub fallback_smtp_ports: Vec<u16>,
 	/// Add timeout for the SMTP verification step. Set to None if you don't
 	/// want to use a timeout. This timeout is per SMTP connection. For
 	/// instance, if you set the number of retries to 2, then the total time
@@ -407,6 +534,43 @@ pub struct VerifMethodSmtpConfig {
 	///
 	/// Defaults to 1.
 	pub retries: usize,
+	/// When and how to retry the SMTP connection on transient errors, e.g.
+	/// greylisting.
+	///
+	/// Defaults to retrying immediately on unknown errors, too many
+	/// connections, greylisting and rate limiting.
+	pub retry_policy: RetryPolicy,
+	/// Stop connecting to an MX host for a while after too many consecutive
+	/// connection or rate limiting failures on it. Set to None to not use a
+	/// circuit breaker.
+	///
+	/// Defaults to None.
+	pub circuit_breaker: Option<CircuitBreakerConfig>,
+	/// Whether to upgrade the connection to TLS with STARTTLS.
+	///
+	/// Defaults to disabled.
+	pub starttls: StarttlsMode,
+	/// Whether to encrypt the connection with TLS before the SMTP greeting,
+	/// as SMTPS servers on port 465 expect. If set, `starttls` is ignored.
+	///
+	/// Defaults to false.
+	pub implicit_tls: bool,
+	/// Local IP addresses to bind outbound SMTP connections to, e.g. the
+	/// public IPs of the host with a proper reverse DNS. A source IP is only
+	/// used for MX addresses of the same IP version. Ignored when using a
+	/// proxy.
+	///
+	/// Defaults to none, i.e. the OS picks the source IP.
+	pub source_ips: Vec<IpAddr>,
+	/// How to pick the source IP among `source_ips`.
+	///
+	/// Defaults to round-robin.
+	pub source_ip_selection: SourceIpSelection,
+	/// Which IP version to try first when the MX host has both IPv4 and
+	/// IPv6 addresses.
+	///
+	/// Defaults to any, i.e. the resolver order.
+	pub ip_version_preference: IpVersionPreference,
 }
 
 impl Default for VerifMethodSmtpConfig {
@@ -415,9 +579,18 @@ impl Default for VerifMethodSmtpConfig {
 			from_email: "reacher@gmail.com".to_string(),
 			hello_name: "gmail.com".to_string(),
 			proxy: None,
+			proxy_pool: None,
 			smtp_port: 25,
+			fallback_smtp_ports: vec![],
 			smtp_timeout: None,
 			retries: 1,
+			retry_policy: RetryPolicy::default(),
+			circuit_breaker: None,
+			starttls: StarttlsMode::Disabled,
+			implicit_tls: false,
+			source_ips: vec![],
+			source_ip_selection: SourceIpSelection::RoundRobin,
+			ip_version_preference: IpVersionPreference::Any,
 		}
 	}
 }
@@ -428,6 +601,14 @@ pub struct VerifMethodSmtp {
 	pub proxy: Option<CheckEmailInputProxy>,
 }
 
+impl VerifMethodSmtpConfig {
+	/// Total number of SMTP connections to do on an MX host, taking the
+	/// retry policy into account.
+	pub fn max_attempts(&self) -> usize {
+		self.retry_policy.max_attempts.unwrap_or(self.retries)
+	}
+}
+
 impl VerifMethodSmtp {
 	pub fn new(config: VerifMethodSmtpConfig, proxy: Option<CheckEmailInputProxy>) -> Self {
 		Self { config, proxy }
@@ -437,6 +618,7 @@ impl VerifMethodSmtp {
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::smtp::ProxyPoolMember;
 
 	#[test]
 	fn test_validate_proxies() {
@@ -467,20 +649,94 @@ mod tests {
 				proxy: Some("proxy2".to_string()),
 				..Default::default()
 			}),
-			hotmailb2c: HotmailB2CVerifMethod::Smtp(VerifMethodSmtpConfig {
+			hotmailb2c: HotmailB2CVerifMethod::Smtp(Box::new(VerifMethodSmtpConfig {
 				proxy: Some("proxy1".to_string()),
 				..Default::default()
-			}),
-			yahoo: YahooVerifMethod::Smtp(VerifMethodSmtpConfig {
+			})),
+			yahoo: YahooVerifMethod::Smtp(Box::new(VerifMethodSmtpConfig {
 				proxy: Some("proxy2".to_string()),
 				..Default::default()
-			}),
+			})),
 			..Default::default()
 		};
 
 		assert!(verif_method.validate_proxies().is_ok());
 	}
 
+	#[test]
+	fn test_validate_proxy_pools() {
+		let mut proxies = HashMap::new();
+		proxies.insert("proxy1".to_string(), CheckEmailInputProxy::default());
+		let pool = |member: &str| ProxyPool {
+			proxies: vec![ProxyPoolMember {
+				proxy: member.to_string(),
+				weight: 1,
+			}],
+			selection: Default::default(),
+			quarantine_secs: 300,
+		};
+		let verif_method = |member: &str, proxy_pool: &str, proxy: Option<&str>| {
+			let mut proxy_pools = HashMap::new();
+			proxy_pools.insert("pool1".to_string(), pool(member));
+			VerifMethod {
+				proxies: proxies.clone(),
+				proxy_pools,
+				gmail: GmailVerifMethod::Smtp(VerifMethodSmtpConfig {
+					proxy: proxy.map(str::to_string),
+					proxy_pool: Some(proxy_pool.to_string()),
+					..Default::default()
+				}),
+				..Default::default()
+			}
+		};
+
+		let valid = verif_method("proxy1", "pool1", None);
+		assert!(valid.validate_proxies().is_ok());
+		let GmailVerifMethod::Smtp(config) = &valid.gmail;
+		assert_eq!(
+			valid.select_proxy(config).map(|(id, _)| id.as_str()),
+			Some("proxy1")
+		);
+
+		assert!(verif_method("proxy2", "pool1", None)
+			.validate_proxies()
+			.is_err());
+		assert!(verif_method("proxy1", "pool2", None)
+			.validate_proxies()
+			.is_err());
+		assert!(verif_method("proxy1", "pool1", Some("proxy1"))
+			.validate_proxies()
+			.is_err());
+	}
+
+	#[test]
+	fn test_validate_http_connect_proxies() {
+		let proxy = |username: Option<&str>, password: Option<&str>| {
+			let mut proxies = HashMap::new();
+			proxies.insert(
+				"proxy1".to_string(),
+				CheckEmailInputProxy {
+					proxy_type: ProxyType::HttpConnect,
+					username: username.map(str::to_string),
+					password: password.map(str::to_string),
+					..Default::default()
+				},
+			);
+			VerifMethod {
+				proxies,
+				..Default::default()
+			}
+		};
+
+		assert!(proxy(None, None).validate_proxies().is_ok());
+		assert!(proxy(Some("user"), Some("pass")).validate_proxies().is_ok());
+		assert!(proxy(Some("user"), None).validate_proxies().is_err());
+		assert!(proxy(None, Some("pass")).validate_proxies().is_err());
+		assert!(proxy(Some("us:er"), Some("pass"))
+			.validate_proxies()
+			.is_err());
+	}
+
 	#[test]
 	fn test_get_proxy() {
 		let mut proxies = HashMap::new();
@@ -497,14 +753,14 @@ mod tests {
 				proxy: Some("proxy2".to_string()),
 				..Default::default()
 			}),
-			hotmailb2c: HotmailB2CVerifMethod::Smtp(VerifMethodSmtpConfig {
+			hotmailb2c: HotmailB2CVerifMethod::Smtp(Box::new(VerifMethodSmtpConfig {
 				proxy: Some("proxy1".to_string()),
 				..Default::default()
-			}),
-			yahoo: YahooVerifMethod::Smtp(VerifMethodSmtpConfig {
+			})),
+			yahoo: YahooVerifMethod::Smtp(Box::new(VerifMethodSmtpConfig {
 				proxy: Some("proxy2".to_string()),
 				..Default::default()
-			}),
+			})),
 			..Default::default()
 		};
 
@@ -17,8 +17,8 @@
 use super::YahooError;
 use crate::LOG_TARGET;
 use crate::{
-	smtp::{http_api::create_client, SmtpDetails},
-	util::input_output::CheckEmailInput,
+	smtp::SmtpDetails,
+	util::{http_client::create_client, input_output::CheckEmailInput},
 };
 use regex::Regex;
 use serde::{Deserialize, Serialize};
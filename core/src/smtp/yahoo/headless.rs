@@ -133,6 +133,7 @@ async fn check_headless_inner(
 		is_catch_all: false,
 		is_deliverable,
 		is_disabled,
+		..Default::default()
 	})
 }
 
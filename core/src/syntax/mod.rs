@@ -23,7 +23,7 @@ use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
 /// Syntax information after parsing an email address
-#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
 pub struct SyntaxDetails {
 	/// The email address as a async_smtp `EmailAddress`. It will be `None` if
 	/// the email address is ill-formed.
@@ -0,0 +1,170 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Factory of the HTTP clients used for the HTTP-based verifications and
+//! lookups (Yahoo API, Microsoft 365, HaveIBeenPwned, Gravatar...).
+
+use crate::util::input_output::{CheckEmailInput, CheckEmailInputProxy, ProxyType};
+use once_cell::sync::Lazy;
+use reqwest::{Client, Error as ReqwestError, Proxy};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Configuration of the HTTP clients.
+#[derive(Debug, Clone, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct HttpClientConfig {
+	/// Timeout of each HTTP request, in ms. Set to None to not use a timeout.
+	///
+	/// Defaults to None.
+	pub timeout_ms: Option<u64>,
+	/// The default `User-Agent` header of the HTTP requests. Some APIs
+	/// override it.
+	///
+	/// Defaults to "reacher".
+	pub user_agent: String,
+}
+
+impl Default for HttpClientConfig {
+	fn default() -> Self {
+		Self {
+			timeout_ms: None,
+			user_agent: "reacher".into(),
+		}
+	}
+}
+
+/// The type, host, port, username and password of a proxy.
+type ProxyKey = (ProxyType, String, u16, Option<String>, Option<String>);
+
+/// Everything a client is built from, so that clients with the same
+/// settings, and thus their connection pools, are shared.
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+struct ClientKey {
+	proxy: Option<ProxyKey>,
+	timeout_ms: Option<u64>,
+	user_agent: String,
+}
+
+static CLIENTS: Lazy<Mutex<HashMap<ClientKey, Client>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn build_proxy(proxy: &CheckEmailInputProxy) -> Result<Proxy, ReqwestError> {
+	let url = match proxy.proxy_type {
+		// socks5h, so that the proxy resolves the host names.
+		ProxyType::Socks5 => format!("socks5h://{}:{}", proxy.host, proxy.port),
+		ProxyType::HttpConnect => format!("http://{}:{}", proxy.host, proxy.port),
+	};
+	let reqwest_proxy = Proxy::all(url)?;
+
+	Ok(match (&proxy.username, &proxy.password) {
+		(Some(username), Some(password)) => reqwest_proxy.basic_auth(username, password),
+		_ => reqwest_proxy,
+	})
+}
+
+/// Get the HTTP client for the given API, e.g. "yahoo" or "gravatar". It
+/// uses the proxy configured for this API in `VerifMethod.http_proxies`, if
+/// any, and the timeout and user agent of `CheckEmailInput.http_client`.
+/// Clients are cached, so that their connection pools are reused across
+/// verifications.
+pub fn create_client(input: &CheckEmailInput, api_name: &str) -> Result<Client, ReqwestError> {
+	let proxy = input
+		.verif_method
+		.http_proxies
+		.get(api_name)
+		.and_then(|proxy_id| input.verif_method.proxies.get(proxy_id));
+	let key = ClientKey {
+		proxy: proxy.map(|p| {
+			(
+				p.proxy_type,
+				p.host.clone(),
+				p.port,
+				p.username.clone(),
+				p.password.clone(),
+			)
+		}),
+		timeout_ms: input.http_client.timeout_ms,
+		user_agent: input.http_client.user_agent.clone(),
+	};
+
+	let mut clients = CLIENTS
+		.lock()
+		.expect("HTTP clients mutex is not poisoned. qed.");
+	if let Some(client) = clients.get(&key) {
+		return Ok(client.clone());
+	}
+
+	let mut builder = Client::builder().user_agent(key.user_agent.as_str());
+	if let Some(timeout_ms) = key.timeout_ms {
+		builder = builder.timeout(Duration::from_millis(timeout_ms));
+	}
+	if let Some(proxy) = proxy {
+		builder = builder.proxy(build_proxy(proxy)?);
+	}
+	let client = builder.build()?;
+	clients.insert(key, client.clone());
+
+	Ok(client)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn should_cache_clients_by_settings() {
+		let mut input = CheckEmailInput::default();
+		input.verif_method.proxies.insert(
+			"proxy1".into(),
+			CheckEmailInputProxy {
+				proxy_type: ProxyType::HttpConnect,
+				host: "my-proxy.io".into(),
+				port: 3128,
+				username: Some("user".into()),
+				password: Some("pass".into()),
+				timeout_ms: None,
+			},
+		);
+		input
+			.verif_method
+			.http_proxies
+			.insert("yahoo".into(), "proxy1".into());
+		input.http_client.timeout_ms = Some(1234);
+
+		create_client(&input, "yahoo").unwrap();
+		create_client(&input, "gravatar").unwrap();
+
+		let clients = CLIENTS.lock().unwrap();
+		assert!(clients.contains_key(&ClientKey {
+			proxy: Some((
+				ProxyType::HttpConnect,
+				"my-proxy.io".into(),
+				3128,
+				Some("user".into()),
+				Some("pass".into())
+			)),
+			timeout_ms: Some(1234),
+			user_agent: "reacher".into(),
+		}));
+		assert!(clients.contains_key(&ClientKey {
+			proxy: None,
+			timeout_ms: Some(1234),
+			user_agent: "reacher".into(),
+		}));
+	}
+}
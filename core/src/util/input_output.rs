@@ -14,11 +14,19 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::events::CheckEventSender;
 use crate::misc::{MiscDetails, MiscError};
+use crate::mx::resolver::{DnsConfig, DnsResolver};
 use crate::mx::{MxDetails, MxError};
+use crate::reason::ReasonCode;
+use crate::score::{ClassificationPolicy, Score, ScoreConfig};
 use crate::smtp::verif_method::VerifMethod;
-use crate::smtp::{SmtpDebug, SmtpDetails, SmtpError, SmtpErrorDesc};
+use crate::smtp::{
+	CatchAllCache, SmtpDebug, SmtpDetails, SmtpError, SmtpErrorDesc, SmtpSessionPool,
+	SmtpTranscriptConfig,
+};
 use crate::syntax::SyntaxDetails;
+use crate::util::http_client::HttpClientConfig;
 use crate::util::ser_with_display::ser_with_display;
 use async_smtp::EmailAddress as AsyncSmtpEmailAddress;
 use chrono::{DateTime, Utc};
@@ -26,7 +34,8 @@ use derive_builder::Builder;
 use serde::{ser::SerializeMap, Deserialize, Serialize, Serializer};
 use std::fmt::Display;
 use std::str::FromStr;
-use std::time::{Duration, SystemTime};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 /// Wrapper around the `EmailAddress` from `async_smtp` to allow for
 /// serialization and deserialization.
@@ -90,13 +99,29 @@ impl AsRef<str> for EmailAddress {
 	}
 }
 
+/// The protocol spoken by a proxy.
+#[derive(Debug, Default, Clone, Copy, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyType {
+	/// A SOCKS5 proxy.
+	#[default]
+	Socks5,
+	/// An HTTP proxy, tunneling the SMTP connection with the CONNECT method.
+	HttpConnect,
+}
+
 /// Perform the email verification via a specified proxy. The usage of a proxy
 /// is optional.
 #[derive(Debug, Default, Clone, Deserialize, PartialEq, Serialize)]
 pub struct CheckEmailInputProxy {
-	/// Use the specified SOCKS5 proxy host to perform email verification.
+	/// The protocol of the proxy.
+	///
+	/// Defaults to SOCKS5.
+	#[serde(default, rename = "type")]
+	pub proxy_type: ProxyType,
+	/// Use the specified proxy host to perform email verification.
 	pub host: String,
-	/// Use the specified SOCKS5 proxy port to perform email verification.
+	/// Use the specified proxy port to perform email verification.
 	pub port: u16,
 	/// Username to pass to proxy authentication.
 	pub username: Option<String>,
@@ -116,6 +141,53 @@ pub struct CheckEmailInput {
 
 	pub verif_method: VerifMethod,
 
+	/// Configuration of the DNS resolver used for the MX lookups, e.g. to
+	/// use custom nameservers or DNS-over-HTTPS. Resolvers are shared
+	/// across all verifications with the same configuration.
+	///
+	/// Defaults to the system configuration.
+	#[serde(default)]
+	pub dns: DnsConfig,
+	/// A custom DNS resolver to use instead of the one built from `dns`,
+	/// e.g. an `InMemoryResolver` in tests.
+	///
+	/// Defaults to None.
+	#[serde(skip)]
+	pub dns_resolver: Option<Arc<dyn DnsResolver>>,
+	/// A pool of SMTP sessions to reuse connections across verifications,
+	/// instead of opening one connection per email. See `check_emails` to
+	/// use it for a batch of emails.
+	///
+	/// Defaults to None.
+	#[serde(skip)]
+	pub smtp_session_pool: Option<Arc<SmtpSessionPool>>,
+	/// A cache of the catch-all status of domains, consulted before probing
+	/// the SMTP server with a random address, e.g. an
+	/// `InMemoryCatchAllCache`.
+	///
+	/// Defaults to None.
+	#[serde(skip)]
+	pub catch_all_cache: Option<Arc<dyn CatchAllCache>>,
+	/// Whether to record the SMTP conversation in the debug output, and
+	/// whether to redact the email addresses in it.
+	///
+	/// Defaults to disabled.
+	#[serde(default)]
+	pub smtp_transcript: SmtpTranscriptConfig,
+
+	/// Weights and thresholds used to calculate the confidence score, from
+	/// which `is_reachable` is derived.
+	///
+	/// Defaults to the default ScoreConfig.
+	#[serde(default)]
+	pub score: ScoreConfig,
+	/// How catch-all, role account, disposable and full inbox emails are
+	/// classified.
+	///
+	/// Defaults to Risky for all of them.
+	#[serde(default)]
+	pub classification_policy: ClassificationPolicy,
+
 	/// Whether to check if a gravatar image is existing for the given email.
 	/// Adds a bit of latency to the verification process.
 	///
@@ -124,6 +196,27 @@ pub struct CheckEmailInput {
 	/// Check if a the email address is present in HaveIBeenPwned API.
 	/// If the api_key is filled, HaveIBeenPwned API is checked
 	pub haveibeenpwned_api_key: Option<String>,
+	/// Timeout and user agent of the HTTP requests, e.g. to the Yahoo API
+	/// or HaveIBeenPwned. Their proxies are set in
+	/// `VerifMethod.http_proxies`.
+	///
+	/// Defaults to no timeout, and the "reacher" user agent.
+	#[serde(default)]
+	pub http_client: HttpClientConfig,
+	/// Timeout of the whole verification, covering the MX lookup, the misc
+	/// checks and the SMTP, HTTP or headless verification. When it fires,
+	/// the results of the stages which finished are returned, along with
+	/// the stage which timed out in `DebugDetails.timed_out_stage`. Set to
+	/// None to not use a total timeout.
+	///
+	/// Defaults to None.
+	pub total_timeout: Option<Duration>,
+	/// A channel to emit events on as the verification progresses, see
+	/// `check_email_stream`.
+	///
+	/// Defaults to None.
+	#[serde(skip)]
+	pub events: Option<CheckEventSender>,
 
 	/// The WebDriver address to use for headless verifications.
 	///
@@ -150,8 +243,18 @@ impl Default for CheckEmailInput {
 		CheckEmailInput {
 			to_email: "".into(),
 			verif_method: VerifMethod::default(),
+			dns: DnsConfig::default(),
+			dns_resolver: None,
+			smtp_session_pool: None,
+			catch_all_cache: None,
+			smtp_transcript: SmtpTranscriptConfig::default(),
+			score: ScoreConfig::default(),
+			classification_policy: ClassificationPolicy::default(),
 			check_gravatar: false,
 			haveibeenpwned_api_key: None,
+			http_client: HttpClientConfig::default(),
+			total_timeout: None,
+			events: None,
 			webdriver_addr: "http://localhost:9515".into(),
 			webdriver_config: WebdriverConfig::default(),
 			backend_name: "backend-dev".into(),
@@ -182,6 +285,39 @@ pub enum Reachable {
 	Unknown,
 }
 
+/// A stage of the email verification, after the syntax check.
+#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStage {
+	/// The lookup of the MX records.
+	Mx,
+	/// The misc checks, e.g. Gravatar or HaveIBeenPwned.
+	Misc,
+	/// The SMTP, HTTP or headless verification of the mailbox.
+	Smtp,
+}
+
+/// Time spent in each stage of the email verification. A stage is None if it
+/// didn't run. See `SmtpDebug.durations` for the steps of the SMTP stage.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct StageDurations {
+	/// The syntax check.
+	pub syntax: Option<Duration>,
+	/// The lookup of the MX records.
+	pub mx: Option<Duration>,
+	/// The misc checks, including the Gravatar and HaveIBeenPwned requests.
+	pub misc: Option<Duration>,
+	/// The SMTP, HTTP or headless verification of the mailbox, on all the
+	/// attempted MX hosts.
+	pub smtp: Option<Duration>,
+}
+
+/// Add the time elapsed since `start` to the duration of a stage, so that
+/// stages run several times, e.g. on retries, are summed up.
+pub(crate) fn add_elapsed(duration: &mut Option<Duration>, start: Instant) {
+	*duration = Some(duration.unwrap_or_default() + start.elapsed());
+}
+
 /// Details about the email verification used for debugging.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct DebugDetails {
@@ -195,6 +331,13 @@ pub struct DebugDetails {
 	pub duration: Duration,
 	/// Details about the email verification used for debugging.
 	pub smtp: SmtpDebug,
+	/// The stage which was running when `CheckEmailInput.total_timeout`
+	/// fired, if it did.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub timed_out_stage: Option<CheckStage>,
+	/// Time spent in each stage of the verification.
+	#[serde(default)]
+	pub durations: StageDurations,
 }
 
 impl Default for DebugDetails {
@@ -205,6 +348,8 @@ impl Default for DebugDetails {
 			duration: Duration::default(),
 			smtp: SmtpDebug::default(),
 			backend_name: "backend-dev".into(),
+			timed_out_stage: None,
+			durations: StageDurations::default(),
 		}
 	}
 }
@@ -215,6 +360,8 @@ pub struct CheckEmailOutput {
 	/// Input by the user.
 	pub input: String,
 	pub is_reachable: Reachable,
+	/// Reasons explaining the `is_reachable` verdict.
+	pub reasons: Vec<ReasonCode>,
 	/// Misc details about the email address.
 	pub misc: Result<MiscDetails, MiscError>,
 	/// Details about the MX host.
@@ -223,6 +370,9 @@ pub struct CheckEmailOutput {
 	pub smtp: Result<SmtpDetails, SmtpError>,
 	/// Details about the email address.
 	pub syntax: SyntaxDetails,
+	/// Confidence score of the verification, and the signals which
+	/// contributed to it.
+	pub score: Score,
 	/// Details about the email verification used for debugging.
 	pub debug: DebugDetails,
 }
@@ -232,10 +382,12 @@ impl Default for CheckEmailOutput {
 		CheckEmailOutput {
 			input: String::default(),
 			is_reachable: Reachable::Unknown,
+			reasons: vec![],
 			misc: Ok(MiscDetails::default()),
 			mx: Ok(MxDetails::default()),
 			smtp: Ok(SmtpDetails::default()),
 			syntax: SyntaxDetails::default(),
+			score: Score::default(),
 			debug: DebugDetails::default(),
 		}
 	}
@@ -291,6 +443,8 @@ impl Serialize for CheckEmailOutput {
 			)?,
 		}
 		map.serialize_entry("syntax", &self.syntax)?;
+		map.serialize_entry("score", &self.score)?;
+		map.serialize_entry("reasons", &self.reasons)?;
 		map.serialize_entry("debug", &self.debug)?;
 		map.end()
 	}
@@ -323,10 +477,12 @@ mod tests {
 			CheckEmailOutput {
 				input: "foo".to_string(),
 				is_reachable: super::Reachable::Unknown,
+				reasons: vec![],
 				misc: Ok(super::MiscDetails::default()),
 				mx: Ok(super::MxDetails::default()),
 				syntax: super::SyntaxDetails::default(),
 				smtp: Err(super::SmtpError::AsyncSmtpError(r.into())),
+				score: super::Score::default(),
 				debug: DebugDetails::default(),
 			}
 		}
@@ -14,6 +14,7 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod http_client;
 pub mod input_output;
 #[cfg(feature = "sentry")]
 pub mod sentry;
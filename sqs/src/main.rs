@@ -60,6 +60,7 @@ impl CheckEmailPartialTask {
 			input: self.input.to_check_email_input(backend_config),
 			job_id: self.job_id,
 			webhook: self.webhook,
+			deferrals: 0,
 		}
 	}
 }
@@ -100,7 +101,7 @@ async fn handler(event: LambdaEvent<SQSPayload>) -> Result<CheckEmailOutput, Err
 
 	let task = &task.into_check_email_task(backend_config.clone());
 
-	let worker_output = check_email_and_send_result(task).await;
+	let worker_output = check_email_and_send_result(task, &backend_config).await;
 	match worker_output.as_ref() {
 		Ok(output) => {
 			info!(email = ?output.input, is_reachable = ?output.is_reachable, "Task completed");